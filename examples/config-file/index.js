@@ -0,0 +1 @@
+console.log(`${process.env.GREETING} from nixpacks.toml`);
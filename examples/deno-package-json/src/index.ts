@@ -0,0 +1,7 @@
+import * as o from "https://deno.land/x/cowsay/mod.ts";
+
+let m = o.say({
+  text: "Hello Deno",
+});
+
+console.log(m);
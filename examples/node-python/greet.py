@@ -0,0 +1,3 @@
+import requests
+
+print("Hello from Python")
@@ -0,0 +1,4 @@
+const { execSync } = require("child_process");
+
+const output = execSync("python greet.py").toString().trim();
+console.log(`${output} and Node`);
@@ -0,0 +1,4 @@
+const fs = require("fs");
+
+const message = fs.readFileSync("message.txt").toString();
+fs.writeFileSync("message.json", JSON.stringify({ message }));
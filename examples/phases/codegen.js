@@ -0,0 +1,3 @@
+const fs = require("fs");
+
+fs.writeFileSync("message.txt", "Hello from generated code");
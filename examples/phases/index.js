@@ -0,0 +1 @@
+console.log(require("./message.json").message);
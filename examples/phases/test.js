@@ -0,0 +1,3 @@
+const assert = require("assert");
+
+assert.ok(require("./message.json").message.startsWith("Hello"));
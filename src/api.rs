@@ -0,0 +1,400 @@
+use anyhow::Result;
+
+use crate::{
+    create_environment_with_files, get_providers,
+    nixpacks::{
+        app::App,
+        builders::ImageBuilder,
+        logger::{HumanLogger, Logger},
+        nix::Pkg,
+        plan::BuildPlan,
+        AppBuilder, AppBuilderOptions, BuildResult,
+    },
+};
+
+/// Entry point for using Nixpacks as a library
+///
+/// ```no_run
+/// use nixpacks::Nixpacks;
+///
+/// let result = Nixpacks::builder()
+///     .path("./examples/node")
+///     .env("NODE_ENV=production")
+///     .tag("my-app:latest")
+///     .build()?;
+/// println!("{:?}", result.output);
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub struct Nixpacks {}
+
+impl Nixpacks {
+    pub fn builder<'a>() -> NixpacksBuilder<'a> {
+        NixpacksBuilder::default()
+    }
+}
+
+/// Options for generating a plan or building an image, created with `Nixpacks::builder()`
+pub struct NixpacksBuilder<'a> {
+    path: String,
+    name: Option<String>,
+    envs: Vec<String>,
+    env_files: Vec<String>,
+    logger: Box<dyn Logger + 'a>,
+    image_builder: Option<Box<dyn ImageBuilder + 'a>>,
+    options: AppBuilderOptions,
+}
+
+impl<'a> Default for NixpacksBuilder<'a> {
+    fn default() -> Self {
+        Self {
+            path: ".".to_string(),
+            name: None,
+            envs: Vec::new(),
+            env_files: Vec::new(),
+            logger: Box::new(HumanLogger {}),
+            image_builder: None,
+            options: AppBuilderOptions::empty(),
+        }
+    }
+}
+
+impl<'a> NixpacksBuilder<'a> {
+    /// App source directory, defaults to the current directory
+    pub fn path<S: Into<String>>(mut self, path: S) -> Self {
+        self.path = path.into();
+        self
+    }
+
+    /// Name of the built image, defaults to a random id
+    pub fn name<S: Into<String>>(mut self, name: S) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Additional Nix package to install
+    pub fn pkg<S: AsRef<str>>(mut self, pkg: S) -> Self {
+        self.options.custom_pkgs.push(Pkg::new(pkg.as_ref()));
+        self
+    }
+
+    pub fn pkgs<I: IntoIterator<Item = S>, S: AsRef<str>>(self, pkgs: I) -> Self {
+        pkgs.into_iter().fold(self, |builder, pkg| builder.pkg(pkg))
+    }
+
+    pub fn pin_pkgs(mut self, pin_pkgs: bool) -> Self {
+        self.options.pin_pkgs = pin_pkgs;
+        self
+    }
+
+    pub fn build_cmd<S: Into<String>>(mut self, cmd: S) -> Self {
+        self.options.custom_build_cmd = Some(cmd.into());
+        self
+    }
+
+    pub fn start_cmd<S: Into<String>>(mut self, cmd: S) -> Self {
+        self.options.custom_start_cmd = Some(cmd.into());
+        self
+    }
+
+    /// Environment variable as `NAME=value`, or `NAME` to take the value from the current process
+    pub fn env<S: Into<String>>(mut self, env: S) -> Self {
+        self.envs.push(env.into());
+        self
+    }
+
+    pub fn envs<I: IntoIterator<Item = S>, S: Into<String>>(self, envs: I) -> Self {
+        envs.into_iter().fold(self, |builder, env| builder.env(env))
+    }
+
+    /// `.env` file to load variables from, overridden by `env`
+    pub fn env_file<S: Into<String>>(mut self, path: S) -> Self {
+        self.env_files.push(path.into());
+        self
+    }
+
+    /// Name of a build-time secret, read from the environment when the image is built
+    pub fn secret<S: Into<String>>(mut self, name: S) -> Self {
+        self.options.secrets.push(name.into());
+        self
+    }
+
+    /// Fail when a `${NAME}` reference is to a variable that isn't set, instead of leaving it for
+    /// the shell of the container
+    pub fn strict_variables(mut self, strict_variables: bool) -> Self {
+        self.options.strict_variables = strict_variables;
+        self
+    }
+
+    /// Existing build plan file to build instead of generating a new plan
+    pub fn plan_path<S: Into<String>>(mut self, path: S) -> Self {
+        self.options.plan_path = Some(path.into());
+        self
+    }
+
+    /// Partial build plan file merged onto the generated plan
+    pub fn plan_override_path<S: Into<String>>(mut self, path: S) -> Self {
+        self.options.plan_override_path = Some(path.into());
+        self
+    }
+
+    /// Save the Dockerfile and app source to a directory instead of building an image
+    pub fn out_dir<S: Into<String>>(mut self, dir: S) -> Self {
+        self.options.out_dir = Some(dir.into());
+        self
+    }
+
+    /// Save the image as an OCI image layout tarball
+    pub fn oci_output<S: Into<String>>(mut self, path: S) -> Self {
+        self.options.oci_output = Some(path.into());
+        self
+    }
+
+    pub fn tag<S: Into<String>>(mut self, tag: S) -> Self {
+        self.options.tags.push(tag.into());
+        self
+    }
+
+    pub fn label<S: Into<String>>(mut self, label: S) -> Self {
+        self.options.labels.push(label.into());
+        self
+    }
+
+    pub fn quiet(mut self, quiet: bool) -> Self {
+        self.options.quiet = quiet;
+        self
+    }
+
+    pub fn no_cache_mounts(mut self, no_cache_mounts: bool) -> Self {
+        self.options.no_cache_mounts = no_cache_mounts;
+        self
+    }
+
+    /// Install the packages from a `flake.nix` and `flake.lock` with a locked nixpkgs instead of
+    /// an `environment.nix`
+    pub fn use_flake(mut self, use_flake: bool) -> Self {
+        self.options.use_flake = use_flake;
+        self
+    }
+
+    /// Don't check the package names against the bundled package index
+    pub fn skip_pkg_validation(mut self, skip_pkg_validation: bool) -> Self {
+        self.options.skip_pkg_validation = skip_pkg_validation;
+        self
+    }
+
+    pub fn use_gitignore(mut self, use_gitignore: bool) -> Self {
+        self.options.use_gitignore = use_gitignore;
+        self
+    }
+
+    /// One of the built-in image builders (`docker`, `podman`, or `buildah`)
+    pub fn backend<S: Into<String>>(mut self, backend: S) -> Self {
+        self.options.backend = Some(backend.into());
+        self
+    }
+
+    /// Build the image with a custom backend, taking priority over `backend`
+    pub fn image_builder(mut self, image_builder: Box<dyn ImageBuilder + 'a>) -> Self {
+        self.image_builder = Some(image_builder);
+        self
+    }
+
+    /// Receives the events of the build, defaults to colored output on stdout
+    pub fn logger(mut self, logger: Box<dyn Logger + 'a>) -> Self {
+        self.logger = logger;
+        self
+    }
+
+    /// Generate the build plan for the app
+    pub fn plan(&self) -> Result<BuildPlan> {
+        let app = App::new(&self.path)?;
+        let environment = create_environment_with_files(
+            self.envs.iter().map(|s| s.as_str()).collect(),
+            self.env_files.iter().map(|s| s.as_str()).collect(),
+        )?;
+        let mut app_builder = AppBuilder::new(
+            None,
+            &app,
+            &environment,
+            self.logger.as_ref(),
+            &self.options,
+        )?;
+
+        app_builder.plan(get_providers())
+    }
+
+    /// Generate the build plan (or load it from `plan_path`) and build the image
+    pub fn build(self) -> Result<BuildResult> {
+        let app = App::new(&self.path)?;
+        let environment = create_environment_with_files(
+            self.envs.iter().map(|s| s.as_str()).collect(),
+            self.env_files.iter().map(|s| s.as_str()).collect(),
+        )?;
+        let mut app_builder = AppBuilder::new(
+            self.name,
+            &app,
+            &environment,
+            self.logger.as_ref(),
+            &self.options,
+        )?;
+        if let Some(image_builder) = self.image_builder {
+            app_builder.set_image_builder(image_builder);
+        }
+
+        app_builder.build(get_providers())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nixpacks::{builders::ImageBuildOptions, BuildOutput};
+    use std::{cell::RefCell, path::Path, rc::Rc};
+
+    /// Keeps the options of the build instead of building an image
+    struct RecordingImageBuilder {
+        options: Rc<RefCell<Option<ImageBuildOptions>>>,
+    }
+
+    impl ImageBuilder for RecordingImageBuilder {
+        fn name(&self) -> &str {
+            "recording"
+        }
+
+        fn build_image(
+            &self,
+            _dir: &Path,
+            options: &ImageBuildOptions,
+            _logger: &dyn Logger,
+        ) -> Result<()> {
+            *self.options.borrow_mut() = Some(options.clone());
+            Ok(())
+        }
+
+        fn supports_mounts(&self) -> bool {
+            true
+        }
+    }
+
+    /// Keeps the Dockerfile of the build, without support for `RUN --mount`
+    struct DockerfileImageBuilder {
+        dockerfile: Rc<RefCell<String>>,
+    }
+
+    impl ImageBuilder for DockerfileImageBuilder {
+        fn name(&self) -> &str {
+            "dockerfile"
+        }
+
+        fn build_image(
+            &self,
+            dir: &Path,
+            _options: &ImageBuildOptions,
+            _logger: &dyn Logger,
+        ) -> Result<()> {
+            *self.dockerfile.borrow_mut() = std::fs::read_to_string(dir.join("Dockerfile"))?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_builder_plan() -> Result<()> {
+        let plan = Nixpacks::builder()
+            .path("./examples/node")
+            .pkg("cowsay")
+            .build_cmd("npm run build -- ${MODE:-production}")
+            .env("HELLO=world")
+            .plan()?;
+
+        assert_eq!(
+            plan.build.unwrap().cmd,
+            Some("npm run build -- production".to_string())
+        );
+        assert!(plan.setup.unwrap().pkgs.contains(&Pkg::new("cowsay")));
+        assert_eq!(
+            plan.variables.unwrap().get("HELLO"),
+            Some(&"world".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_builder_out_dir() -> Result<()> {
+        let out_dir = tempdir::TempDir::new("nixpacks-api")?;
+        let result = Nixpacks::builder()
+            .path("./examples/node")
+            .out_dir(out_dir.path().to_str().unwrap())
+            .build()?;
+
+        assert!(result.plan.start.is_some());
+        assert_eq!(
+            result.output,
+            BuildOutput::Directory(out_dir.path().to_path_buf())
+        );
+        assert!(out_dir.path().join("Dockerfile").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_builder_secret_from_env() -> Result<()> {
+        let options = Rc::new(RefCell::new(None));
+        Nixpacks::builder()
+            .path("./examples/node")
+            .env("NIXPACKS_TEST_SECRET=hunter2")
+            .secret("NIXPACKS_TEST_SECRET")
+            .image_builder(Box::new(RecordingImageBuilder {
+                options: options.clone(),
+            }))
+            .build()?;
+
+        let options = options.borrow().clone().unwrap();
+        assert_eq!(options.secrets, vec!["NIXPACKS_TEST_SECRET".to_string()]);
+        assert_eq!(
+            options.secret_values,
+            vec![("NIXPACKS_TEST_SECRET".to_string(), "hunter2".to_string())]
+        );
+        assert!(!options
+            .build_args
+            .iter()
+            .any(|(name, _)| name == "NIXPACKS_TEST_SECRET"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_builder_missing_secret() {
+        let error = Nixpacks::builder()
+            .path("./examples/node")
+            .secret("NIXPACKS_TEST_MISSING_SECRET")
+            .image_builder(Box::new(RecordingImageBuilder {
+                options: Rc::new(RefCell::new(None)),
+            }))
+            .build()
+            .unwrap_err();
+        assert!(format!("{:#}", error).contains("Secret NIXPACKS_TEST_MISSING_SECRET is not set"));
+    }
+
+    #[test]
+    fn test_builder_without_mounts() -> Result<()> {
+        let dockerfile = Rc::new(RefCell::new(String::new()));
+        Nixpacks::builder()
+            .path("./examples/yarn")
+            .image_builder(Box::new(DockerfileImageBuilder {
+                dockerfile: dockerfile.clone(),
+            }))
+            .build()?;
+        assert!(dockerfile.borrow().contains("RUN yarn"));
+        assert!(!dockerfile.borrow().contains("--mount"));
+
+        let error = Nixpacks::builder()
+            .path("./examples/yarn")
+            .env("NIXPACKS_TEST_SECRET=hunter2")
+            .secret("NIXPACKS_TEST_SECRET")
+            .image_builder(Box::new(DockerfileImageBuilder {
+                dockerfile: dockerfile.clone(),
+            }))
+            .build()
+            .unwrap_err();
+        assert!(format!("{:#}", error).contains("doesn't support secret mounts"));
+        Ok(())
+    }
+}
@@ -1,6 +1,16 @@
-use ::nixpacks::{build, gen_plan};
-use anyhow::Result;
+use ::nixpacks::{
+    detect,
+    nixpacks::{
+        logger::{get_logger, LogFormat},
+        plan::{BuildPlan, PlanFormat},
+        schema::get_plan_schema,
+        shell::{gen_shell_nix, SHELL_NIX_FILE_NAME, SHELL_NIX_HEADER},
+    },
+    Nixpacks,
+};
+use anyhow::{bail, Context, Result};
 use clap::{arg, Arg, Command};
+use std::{fs, path::PathBuf, process};
 
 fn main() -> Result<()> {
     const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -12,7 +22,72 @@ fn main() -> Result<()> {
         .subcommand(
             Command::new("plan")
                 .about("Generate a build plan for an app")
-                .arg(arg!(<PATH> "App source")),
+                .arg(arg!(<PATH> "App source"))
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .short('f')
+                        .help("Format of the plan, defaults to the extension of --output or json")
+                        .takes_value(true)
+                        .possible_values(["json", "yaml", "toml"]),
+                )
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .short('o')
+                        .help("Write the plan to a file instead of stdout")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("diff")
+                        .long("diff")
+                        .help("Show the changes from an existing plan file instead of the plan")
+                        .takes_value(true)
+                        .conflicts_with("output"),
+                )
+                .arg(
+                    Arg::new("exit_code")
+                        .long("exit-code")
+                        .help("Exit with status 1 if --diff found any changes")
+                        .takes_value(false)
+                        .requires("diff"),
+                ),
+        )
+        .subcommand(
+            Command::new("shell")
+                .about("Write a shell.nix with the packages and variables of the plan")
+                .arg(arg!(<PATH> "App source"))
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .short('o')
+                        .help("Write the shell.nix to a file instead of the app source")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("plan")
+                        .long("plan")
+                        .help("Existing build plan file to use instead of generating one")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("exec")
+                        .long("exec")
+                        .help("Run nix-shell with the generated shell.nix")
+                        .takes_value(false),
+                ),
+        )
+        .subcommand(Command::new("schema").about("Print the JSON Schema of the build plan"))
+        .subcommand(
+            Command::new("detect")
+                .about("Show which providers match an app and why")
+                .arg(arg!(<PATH> "App source"))
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .help("Output the detection results as JSON")
+                        .takes_value(false),
+                ),
         )
         .subcommand(
             Command::new("build")
@@ -53,6 +128,38 @@ fn main() -> Result<()> {
                         .help("Additional labels to add to the output image")
                         .takes_value(true)
                         .multiple_values(true),
+                )
+                .arg(
+                    Arg::new("no_cache_mounts")
+                        .long("no-cache-mounts")
+                        .help("Don't keep package manager caches between builds with BuildKit cache mounts")
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::new("use_gitignore")
+                        .long("use-gitignore")
+                        .help("Leave files listed in .gitignore out of the image, in addition to .dockerignore")
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::new("flake")
+                        .long("flake")
+                        .help("Install packages from a flake.nix with a locked nixpkgs instead of environment.nix")
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::new("backend")
+                        .long("backend")
+                        .help("Tool used to build the image")
+                        .takes_value(true)
+                        .possible_values(["docker", "podman", "buildah"]),
+                )
+                .arg(
+                    Arg::new("output_oci")
+                        .long("output-oci")
+                        .help("Save the image as an OCI image layout tarball instead of loading it into the local image store")
+                        .takes_value(true)
+                        .conflicts_with("out"),
                 ),
         )
         .arg(
@@ -75,7 +182,7 @@ fn main() -> Result<()> {
             Arg::new("pkgs")
                 .long("pkgs")
                 .short('p')
-                .help("Provide additional nix packages to install in the environment")
+                .help("Provide additional nix packages to install in the environment, or `name@version` for a version of node, python, or go")
                 .takes_value(true)
                 .multiple_values(true)
                 .global(true),
@@ -87,57 +194,226 @@ fn main() -> Result<()> {
                 .takes_value(false)
                 .global(true),
         )
+        .arg(
+            Arg::new("skip_pkg_validation")
+                .long("skip-pkg-validation")
+                .help("Don't check the names of Nix packages against the bundled package index")
+                .takes_value(false)
+                .global(true),
+        )
+        .arg(
+            Arg::new("plan_override")
+                .long("plan-override")
+                .help("Partial build plan file to merge onto the generated plan")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::new("secret")
+                .long("secret")
+                .help("Name of an environment variable that is only available while installing and building the app")
+                .takes_value(true)
+                .multiple_values(true)
+                .global(true),
+        )
         .arg(
             Arg::new("env")
                 .long("env")
                 .help("Provide environment variables to your build")
                 .takes_value(true)
                 .multiple_values(true)
+                .multiple_occurrences(true)
+                .global(true),
+        )
+        .arg(
+            Arg::new("strict_variables")
+                .long("strict-vars")
+                .help("Fail when a ${NAME} reference in a command is to a variable that isn't set, instead of leaving it for the shell")
+                .takes_value(false)
+                .global(true),
+        )
+        .arg(
+            Arg::new("env_file")
+                .long("env-file")
+                .help("Load environment variables from a .env file, overridden by --env")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .global(true),
+        )
+        .arg(
+            Arg::new("log_format")
+                .long("log-format")
+                .help("Format of the build output. The human format has no colors if NO_COLOR is set")
+                .takes_value(true)
+                .possible_values(["human", "plain", "json"])
+                .default_value("human")
                 .global(true),
         )
         .get_matches();
 
-    let build_cmd = matches.value_of("build_cmd").map(|s| s.to_string());
-    let start_cmd = matches.value_of("start_cmd").map(|s| s.to_string());
-    let pkgs: Vec<_> = match matches.values_of("pkgs") {
-        Some(values) => values.collect(),
-        None => Vec::new(),
-    };
-    let pin_pkgs = matches.is_present("pin");
+    // Options shared by every subcommand
+    let log_format: LogFormat = matches.value_of_t("log_format")?;
+    let mut nixpacks = Nixpacks::builder()
+        .logger(get_logger(log_format))
+        .pin_pkgs(matches.is_present("pin"))
+        .skip_pkg_validation(matches.is_present("skip_pkg_validation"))
+        .strict_variables(matches.is_present("strict_variables"));
+    if let Some(cmd) = matches.value_of("build_cmd") {
+        nixpacks = nixpacks.build_cmd(cmd);
+    }
+    if let Some(cmd) = matches.value_of("start_cmd") {
+        nixpacks = nixpacks.start_cmd(cmd);
+    }
+    if let Some(pkgs) = matches.values_of("pkgs") {
+        nixpacks = nixpacks.pkgs(pkgs);
+    }
+    if let Some(path) = matches.value_of("plan_override") {
+        nixpacks = nixpacks.plan_override_path(path);
+    }
+    if let Some(secrets) = matches.values_of("secret") {
+        nixpacks = secrets.fold(nixpacks, |nixpacks, secret| nixpacks.secret(secret));
+    }
 
     let envs: Vec<_> = match matches.values_of("env") {
         Some(envs) => envs.collect(),
         None => Vec::new(),
     };
+    let env_files: Vec<_> = match matches.values_of("env_file") {
+        Some(env_files) => env_files.collect(),
+        None => Vec::new(),
+    };
+    nixpacks = nixpacks.envs(envs.clone());
+    nixpacks = env_files
+        .iter()
+        .fold(nixpacks, |nixpacks, env_file| nixpacks.env_file(*env_file));
 
     match &matches.subcommand() {
         Some(("plan", matches)) => {
             let path = matches.value_of("PATH").expect("required");
 
-            let plan = gen_plan(path, pkgs, build_cmd, start_cmd, envs, pin_pkgs)?;
-            let json = serde_json::to_string_pretty(&plan)?;
-            println!("{}", json);
+            let output = matches.value_of("output");
+            let format = match matches.value_of("format") {
+                Some(format) => format.parse()?,
+                None => output
+                    .and_then(PlanFormat::from_path)
+                    .unwrap_or(PlanFormat::Json),
+            };
+
+            let plan = nixpacks.path(path).plan()?;
+
+            if let Some(old_plan_path) = matches.value_of("diff") {
+                let old_plan = BuildPlan::from_file(old_plan_path)
+                    .with_context(|| format!("Loading build plan {}", old_plan_path))?;
+                let diff = old_plan.diff(&plan);
+                print!("{}", diff);
+
+                if matches.is_present("exit_code") && !diff.is_empty() {
+                    process::exit(1);
+                }
+                return Ok(());
+            }
+
+            let serialized = plan.to_format(format)?;
+            match output {
+                Some(output) => fs::write(output, serialized)
+                    .with_context(|| format!("Writing build plan to {}", output))?,
+                None => println!("{}", serialized),
+            }
+        }
+        Some(("shell", matches)) => {
+            let path = matches.value_of("PATH").expect("required");
+
+            let plan = match matches.value_of("plan") {
+                Some(plan_path) => BuildPlan::from_file(plan_path)
+                    .with_context(|| format!("Loading build plan {}", plan_path))?,
+                None => nixpacks.path(path).plan()?,
+            };
+            let shell_nix = gen_shell_nix(&plan)?;
+
+            let output = match matches.value_of("output") {
+                Some(output) => PathBuf::from(output),
+                None => {
+                    let output = PathBuf::from(path).join(SHELL_NIX_FILE_NAME);
+                    // Don't replace a shell.nix that is part of the app
+                    if let Ok(existing) = fs::read_to_string(&output) {
+                        if !existing.starts_with(SHELL_NIX_HEADER) {
+                            bail!(
+                                "{} already exists. Pass --output to write the shell.nix somewhere else",
+                                output.display()
+                            );
+                        }
+                    }
+                    output
+                }
+            };
+            fs::write(&output, shell_nix)
+                .with_context(|| format!("Writing {}", output.display()))?;
+
+            if matches.is_present("exec") {
+                let status = process::Command::new("nix-shell")
+                    .arg(&output)
+                    .status()
+                    .context("Running nix-shell")?;
+                process::exit(status.code().unwrap_or(1));
+            }
+            println!("Saved shell.nix to:\n  {}", output.display());
+        }
+        Some(("schema", _)) => {
+            println!("{}", get_plan_schema()?);
+        }
+        Some(("detect", matches)) => {
+            let path = matches.value_of("PATH").expect("required");
+
+            let detections = detect(path, envs, env_files)?;
+            if matches.is_present("json") {
+                let json = serde_json::to_string_pretty(&detections)?;
+                println!("{}", json);
+            } else {
+                for detection in detections {
+                    if detection.matched {
+                        println!(
+                            "=> {} (score {})\n    -> {}",
+                            detection.provider,
+                            detection.score,
+                            detection.reasons.join("\n    -> ")
+                        );
+                    } else {
+                        println!("=> {}\n    -> No match", detection.provider);
+                    }
+                }
+            }
         }
         Some(("build", matches)) => {
             let path = matches.value_of("PATH").expect("required");
-            let name = matches.value_of("name").map(|n| n.to_string());
-            let plan_path = matches.value_of("plan").map(|n| n.to_string());
-            let output_dir = matches.value_of("out").map(|n| n.to_string());
-
-            let tags = matches
-                .values_of("tag")
-                .map(|values| values.collect())
-                .unwrap_or_default();
-
-            let labels = matches
-                .values_of("label")
-                .map(|values| values.collect())
-                .unwrap_or_default();
-
-            build(
-                path, name, pkgs, build_cmd, start_cmd, pin_pkgs, envs, plan_path, output_dir,
-                tags, labels, false,
-            )?;
+            nixpacks = nixpacks
+                .path(path)
+                .no_cache_mounts(matches.is_present("no_cache_mounts"))
+                .use_gitignore(matches.is_present("use_gitignore"))
+                .use_flake(matches.is_present("flake"));
+
+            if let Some(name) = matches.value_of("name") {
+                nixpacks = nixpacks.name(name);
+            }
+            if let Some(plan_path) = matches.value_of("plan") {
+                nixpacks = nixpacks.plan_path(plan_path);
+            }
+            if let Some(out_dir) = matches.value_of("out") {
+                nixpacks = nixpacks.out_dir(out_dir);
+            }
+            if let Some(backend) = matches.value_of("backend") {
+                nixpacks = nixpacks.backend(backend);
+            }
+            if let Some(oci_output) = matches.value_of("output_oci") {
+                nixpacks = nixpacks.oci_output(oci_output);
+            }
+            if let Some(tags) = matches.values_of("tag") {
+                nixpacks = tags.fold(nixpacks, |nixpacks, tag| nixpacks.tag(tag));
+            }
+            if let Some(labels) = matches.values_of("label") {
+                nixpacks = labels.fold(nixpacks, |nixpacks, label| nixpacks.label(label));
+            }
+
+            nixpacks.build()?;
         }
         _ => eprintln!("Invalid command"),
     }
@@ -0,0 +1,62 @@
+use std::{path::Path, process::Command};
+
+use anyhow::Result;
+
+use crate::nixpacks::logger::Logger;
+
+use super::{add_build_args, run_build_command, run_command, ImageBuildOptions, ImageBuilder};
+
+pub struct BuildahImageBuilder {}
+
+impl ImageBuilder for BuildahImageBuilder {
+    fn name(&self) -> &str {
+        "buildah"
+    }
+
+    fn build_image(
+        &self,
+        dir: &Path,
+        options: &ImageBuildOptions,
+        logger: &dyn Logger,
+    ) -> Result<()> {
+        run_build_command(self.get_build_command(dir, options), "Buildah", logger)?;
+
+        if let Some(oci_output) = &options.oci_output {
+            let mut push_cmd = Command::new("buildah");
+            push_cmd
+                .arg("push")
+                .arg(&options.name)
+                .arg(format!("oci-archive:{}", oci_output.display()));
+            run_command(push_cmd, "Buildah", "push", logger)?;
+        }
+
+        Ok(())
+    }
+
+    fn supports_mounts(&self) -> bool {
+        true
+    }
+}
+
+impl BuildahImageBuilder {
+    pub fn get_build_command(&self, dir: &Path, options: &ImageBuildOptions) -> Command {
+        let mut cmd = Command::new("buildah");
+        // Unlike Docker and Podman, Buildah doesn't cache layers by default
+        cmd.arg("build").arg("--layers");
+        add_build_args(&mut cmd, dir, options);
+        cmd
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nixpacks::builders::tests::{get_args, get_options};
+
+    #[test]
+    fn test_buildah_build_command() {
+        let cmd = BuildahImageBuilder {}.get_build_command(Path::new("/tmp/app"), &get_options());
+        assert_eq!(cmd.get_program(), "buildah");
+        assert_eq!(get_args(&cmd)[..4], ["build", "--layers", "-t", "app"]);
+    }
+}
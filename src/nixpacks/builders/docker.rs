@@ -0,0 +1,147 @@
+use std::{path::Path, process::Command};
+
+use anyhow::{bail, Context, Result};
+
+use crate::nixpacks::logger::Logger;
+
+use super::{add_build_args, run_build_command, ImageBuildOptions, ImageBuilder};
+
+/// Creating a builder with the `docker-container` driver, which supports the OCI exporter
+const CREATE_BUILDER_COMMAND: &str = "docker buildx create --use --driver docker-container";
+
+pub struct DockerImageBuilder {}
+
+impl ImageBuilder for DockerImageBuilder {
+    fn name(&self) -> &str {
+        "docker"
+    }
+
+    fn build_image(
+        &self,
+        dir: &Path,
+        options: &ImageBuildOptions,
+        logger: &dyn Logger,
+    ) -> Result<()> {
+        if options.oci_output.is_some() {
+            check_oci_exporter()?;
+        }
+        run_build_command(self.get_build_command(dir, options), "Docker", logger)
+    }
+
+    fn run_command(&self, name: &str) -> Option<String> {
+        Some(format!("docker run -it {}", name))
+    }
+
+    fn supports_mounts(&self) -> bool {
+        true
+    }
+}
+
+impl DockerImageBuilder {
+    pub fn get_build_command(&self, dir: &Path, options: &ImageBuildOptions) -> Command {
+        let mut cmd = Command::new("docker");
+        // The OCI exporter runs on the selected buildx builder, which `docker build` only uses
+        // when buildx is the default builder
+        if options.oci_output.is_some() {
+            cmd.arg("buildx");
+        }
+        cmd.arg("build");
+
+        // Mounts and exporters are only supported by BuildKit
+        if options.buildkit || options.oci_output.is_some() {
+            cmd.env("DOCKER_BUILDKIT", "1");
+        }
+
+        if let Some(oci_output) = &options.oci_output {
+            cmd.arg("--output")
+                .arg(format!("type=oci,dest={}", oci_output.display()));
+        }
+
+        add_build_args(&mut cmd, dir, options);
+        cmd
+    }
+}
+
+/// The default `docker` driver of buildx can't export OCI archives, so fail before building
+/// rather than with "OCI exporter is not supported for the docker driver" afterwards
+fn check_oci_exporter() -> Result<()> {
+    let output = Command::new("docker")
+        .args(["buildx", "inspect"])
+        .output()
+        .context("Running docker buildx inspect")?;
+    if !output.status.success() {
+        bail!(
+            "Saving an OCI archive with Docker needs buildx. Install it, then create a builder with `{}`",
+            CREATE_BUILDER_COMMAND
+        );
+    }
+
+    let inspect = String::from_utf8_lossy(&output.stdout);
+    if get_buildx_driver(&inspect) == Some("docker") {
+        bail!(
+            "The current buildx builder uses the `docker` driver, which can't save OCI archives. Create a builder with `{}`, or use --backend podman",
+            CREATE_BUILDER_COMMAND
+        );
+    }
+    Ok(())
+}
+
+/// Driver of the builder in the output of `docker buildx inspect`
+fn get_buildx_driver(inspect: &str) -> Option<&str> {
+    inspect.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        match key.trim() {
+            "Driver" => Some(value.trim()),
+            _ => None,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nixpacks::builders::tests::{get_args, get_options};
+    use std::ffi::OsStr;
+
+    #[test]
+    fn test_docker_build_command() {
+        let cmd = DockerImageBuilder {}.get_build_command(Path::new("/tmp/app"), &get_options());
+        assert_eq!(cmd.get_program(), "docker");
+        assert_eq!(get_args(&cmd)[..3], ["build", "-t", "app"]);
+        assert!(cmd
+            .get_envs()
+            .any(|(name, value)| name == "DOCKER_BUILDKIT" && value == Some(OsStr::new("1"))));
+    }
+
+    #[test]
+    fn test_docker_oci_output() {
+        let options = ImageBuildOptions {
+            buildkit: false,
+            oci_output: Some("/tmp/image.tar".into()),
+            ..get_options()
+        };
+        let cmd = DockerImageBuilder {}.get_build_command(Path::new("/tmp/app"), &options);
+        assert_eq!(
+            get_args(&cmd)[..4],
+            [
+                "buildx",
+                "build",
+                "--output",
+                "type=oci,dest=/tmp/image.tar"
+            ]
+        );
+        assert_eq!(cmd.get_envs().count(), 1);
+    }
+
+    #[test]
+    fn test_get_buildx_driver() {
+        let inspect =
+            "Name:   default\nDriver: docker\n\nNodes:\nName:      default\nEndpoint:  default\n";
+        assert_eq!(get_buildx_driver(inspect), Some("docker"));
+
+        let inspect = "Name:          builder\nDriver:        docker-container\nLast Activity: 2022-08-01 10:00:00 +0000 UTC\n";
+        assert_eq!(get_buildx_driver(inspect), Some("docker-container"));
+
+        assert_eq!(get_buildx_driver(""), None);
+    }
+}
@@ -0,0 +1,228 @@
+use std::{
+    io::{BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::mpsc,
+    thread,
+};
+
+use anyhow::{bail, Context, Result};
+
+use super::logger::{LogEvent, Logger};
+
+use self::{buildah::BuildahImageBuilder, docker::DockerImageBuilder, podman::PodmanImageBuilder};
+
+pub mod buildah;
+pub mod docker;
+pub mod podman;
+
+pub const DEFAULT_IMAGE_BUILDER: &str = "docker";
+
+/// Everything needed to build an image from a directory with the app source and `Dockerfile`
+#[derive(Debug, Clone, Default)]
+pub struct ImageBuildOptions {
+    pub name: String,
+    pub tags: Vec<String>,
+    pub labels: Vec<String>,
+    /// Values for the `ARG`s of the Dockerfile, sorted by name
+    pub build_args: Vec<(String, String)>,
+    /// Names of the secrets, read from the environment of the build command
+    pub secrets: Vec<String>,
+    /// Values of secrets that are not in the environment of the build command, e.g. ones given
+    /// with `--env`, which are added to it
+    pub secret_values: Vec<(String, String)>,
+    pub quiet: bool,
+    /// The Dockerfile uses features that need BuildKit, such as mounts
+    pub buildkit: bool,
+    /// Save the image as an OCI image layout tarball instead of (or in addition to) storing it
+    /// with the builder
+    pub oci_output: Option<PathBuf>,
+}
+
+/// Tool used to build the image from the generated Dockerfile
+pub trait ImageBuilder {
+    fn name(&self) -> &str;
+
+    fn build_image(
+        &self,
+        dir: &Path,
+        options: &ImageBuildOptions,
+        logger: &dyn Logger,
+    ) -> Result<()>;
+
+    /// Command to run the image, shown after a successful build
+    fn run_command(&self, _name: &str) -> Option<String> {
+        None
+    }
+
+    /// Whether `RUN --mount` instructions (cache and secret mounts) can be built without a
+    /// `# syntax` directive, e.g. by BuildKit or Buildah
+    fn supports_mounts(&self) -> bool {
+        false
+    }
+}
+
+/// Get one of the built-in image builders by name
+pub fn get_image_builder(name: &str) -> Result<Box<dyn ImageBuilder>> {
+    match name {
+        "docker" => Ok(Box::new(DockerImageBuilder {})),
+        "podman" => Ok(Box::new(PodmanImageBuilder {})),
+        "buildah" => Ok(Box::new(BuildahImageBuilder {})),
+        _ => bail!(
+            "Unknown image builder `{}`. Expected one of docker, podman, buildah",
+            name
+        ),
+    }
+}
+
+/// Arguments shared by every CLI that accepts `docker build` options
+fn add_build_args(cmd: &mut Command, dir: &Path, options: &ImageBuildOptions) {
+    cmd.arg("-t").arg(&options.name);
+
+    if options.quiet {
+        cmd.arg("--quiet");
+    }
+
+    for name in &options.secrets {
+        cmd.arg("--secret").arg(format!("id={},env={}", name, name));
+    }
+    for (name, value) in &options.secret_values {
+        cmd.env(name, value);
+    }
+
+    for (name, value) in &options.build_args {
+        cmd.arg("--build-arg").arg(format!("{}={}", name, value));
+    }
+
+    // Add user defined tags and labels to the image
+    for tag in &options.tags {
+        cmd.arg("-t").arg(tag);
+    }
+    for label in &options.labels {
+        cmd.arg("--label").arg(label);
+    }
+
+    cmd.arg(dir);
+}
+
+fn run_build_command(cmd: Command, builder: &str, logger: &dyn Logger) -> Result<()> {
+    run_command(cmd, builder, "build", logger)
+}
+
+fn run_command(mut cmd: Command, builder: &str, action: &str, logger: &dyn Logger) -> Result<()> {
+    if !logger.captures_build_output() {
+        let status = cmd
+            .spawn()
+            .with_context(|| format!("Running {}", builder))?
+            .wait()
+            .with_context(|| format!("Running {} {}", builder, action))?;
+        if !status.success() {
+            bail!("{} {} failed", builder, action);
+        }
+        return Ok(());
+    }
+
+    let mut child = cmd
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Running {}", builder))?;
+
+    // Read stdout and stderr at the same time so neither of the pipes fills up
+    let (sender, receiver) = mpsc::channel();
+    let outputs: Vec<Box<dyn Read + Send>> = vec![
+        Box::new(child.stdout.take().context("Reading build output")?),
+        Box::new(child.stderr.take().context("Reading build output")?),
+    ];
+    for output in outputs {
+        let sender = sender.clone();
+        thread::spawn(move || {
+            for line in BufReader::new(output).lines().map_while(Result::ok) {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    drop(sender);
+
+    for line in receiver {
+        logger.log(LogEvent::BuildOutput { line });
+    }
+
+    let status = child
+        .wait()
+        .with_context(|| format!("Running {} {}", builder, action))?;
+    if !status.success() {
+        bail!("{} {} failed", builder, action);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    pub fn get_args(cmd: &Command) -> Vec<String> {
+        cmd.get_args()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect()
+    }
+
+    pub fn get_options() -> ImageBuildOptions {
+        ImageBuildOptions {
+            name: "app".to_string(),
+            tags: vec!["app:latest".to_string()],
+            labels: vec!["team=web".to_string()],
+            build_args: vec![("NODE_ENV".to_string(), "production".to_string())],
+            secrets: vec!["NPM_TOKEN".to_string()],
+            secret_values: Vec::new(),
+            quiet: true,
+            buildkit: true,
+            oci_output: None,
+        }
+    }
+
+    #[test]
+    fn test_build_args() {
+        let mut cmd = Command::new("docker");
+        add_build_args(&mut cmd, Path::new("/tmp/app"), &get_options());
+        assert_eq!(
+            get_args(&cmd),
+            vec![
+                "-t",
+                "app",
+                "--quiet",
+                "--secret",
+                "id=NPM_TOKEN,env=NPM_TOKEN",
+                "--build-arg",
+                "NODE_ENV=production",
+                "-t",
+                "app:latest",
+                "--label",
+                "team=web",
+                "/tmp/app"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_secret_values() {
+        let options = ImageBuildOptions {
+            secret_values: vec![("NPM_TOKEN".to_string(), "token".to_string())],
+            ..get_options()
+        };
+        let mut cmd = Command::new("docker");
+        add_build_args(&mut cmd, Path::new("/tmp/app"), &options);
+        assert!(cmd
+            .get_envs()
+            .any(|(name, value)| name == "NPM_TOKEN" && value == Some("token".as_ref())));
+    }
+
+    #[test]
+    fn test_get_image_builder() {
+        assert_eq!(get_image_builder("podman").unwrap().name(), "podman");
+        assert!(get_image_builder("kaniko").is_err());
+    }
+}
@@ -0,0 +1,69 @@
+use std::{path::Path, process::Command};
+
+use anyhow::Result;
+
+use crate::nixpacks::logger::Logger;
+
+use super::{add_build_args, run_build_command, run_command, ImageBuildOptions, ImageBuilder};
+
+pub struct PodmanImageBuilder {}
+
+impl ImageBuilder for PodmanImageBuilder {
+    fn name(&self) -> &str {
+        "podman"
+    }
+
+    fn build_image(
+        &self,
+        dir: &Path,
+        options: &ImageBuildOptions,
+        logger: &dyn Logger,
+    ) -> Result<()> {
+        run_build_command(self.get_build_command(dir, options), "Podman", logger)?;
+
+        if let Some(oci_output) = &options.oci_output {
+            let mut save_cmd = Command::new("podman");
+            save_cmd
+                .arg("save")
+                .arg("--format")
+                .arg("oci-archive")
+                .arg("-o")
+                .arg(oci_output)
+                .arg(&options.name);
+            run_command(save_cmd, "Podman", "save", logger)?;
+        }
+
+        Ok(())
+    }
+
+    fn run_command(&self, name: &str) -> Option<String> {
+        Some(format!("podman run -it {}", name))
+    }
+
+    fn supports_mounts(&self) -> bool {
+        true
+    }
+}
+
+impl PodmanImageBuilder {
+    pub fn get_build_command(&self, dir: &Path, options: &ImageBuildOptions) -> Command {
+        let mut cmd = Command::new("podman");
+        cmd.arg("build");
+        add_build_args(&mut cmd, dir, options);
+        cmd
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nixpacks::builders::tests::{get_args, get_options};
+
+    #[test]
+    fn test_podman_build_command() {
+        let cmd = PodmanImageBuilder {}.get_build_command(Path::new("/tmp/app"), &get_options());
+        assert_eq!(cmd.get_program(), "podman");
+        assert_eq!(get_args(&cmd)[..3], ["build", "-t", "app"]);
+        assert_eq!(cmd.get_envs().count(), 0);
+    }
+}
@@ -0,0 +1,246 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    app::App,
+    environment::{EnvironmentVariables, VariableScopes},
+    phase::Phase,
+};
+
+pub const CONFIG_FILE_NAME: &str = "nixpacks.toml";
+
+/// Project level configuration read from a `nixpacks.toml` file at the app root
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct NixpacksConfig {
+    pub provider: Option<String>,
+    /// Providers to combine, the first one starts the app
+    pub providers: Option<Vec<String>>,
+    pub backend: Option<String>,
+    pub variables: Option<EnvironmentVariables>,
+
+    /// Scope of the variables of the plan, overriding the scopes set by the providers
+    #[serde(rename = "variableScopes")]
+    pub variable_scopes: Option<VariableScopes>,
+
+    pub secrets: Option<Vec<String>>,
+    pub setup: Option<SetupConfig>,
+    pub install: Option<PhaseConfig>,
+    pub build: Option<PhaseConfig>,
+    pub phases: Option<Vec<Phase>>,
+    pub start: Option<StartConfig>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SetupConfig {
+    pub pkgs: Option<Vec<String>>,
+    pub archive: Option<String>,
+
+    #[serde(rename = "archiveHash")]
+    pub archive_hash: Option<String>,
+
+    /// nixpkgs revisions to install packages from, by package name
+    pub archives: Option<BTreeMap<String, ArchivePin>>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ArchivePin {
+    pub archive: String,
+
+    #[serde(rename = "archiveHash")]
+    pub archive_hash: Option<String>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PhaseConfig {
+    pub cmd: Option<String>,
+
+    #[serde(rename = "dependsOn")]
+    pub depends_on: Option<Vec<String>>,
+
+    #[serde(rename = "cacheDirectories")]
+    pub cache_directories: Option<Vec<String>>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct StartConfig {
+    pub cmd: Option<String>,
+
+    #[serde(rename = "runImage")]
+    pub run_image: Option<String>,
+}
+
+impl NixpacksConfig {
+    /// Load the config file from the app source, falling back to an empty config
+    pub fn from_app(app: &App) -> Result<NixpacksConfig> {
+        if app.includes_file(CONFIG_FILE_NAME) {
+            app.read_toml(CONFIG_FILE_NAME)
+                .with_context(|| format!("Reading {}", CONFIG_FILE_NAME))
+        } else {
+            Ok(NixpacksConfig::default())
+        }
+    }
+
+    pub fn get_pkgs(&self) -> Vec<String> {
+        self.setup
+            .as_ref()
+            .and_then(|setup| setup.pkgs.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn get_archive(&self) -> Option<String> {
+        self.setup.as_ref().and_then(|setup| setup.archive.clone())
+    }
+
+    pub fn get_archive_hash(&self) -> Option<String> {
+        self.setup
+            .as_ref()
+            .and_then(|setup| setup.archive_hash.clone())
+    }
+
+    pub fn get_archive_pins(&self) -> BTreeMap<String, ArchivePin> {
+        self.setup
+            .as_ref()
+            .and_then(|setup| setup.archives.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn get_install_cmd(&self) -> Option<String> {
+        self.install
+            .as_ref()
+            .and_then(|install| install.cmd.clone())
+    }
+
+    pub fn get_install_depends_on(&self) -> Option<Vec<String>> {
+        self.install
+            .as_ref()
+            .and_then(|install| install.depends_on.clone())
+    }
+
+    pub fn get_install_cache_directories(&self) -> Option<Vec<String>> {
+        self.install
+            .as_ref()
+            .and_then(|install| install.cache_directories.clone())
+    }
+
+    pub fn get_build_cmd(&self) -> Option<String> {
+        self.build.as_ref().and_then(|build| build.cmd.clone())
+    }
+
+    pub fn get_build_depends_on(&self) -> Option<Vec<String>> {
+        self.build
+            .as_ref()
+            .and_then(|build| build.depends_on.clone())
+    }
+
+    pub fn get_build_cache_directories(&self) -> Option<Vec<String>> {
+        self.build
+            .as_ref()
+            .and_then(|build| build.cache_directories.clone())
+    }
+
+    pub fn get_start_cmd(&self) -> Option<String> {
+        self.start.as_ref().and_then(|start| start.cmd.clone())
+    }
+
+    pub fn get_run_image(&self) -> Option<String> {
+        self.start
+            .as_ref()
+            .and_then(|start| start.run_image.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nixpacks::environment::VariableScope;
+
+    #[test]
+    fn test_read_config_file() -> Result<()> {
+        let config = NixpacksConfig::from_app(&App::new("./examples/config-file")?)?;
+        assert_eq!(config.provider, Some("node".to_string()));
+        assert_eq!(config.get_pkgs(), vec!["cowsay".to_string()]);
+        assert_eq!(config.get_build_cmd(), Some("npm run compile".to_string()));
+        assert_eq!(
+            config.variables.unwrap().get("GREETING"),
+            Some(&"hello".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_variable_scopes() -> Result<()> {
+        let config: NixpacksConfig = toml::from_str(
+            r#"
+            [variables]
+            API_URL = "https://example.com"
+
+            [variableScopes]
+            API_URL = "runtime"
+            "#,
+        )?;
+        assert_eq!(
+            config.variable_scopes,
+            Some(VariableScopes::from([(
+                "API_URL".to_string(),
+                VariableScope::Runtime
+            )]))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_config_file() -> Result<()> {
+        let config = NixpacksConfig::from_app(&App::new("./examples/npm")?)?;
+        assert!(config.provider.is_none());
+        assert!(config.get_pkgs().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_named_phases() -> Result<()> {
+        let config: NixpacksConfig = toml::from_str(
+            r#"
+            [build]
+            dependsOn = ["codegen"]
+
+            [[phases]]
+            name = "codegen"
+            cmd = "npm run codegen"
+            dependsOn = ["install"]
+            "#,
+        )?;
+        assert_eq!(
+            config.get_build_depends_on(),
+            Some(vec!["codegen".to_string()])
+        );
+
+        let phases = config.phases.unwrap();
+        assert_eq!(phases[0].name, "codegen");
+        assert_eq!(phases[0].cmd, Some("npm run codegen".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_secrets() -> Result<()> {
+        let config: NixpacksConfig = toml::from_str("secrets = [\"NPM_TOKEN\"]")?;
+        assert_eq!(config.secrets, Some(vec!["NPM_TOKEN".to_string()]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_config_field() {
+        assert!(toml::from_str::<NixpacksConfig>("[setup]\npackages = [\"cowsay\"]").is_err());
+    }
+}
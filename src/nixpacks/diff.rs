@@ -0,0 +1,299 @@
+use std::fmt;
+
+use serde::Serialize;
+
+use super::{
+    phase::{BUILD_PHASE_NAME, INSTALL_PHASE_NAME, START_PHASE_NAME},
+    plan::BuildPlan,
+};
+
+/// A single difference between two build plans
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "change", rename_all = "camelCase")]
+pub enum PlanChange {
+    PackageAdded {
+        pkg: String,
+    },
+    PackageRemoved {
+        pkg: String,
+    },
+    /// The command of a phase changed, or the phase was added or removed
+    CommandChanged {
+        phase: String,
+        old: Option<String>,
+        new: Option<String>,
+    },
+    VariableAdded {
+        name: String,
+        value: String,
+    },
+    VariableRemoved {
+        name: String,
+    },
+    VariableChanged {
+        name: String,
+        old: String,
+        new: String,
+    },
+    RunImageChanged {
+        old: Option<String>,
+        new: Option<String>,
+    },
+}
+
+/// Differences between an old and a new build plan, created with `BuildPlan::diff`
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct PlanDiff {
+    pub changes: Vec<PlanChange>,
+}
+
+impl PlanDiff {
+    pub fn new(old: &BuildPlan, new: &BuildPlan) -> PlanDiff {
+        let mut changes = Vec::new();
+
+        // Packages
+        let old_pkgs = get_pkgs(old);
+        let new_pkgs = get_pkgs(new);
+        for pkg in &new_pkgs {
+            if !old_pkgs.contains(pkg) {
+                changes.push(PlanChange::PackageAdded { pkg: pkg.clone() });
+            }
+        }
+        for pkg in &old_pkgs {
+            if !new_pkgs.contains(pkg) {
+                changes.push(PlanChange::PackageRemoved { pkg: pkg.clone() });
+            }
+        }
+
+        // Commands, in the order the phases are declared in the old plan
+        let old_cmds = get_cmds(old);
+        let new_cmds = get_cmds(new);
+        let mut phases = old_cmds.iter().map(|(name, _)| name).collect::<Vec<_>>();
+        for (name, _) in &new_cmds {
+            if !phases.contains(&name) {
+                phases.push(name);
+            }
+        }
+        for phase in phases {
+            let old_cmd = find_cmd(&old_cmds, phase);
+            let new_cmd = find_cmd(&new_cmds, phase);
+            if old_cmd != new_cmd {
+                changes.push(PlanChange::CommandChanged {
+                    phase: phase.clone(),
+                    old: old_cmd,
+                    new: new_cmd,
+                });
+            }
+        }
+
+        // Variables, sorted by name
+        let old_variables = old.variables.clone().unwrap_or_default();
+        let new_variables = new.variables.clone().unwrap_or_default();
+        let mut names = old_variables
+            .keys()
+            .chain(new_variables.keys())
+            .cloned()
+            .collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        for name in names {
+            match (old_variables.get(&name), new_variables.get(&name)) {
+                (None, Some(value)) => changes.push(PlanChange::VariableAdded {
+                    name,
+                    value: value.clone(),
+                }),
+                (Some(_), None) => changes.push(PlanChange::VariableRemoved { name }),
+                (Some(old), Some(new)) if old != new => changes.push(PlanChange::VariableChanged {
+                    name,
+                    old: old.clone(),
+                    new: new.clone(),
+                }),
+                _ => {}
+            }
+        }
+
+        // Run image
+        let old_run_image = old.start.as_ref().and_then(|start| start.run_image.clone());
+        let new_run_image = new.start.as_ref().and_then(|start| start.run_image.clone());
+        if old_run_image != new_run_image {
+            changes.push(PlanChange::RunImageChanged {
+                old: old_run_image,
+                new: new_run_image,
+            });
+        }
+
+        PlanDiff { changes }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+fn get_pkgs(plan: &BuildPlan) -> Vec<String> {
+    plan.setup
+        .as_ref()
+        .map(|setup| {
+            setup
+                .pkgs
+                .iter()
+                .map(|pkg| pkg.to_pretty_string())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Name and command of every phase with a command
+fn get_cmds(plan: &BuildPlan) -> Vec<(String, String)> {
+    let mut cmds = vec![
+        (
+            INSTALL_PHASE_NAME.to_string(),
+            plan.install.as_ref().and_then(|phase| phase.cmd.clone()),
+        ),
+        (
+            BUILD_PHASE_NAME.to_string(),
+            plan.build.as_ref().and_then(|phase| phase.cmd.clone()),
+        ),
+    ];
+    for phase in plan.phases.iter().flatten() {
+        cmds.push((phase.name.clone(), phase.cmd.clone()));
+    }
+    cmds.push((
+        START_PHASE_NAME.to_string(),
+        plan.start.as_ref().and_then(|phase| phase.cmd.clone()),
+    ));
+
+    cmds.into_iter()
+        .filter_map(|(name, cmd)| cmd.map(|cmd| (name, cmd)))
+        .collect()
+}
+
+fn find_cmd(cmds: &[(String, String)], phase: &str) -> Option<String> {
+    cmds.iter()
+        .find(|(name, _)| name == phase)
+        .map(|(_, cmd)| cmd.clone())
+}
+
+fn fmt_optional(value: &Option<String>) -> String {
+    match value {
+        Some(value) => format!("`{}`", value),
+        None => "(none)".to_string(),
+    }
+}
+
+impl fmt::Display for PlanChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlanChange::PackageAdded { pkg } => write!(f, "+ package {}", pkg),
+            PlanChange::PackageRemoved { pkg } => write!(f, "- package {}", pkg),
+            PlanChange::CommandChanged {
+                phase,
+                old: None,
+                new: Some(new),
+            } => write!(f, "+ {} command `{}`", phase, new),
+            PlanChange::CommandChanged {
+                phase,
+                old: Some(old),
+                new: None,
+            } => write!(f, "- {} command `{}`", phase, old),
+            PlanChange::CommandChanged { phase, old, new } => write!(
+                f,
+                "~ {} command {} -> {}",
+                phase,
+                fmt_optional(old),
+                fmt_optional(new)
+            ),
+            PlanChange::VariableAdded { name, value } => {
+                write!(f, "+ variable {}={}", name, value)
+            }
+            PlanChange::VariableRemoved { name } => write!(f, "- variable {}", name),
+            PlanChange::VariableChanged { name, old, new } => {
+                write!(f, "~ variable {}: {} -> {}", name, old, new)
+            }
+            PlanChange::RunImageChanged { old, new } => write!(
+                f,
+                "~ run image {} -> {}",
+                fmt_optional(old),
+                fmt_optional(new)
+            ),
+        }
+    }
+}
+
+impl fmt::Display for PlanDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "No changes");
+        }
+
+        for change in &self.changes {
+            writeln!(f, "{}", change)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nixpacks::{
+        environment::EnvironmentVariables,
+        nix::Pkg,
+        phase::{BuildPhase, InstallPhase, Phase, SetupPhase, StartPhase},
+    };
+
+    fn get_plan() -> BuildPlan {
+        BuildPlan {
+            version: None,
+            schema_version: None,
+            setup: Some(SetupPhase::new(vec![Pkg::new("nodejs"), Pkg::new("yarn")])),
+            install: Some(InstallPhase::new("yarn install".to_string())),
+            build: Some(BuildPhase::new("yarn build".to_string())),
+            phases: None,
+            start: Some(StartPhase::new("yarn start".to_string())),
+            variables: Some(EnvironmentVariables::from([
+                ("NODE_ENV".to_string(), "production".to_string()),
+                ("PORT".to_string(), "3000".to_string()),
+            ])),
+            variable_scopes: None,
+            secrets: None,
+        }
+    }
+
+    #[test]
+    fn test_diff_same_plan() {
+        let diff = get_plan().diff(&get_plan());
+        assert!(diff.is_empty());
+        assert_eq!(diff.to_string(), "No changes\n");
+    }
+
+    #[test]
+    fn test_diff_changes() {
+        let mut new = get_plan();
+        new.setup = Some(SetupPhase::new(vec![
+            Pkg::new("nodejs"),
+            Pkg::new("cowsay"),
+        ]));
+        new.build = Some(BuildPhase::new("yarn build --prod".to_string()));
+        new.phases = Some(vec![Phase::new("test", "yarn test".to_string())]);
+        new.variables = Some(EnvironmentVariables::from([
+            ("NODE_ENV".to_string(), "development".to_string()),
+            ("DEBUG".to_string(), "1".to_string()),
+        ]));
+        new.start.as_mut().unwrap().run_image = Some("debian:bullseye-slim".to_string());
+
+        assert_eq!(
+            get_plan().diff(&new).to_string(),
+            indoc::indoc! {"
+                + package cowsay
+                - package yarn
+                ~ build command `yarn build` -> `yarn build --prod`
+                + test command `yarn test`
+                + variable DEBUG=1
+                ~ variable NODE_ENV: production -> development
+                - variable PORT
+                ~ run image (none) -> `debian:bullseye-slim`
+            "}
+        );
+    }
+}
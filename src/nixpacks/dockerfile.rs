@@ -0,0 +1,321 @@
+use std::fmt;
+
+/// Directory that BuildKit mounts secrets into by default
+const SECRETS_DIR: &str = "/run/secrets";
+
+/// A single Dockerfile instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Instruction {
+    /// Starts a new section of the Dockerfile
+    Comment(String),
+    Workdir(String),
+    Arg(Vec<String>),
+    /// Variables set to literal values, which are quoted and escaped when rendered
+    Env(Vec<(String, String)>),
+    /// Variables whose values can reference other variables with `$NAME`, e.g. `$PATH`
+    EnvExpand(Vec<(String, String)>),
+    Copy {
+        from: Option<String>,
+        sources: Vec<String>,
+        dest: String,
+    },
+    Run {
+        mounts: Vec<Mount>,
+        cmd: String,
+    },
+    Cmd(String),
+}
+
+/// A BuildKit mount that is only available while a `RUN` instruction runs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mount {
+    /// Directory that persists between builds
+    Cache { target: String },
+    /// Secret mounted as a file, which the command exports as an environment variable of the same
+    /// name. The `env` option of secret mounts needs a newer Dockerfile frontend.
+    Secret { id: String },
+}
+
+/// A build stage, starting with `FROM image`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stage {
+    pub image: String,
+    pub name: Option<String>,
+    pub instructions: Vec<Instruction>,
+}
+
+/// Intermediate representation of a Dockerfile that can be inspected and amended before it is
+/// rendered with `to_string()`
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Dockerfile {
+    /// Remote frontend used to parse the Dockerfile, for features the image builder lacks
+    pub syntax: Option<String>,
+    pub stages: Vec<Stage>,
+}
+
+impl Instruction {
+    /// A `RUN` instruction without any mounts
+    pub fn run(cmd: &str) -> Self {
+        Instruction::Run {
+            mounts: Vec::new(),
+            cmd: cmd.to_string(),
+        }
+    }
+}
+
+impl Stage {
+    pub fn new(image: &str) -> Self {
+        Self {
+            image: image.to_string(),
+            name: None,
+            instructions: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, instruction: Instruction) {
+        self.instructions.push(instruction);
+    }
+
+    /// Add a `COPY` of files from the build context, if there are any
+    pub fn add_copy(&mut self, sources: Vec<String>, dest: &str) {
+        if !sources.is_empty() {
+            self.add(Instruction::Copy {
+                from: None,
+                sources,
+                dest: dest.to_string(),
+            });
+        }
+    }
+}
+
+impl Dockerfile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_stage(&mut self, stage: Stage) {
+        self.stages.push(stage);
+    }
+
+    /// The stage that produces the image
+    pub fn final_stage_mut(&mut self) -> Option<&mut Stage> {
+        self.stages.last_mut()
+    }
+
+    /// Whether any instruction needs BuildKit to build
+    pub fn uses_mounts(&self) -> bool {
+        self.instructions().any(|instruction| {
+            matches!(instruction, Instruction::Run { mounts, .. } if !mounts.is_empty())
+        })
+    }
+
+    /// Every instruction across all stages
+    pub fn instructions(&self) -> impl Iterator<Item = &Instruction> {
+        self.stages
+            .iter()
+            .flat_map(|stage| stage.instructions.iter())
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Instruction::Comment(comment) => write!(f, "# {}", comment),
+            Instruction::Workdir(dir) => write!(f, "WORKDIR {}", dir),
+            Instruction::Arg(names) => write!(f, "ARG {}", names.join(" ")),
+            Instruction::Env(variables) => write_env(f, variables, &['\\', '"', '$']),
+            Instruction::EnvExpand(variables) => write_env(f, variables, &['\\', '"']),
+            Instruction::Copy {
+                from,
+                sources,
+                dest,
+            } => {
+                write!(f, "COPY ")?;
+                if let Some(from) = from {
+                    write!(f, "--from={} ", from)?;
+                }
+                write!(f, "{} {}", sources.join(" "), dest)
+            }
+            Instruction::Run { mounts, cmd } => {
+                write!(f, "RUN ")?;
+                for mount in mounts {
+                    write!(f, "{} ", mount)?;
+                }
+                for mount in mounts {
+                    if let Mount::Secret { id } = mount {
+                        write!(f, "export {}=\"$(cat {}/{})\" && ", id, SECRETS_DIR, id)?;
+                    }
+                }
+                write!(f, "{}", cmd)
+            }
+            Instruction::Cmd(cmd) => write!(f, "CMD {}", cmd),
+        }
+    }
+}
+
+/// Write an `ENV` instruction, quoting values that the Dockerfile parser would otherwise split or
+/// expand and escaping the `escaped` characters inside them
+fn write_env(
+    f: &mut fmt::Formatter<'_>,
+    variables: &[(String, String)],
+    escaped: &[char],
+) -> fmt::Result {
+    let variables = variables
+        .iter()
+        .map(|(name, value)| {
+            let is_plain = |c: char| {
+                c.is_ascii_alphanumeric()
+                    || "_-./:,@%+=".contains(c)
+                    || (c == '$' && !escaped.contains(&c))
+            };
+            if !value.is_empty() && value.chars().all(is_plain) {
+                return format!("{}={}", name, value);
+            }
+
+            let mut quoted = String::with_capacity(value.len() + 2);
+            for c in value.chars() {
+                if escaped.contains(&c) {
+                    quoted.push('\\');
+                }
+                quoted.push(c);
+            }
+            format!("{}=\"{}\"", name, quoted)
+        })
+        .collect::<Vec<_>>();
+
+    write!(f, "ENV {}", variables.join(" "))
+}
+
+impl fmt::Display for Mount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mount::Cache { target } => write!(f, "--mount=type=cache,target={}", target),
+            Mount::Secret { id } => write!(f, "--mount=type=secret,id={}", id),
+        }
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "FROM {}", self.image)?;
+        if let Some(name) = &self.name {
+            write!(f, " AS {}", name)?;
+        }
+        writeln!(f)?;
+
+        for instruction in &self.instructions {
+            // Separate sections with an empty line
+            if let Instruction::Comment(_) = instruction {
+                writeln!(f)?;
+            }
+            writeln!(f, "{}", instruction)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Dockerfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stages = self
+            .stages
+            .iter()
+            .map(|stage| stage.to_string())
+            .collect::<Vec<_>>();
+
+        if let Some(syntax) = &self.syntax {
+            writeln!(f, "# syntax={}", syntax)?;
+        }
+        write!(f, "{}", stages.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_instructions() {
+        assert_eq!(
+            Instruction::EnvExpand(vec![
+                ("A".to_string(), "$A".to_string()),
+                ("B".to_string(), "b".to_string())
+            ])
+            .to_string(),
+            "ENV A=$A B=b"
+        );
+        assert_eq!(
+            Instruction::EnvExpand(vec![("PATH".to_string(), "/my bin:$PATH".to_string())])
+                .to_string(),
+            "ENV PATH=\"/my bin:$PATH\""
+        );
+        assert_eq!(
+            Instruction::Env(vec![
+                ("GREETING".to_string(), "hello world".to_string()),
+                ("PRICE".to_string(), "$5 \"off\"".to_string()),
+                ("EMPTY".to_string(), String::new()),
+                ("B".to_string(), "b".to_string())
+            ])
+            .to_string(),
+            "ENV GREETING=\"hello world\" PRICE=\"\\$5 \\\"off\\\"\" EMPTY=\"\" B=b"
+        );
+        assert_eq!(
+            Instruction::Copy {
+                from: Some("0".to_string()),
+                sources: vec!["/app/".to_string()],
+                dest: "/app/".to_string()
+            }
+            .to_string(),
+            "COPY --from=0 /app/ /app/"
+        );
+        assert_eq!(
+            Instruction::Run {
+                mounts: vec![Mount::Cache {
+                    target: "/root/.npm".to_string()
+                }],
+                cmd: "npm ci".to_string()
+            }
+            .to_string(),
+            "RUN --mount=type=cache,target=/root/.npm npm ci"
+        );
+        assert_eq!(
+            Mount::Secret {
+                id: "NPM_TOKEN".to_string()
+            }
+            .to_string(),
+            "--mount=type=secret,id=NPM_TOKEN"
+        );
+        assert_eq!(
+            Instruction::Run {
+                mounts: vec![Mount::Secret {
+                    id: "NPM_TOKEN".to_string()
+                }],
+                cmd: "npm ci".to_string()
+            }
+            .to_string(),
+            "RUN --mount=type=secret,id=NPM_TOKEN export NPM_TOKEN=\"$(cat /run/secrets/NPM_TOKEN)\" && npm ci"
+        );
+    }
+
+    #[test]
+    fn test_render_dockerfile() {
+        let mut build = Stage::new("node");
+        build.add(Instruction::Workdir("/app/".to_string()));
+        build.add(Instruction::Comment("Build".to_string()));
+        build.add_copy(vec![".".to_string()], "/app/");
+        build.add_copy(Vec::new(), "/app/");
+        build.add(Instruction::run("npm run build"));
+
+        let mut run = Stage::new("debian");
+        run.add(Instruction::Cmd("node index.js".to_string()));
+
+        let mut dockerfile = Dockerfile::new();
+        dockerfile.add_stage(build);
+        dockerfile.add_stage(run);
+
+        assert_eq!(
+            dockerfile.to_string(),
+            "FROM node\nWORKDIR /app/\n\n# Build\nCOPY . /app/\nRUN npm run build\n\nFROM debian\nCMD node index.js\n"
+        );
+    }
+}
@@ -1,7 +1,35 @@
 use std::collections::HashMap;
 
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
 pub type EnvironmentVariables = HashMap<String, String>;
 
+pub type VariableScopes = HashMap<String, VariableScope>;
+
+/// When a variable is available in the image
+#[derive(Serialize, Deserialize, JsonSchema, Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum VariableScope {
+    /// Only while installing and building the app (e.g. `NPM_CONFIG_PRODUCTION`)
+    Build,
+    /// Only in the container that runs the app
+    Runtime,
+    /// While building and running the app
+    #[default]
+    Both,
+}
+
+impl VariableScope {
+    pub fn is_build(&self) -> bool {
+        matches!(self, VariableScope::Build | VariableScope::Both)
+    }
+
+    pub fn is_runtime(&self) -> bool {
+        matches!(self, VariableScope::Runtime | VariableScope::Both)
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct Environment {
     variables: EnvironmentVariables,
@@ -0,0 +1,509 @@
+use anyhow::{bail, Context, Result};
+use indoc::formatdoc;
+use serde_json::{json, Map, Value};
+
+use super::{
+    nix::{archive_import_name, get_archive_hash, get_pkg_archives, is_revision},
+    phase::SetupPhase,
+    plan::BuildPlan,
+};
+
+pub const FLAKE_FILE_NAME: &str = "flake.nix";
+pub const FLAKE_LOCK_FILE_NAME: &str = "flake.lock";
+
+/// Directory of the generated flake, so a `flake.nix` of the app itself is left alone
+pub const FLAKE_DIR: &str = ".nixpacks";
+
+/// Profile the flake environment is installed into, separate from the profile of the base image
+/// that is managed by `nix-env`
+pub const FLAKE_PROFILE: &str = "/nix/var/nix/profiles/nixpacks";
+
+/// Systems the flake builds the environment for
+const FLAKE_SYSTEMS: &[&str] = &["x86_64-linux", "aarch64-linux"];
+
+/// A flake input and how it is locked
+struct FlakeInput {
+    name: String,
+    url: String,
+    flake: bool,
+    locked: Map<String, Value>,
+}
+
+impl FlakeInput {
+    fn is_hashed(&self) -> bool {
+        self.locked.contains_key("narHash")
+    }
+}
+
+/// Create a `flake.nix` with the packages of the setup phase as its default package
+///
+/// Flakes can't use the channel of the base image, so plans without an archive are pinned to
+/// `default_archive`.
+pub fn gen_flake(plan: &BuildPlan, default_archive: &str) -> Result<String> {
+    let setup_phase = plan.setup.clone().unwrap_or_default();
+    let inputs = get_inputs(&setup_phase, default_archive, None)?;
+
+    let input_lines = inputs
+        .iter()
+        .map(|input| match input.flake {
+            true => format!("{}.url = \"{}\";", input.name, input.url),
+            false => format!(
+                "{} = {{ url = \"{}\"; flake = false; }};",
+                input.name, input.url
+            ),
+        })
+        .collect::<Vec<_>>()
+        .join("\n    ");
+
+    let input_names = inputs
+        .iter()
+        .map(|input| input.name.clone())
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let overlays = inputs
+        .iter()
+        .filter(|input| !input.flake)
+        .map(|input| format!("(import {}) ", input.name))
+        .collect::<String>();
+
+    // Packages pinned to other archives are selected from their own import of nixpkgs
+    let archives = get_pkg_archives(
+        &setup_phase.pkgs,
+        Some(&get_archive(&setup_phase, default_archive)),
+    );
+    let archive_imports = (0..archives.len())
+        .map(|i| {
+            format!(
+                "\n          {} = import {} {{ inherit system; overlays = [ {}]; }};",
+                archive_import_name(i),
+                archive_input_name(i),
+                overlays
+            )
+        })
+        .collect::<String>();
+
+    let pkgs = setup_phase
+        .pkgs
+        .iter()
+        .map(|p| p.to_nix_string_with_archives(&archives))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let systems = FLAKE_SYSTEMS
+        .iter()
+        .map(|system| format!("\"{}\"", system))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let flake = formatdoc! {"
+        {{
+          inputs = {{
+            {input_lines}
+          }};
+
+          outputs = {{ self, {input_names} }}:
+            let
+              forAllSystems = nixpkgs.lib.genAttrs [ {systems} ];
+            in {{
+              packages = forAllSystems (system:
+                let
+                  pkgs = import nixpkgs {{
+                    inherit system;
+                    overlays = [ {overlays}];
+                  }};{archive_imports}
+                in {{
+                  default = with pkgs; buildEnv {{
+                    name = \"env\";
+                    paths = [ {pkgs} ];
+                  }};
+                }});
+            }};
+        }}
+    ",
+    input_lines=input_lines,
+    input_names=input_names,
+    systems=systems,
+    overlays=overlays,
+    archive_imports=archive_imports,
+    pkgs=pkgs};
+
+    Ok(flake)
+}
+
+/// Create the `flake.lock` for `gen_flake`, so Nix doesn't need to resolve any of the inputs
+///
+/// nixpkgs is locked to the revision of the archive, with the `archiveHash` of the setup phase as
+/// its NAR hash, or `default_archive_hash` when it is the default archive. Overlays are locked
+/// with their `overlayHash`. Inputs without a hash are still locked to a revision, see
+/// `get_unhashed_inputs`.
+pub fn gen_flake_lock(
+    plan: &BuildPlan,
+    default_archive: &str,
+    default_archive_hash: Option<&str>,
+) -> Result<String> {
+    let setup_phase = plan.setup.clone().unwrap_or_default();
+    let inputs = get_inputs(&setup_phase, default_archive, default_archive_hash)?;
+
+    let mut nodes = Map::new();
+    let mut root_inputs = Map::new();
+    for input in inputs {
+        let mut original = input.locked.clone();
+        original.remove("narHash");
+
+        let mut node = Map::new();
+        if !input.flake {
+            node.insert("flake".to_string(), json!(false));
+        }
+        node.insert("locked".to_string(), Value::Object(input.locked));
+        node.insert("original".to_string(), Value::Object(original));
+
+        root_inputs.insert(input.name.clone(), json!(input.name));
+        nodes.insert(input.name, Value::Object(node));
+    }
+    nodes.insert("root".to_string(), json!({ "inputs": root_inputs }));
+
+    let lock = json!({
+        "nodes": nodes,
+        "root": "root",
+        "version": 7,
+    });
+
+    let mut contents = serde_json::to_string_pretty(&lock).context("Serializing flake.lock")?;
+    contents.push('\n');
+    Ok(contents)
+}
+
+/// URLs of the flake inputs that are locked without a NAR hash, so their contents aren't verified
+pub fn get_unhashed_inputs(
+    plan: &BuildPlan,
+    default_archive: &str,
+    default_archive_hash: Option<&str>,
+) -> Result<Vec<String>> {
+    let setup_phase = plan.setup.clone().unwrap_or_default();
+    Ok(
+        get_inputs(&setup_phase, default_archive, default_archive_hash)?
+            .into_iter()
+            .filter(|input| !input.is_hashed())
+            .map(|input| input.url)
+            .collect(),
+    )
+}
+
+fn get_archive(setup_phase: &SetupPhase, default_archive: &str) -> String {
+    setup_phase
+        .archive
+        .clone()
+        .unwrap_or_else(|| default_archive.to_string())
+}
+
+/// Name of the flake input for the archive at `index` of `get_pkg_archives`
+fn archive_input_name(index: usize) -> String {
+    format!("nixpkgs{}", index)
+}
+
+fn nixpkgs_input(name: String, archive: &str, hash: Option<&str>) -> Result<FlakeInput> {
+    // A branch or tag would be written as the `rev` of the lock, which Nix can't fetch
+    if !is_revision(archive) {
+        bail!(
+            "nixpkgs archive `{}` is not a commit and can't be locked in a flake. Use the full 40 character hash of a commit instead",
+            archive
+        );
+    }
+
+    let mut locked = Map::new();
+    locked.insert("type".to_string(), json!("github"));
+    locked.insert("owner".to_string(), json!("NixOS"));
+    locked.insert("repo".to_string(), json!("nixpkgs"));
+    locked.insert("rev".to_string(), json!(archive));
+    if let Some(hash) = hash {
+        locked.insert("narHash".to_string(), json!(hash));
+    }
+
+    Ok(FlakeInput {
+        name,
+        url: format!("github:NixOS/nixpkgs/{}", archive),
+        flake: true,
+        locked,
+    })
+}
+
+fn get_inputs(
+    setup_phase: &SetupPhase,
+    default_archive: &str,
+    default_archive_hash: Option<&str>,
+) -> Result<Vec<FlakeInput>> {
+    // The hash of the default archive ships with nixpacks
+    let known_hash = |archive: &str| match archive == default_archive {
+        true => default_archive_hash,
+        false => None,
+    };
+
+    let archive = get_archive(setup_phase, default_archive);
+    let mut inputs = vec![nixpkgs_input(
+        "nixpkgs".to_string(),
+        &archive,
+        setup_phase
+            .archive_hash
+            .as_deref()
+            .or_else(|| known_hash(&archive)),
+    )?];
+
+    for (i, pkg_archive) in get_pkg_archives(&setup_phase.pkgs, Some(&archive))
+        .iter()
+        .enumerate()
+    {
+        inputs.push(nixpkgs_input(
+            archive_input_name(i),
+            pkg_archive,
+            get_archive_hash(&setup_phase.pkgs, pkg_archive).or_else(|| known_hash(pkg_archive)),
+        )?);
+    }
+
+    // Overlays are imported from tarballs, like `fetchTarball` in environment.nix
+    let mut overlays: Vec<(String, Option<String>)> = Vec::new();
+    for pkg in &setup_phase.pkgs {
+        if let Some(overlay) = &pkg.overlay {
+            match overlays.iter_mut().find(|(url, _)| url == overlay) {
+                Some((_, hash)) => {
+                    if hash.is_none() {
+                        hash.clone_from(&pkg.overlay_hash);
+                    }
+                }
+                None => overlays.push((overlay.clone(), pkg.overlay_hash.clone())),
+            }
+        }
+    }
+    for (i, (url, hash)) in overlays.into_iter().enumerate() {
+        // The tarball of a branch changes with every commit, so it can't be locked
+        if !is_pinned_url(&url) {
+            bail!(
+                "Overlay `{}` doesn't point at a commit and can't be locked in a flake. Use the archive of a commit instead",
+                url
+            );
+        }
+
+        let mut locked = Map::new();
+        locked.insert("type".to_string(), json!("tarball"));
+        locked.insert("url".to_string(), json!(url));
+        if let Some(hash) = hash {
+            locked.insert("narHash".to_string(), json!(hash));
+        }
+        inputs.push(FlakeInput {
+            name: format!("overlay{}", i),
+            url,
+            flake: false,
+            locked,
+        });
+    }
+
+    Ok(inputs)
+}
+
+/// Whether a tarball URL names a commit, e.g. `https://github.com/owner/repo/archive/<sha>.tar.gz`
+fn is_pinned_url(url: &str) -> bool {
+    url.split('/')
+        .any(|segment| is_revision(segment.trim_end_matches(".tar.gz").trim_end_matches(".zip")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nixpacks::nix::Pkg;
+
+    const DEFAULT_ARCHIVE: &str = "41cc1d5d9584103be4108c1815c350e07c807036";
+    const PKG_ARCHIVE: &str = "a0b1c2d3e4f5a6b7c8d9e0f1a2b3c4d5e6f7a8b9";
+
+    const OVERLAY: &str =
+        "https://github.com/oxalica/rust-overlay/archive/9a6e8f1c1e7d1ab1e9dd1cd0d7c1c3e1b1c0e0b1.tar.gz";
+
+    fn get_plan(setup: SetupPhase) -> BuildPlan {
+        BuildPlan {
+            version: None,
+            schema_version: None,
+            setup: Some(setup),
+            install: None,
+            build: None,
+            phases: None,
+            start: None,
+            variables: None,
+            variable_scopes: None,
+            secrets: None,
+        }
+    }
+
+    #[test]
+    fn test_gen_flake() -> Result<()> {
+        let plan = get_plan(SetupPhase::new(vec![
+            Pkg::new("nodejs"),
+            Pkg::new("rust-bin.stable.latest.default").from_overlay(OVERLAY),
+        ]));
+        let flake = gen_flake(&plan, DEFAULT_ARCHIVE)?;
+
+        assert!(flake.contains(
+            "nixpkgs.url = \"github:NixOS/nixpkgs/41cc1d5d9584103be4108c1815c350e07c807036\";"
+        ));
+        assert!(flake.contains(&format!(
+            "overlay0 = {{ url = \"{}\"; flake = false; }};",
+            OVERLAY
+        )));
+        assert!(flake.contains("outputs = { self, nixpkgs, overlay0 }:"));
+        assert!(flake.contains("overlays = [ (import overlay0) ];"));
+        assert!(flake.contains("paths = [ nodejs rust-bin.stable.latest.default ];"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_gen_flake_with_pinned_pkg() -> Result<()> {
+        let plan = get_plan(SetupPhase::new(vec![
+            Pkg::new("nodejs-10_x").from_archive(PKG_ARCHIVE),
+            Pkg::new("postgresql"),
+        ]));
+        let flake = gen_flake(&plan, DEFAULT_ARCHIVE)?;
+
+        assert!(flake.contains(&format!(
+            "nixpkgs0.url = \"github:NixOS/nixpkgs/{}\";",
+            PKG_ARCHIVE
+        )));
+        assert!(flake.contains("outputs = { self, nixpkgs, nixpkgs0 }:"));
+        assert!(flake.contains("archive0 = import nixpkgs0 { inherit system; overlays = [ ]; };"));
+        assert!(flake.contains("paths = [ archive0.nodejs-10_x postgresql ];"));
+
+        let lock: Value = serde_json::from_str(&gen_flake_lock(&plan, DEFAULT_ARCHIVE, None)?)?;
+        assert_eq!(lock["nodes"]["nixpkgs0"]["locked"]["rev"], PKG_ARCHIVE);
+        assert!(lock["nodes"]["nixpkgs0"]["locked"].get("narHash").is_none());
+        assert_eq!(lock["nodes"]["root"]["inputs"]["nixpkgs0"], "nixpkgs0");
+        Ok(())
+    }
+
+    #[test]
+    fn test_gen_flake_lock() -> Result<()> {
+        let mut setup = SetupPhase::new(vec![Pkg::new("nodejs")]);
+        setup.archive = Some(PKG_ARCHIVE.to_string());
+        setup.archive_hash = Some("sha256-AAAA".to_string());
+        let lock: Value =
+            serde_json::from_str(&gen_flake_lock(&get_plan(setup), DEFAULT_ARCHIVE, None)?)?;
+
+        assert_eq!(lock["root"], "root");
+        assert_eq!(lock["nodes"]["root"]["inputs"]["nixpkgs"], "nixpkgs");
+        assert_eq!(lock["nodes"]["nixpkgs"]["locked"]["rev"], PKG_ARCHIVE);
+        assert_eq!(lock["nodes"]["nixpkgs"]["locked"]["narHash"], "sha256-AAAA");
+        assert!(lock["nodes"]["nixpkgs"]["original"]
+            .get("narHash")
+            .is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_gen_flake_lock_default_archive_hash() -> Result<()> {
+        let plan = get_plan(SetupPhase::new(vec![
+            Pkg::new("nodejs"),
+            Pkg::new("nodejs-10_x").from_archive(PKG_ARCHIVE),
+        ]));
+        let lock: Value = serde_json::from_str(&gen_flake_lock(
+            &plan,
+            DEFAULT_ARCHIVE,
+            Some("sha256-DDDD"),
+        )?)?;
+
+        assert_eq!(lock["nodes"]["nixpkgs"]["locked"]["rev"], DEFAULT_ARCHIVE);
+        assert_eq!(lock["nodes"]["nixpkgs"]["locked"]["narHash"], "sha256-DDDD");
+        // Only the default archive has a bundled hash
+        assert!(lock["nodes"]["nixpkgs0"]["locked"].get("narHash").is_none());
+        assert_eq!(
+            get_unhashed_inputs(&plan, DEFAULT_ARCHIVE, Some("sha256-DDDD"))?,
+            vec![format!("github:NixOS/nixpkgs/{}", PKG_ARCHIVE)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_gen_flake_lock_is_stable() -> Result<()> {
+        let plan = get_plan(SetupPhase::new(vec![Pkg::new("nodejs")]));
+        assert_eq!(
+            gen_flake_lock(&plan, DEFAULT_ARCHIVE, None)?,
+            gen_flake_lock(&plan, DEFAULT_ARCHIVE, None)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_gen_flake_lock_overlay_hash() -> Result<()> {
+        let mut pkg = Pkg::new("rust-bin.stable.latest.default").from_overlay(OVERLAY);
+        pkg.overlay_hash = Some("sha256-BBBB".to_string());
+        let mut setup = SetupPhase::new(vec![pkg]);
+        setup.archive_hash = Some("sha256-AAAA".to_string());
+        let plan = get_plan(setup);
+
+        let lock: Value = serde_json::from_str(&gen_flake_lock(&plan, DEFAULT_ARCHIVE, None)?)?;
+        assert_eq!(lock["nodes"]["overlay0"]["locked"]["type"], "tarball");
+        assert_eq!(
+            lock["nodes"]["overlay0"]["locked"]["narHash"],
+            "sha256-BBBB"
+        );
+        assert!(get_unhashed_inputs(&plan, DEFAULT_ARCHIVE, None)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_gen_flake_lock_pinned_pkg_hash() -> Result<()> {
+        let mut pkg = Pkg::new("nodejs-10_x").from_archive(PKG_ARCHIVE);
+        pkg.archive_hash = Some("sha256-CCCC".to_string());
+        let plan = get_plan(SetupPhase::new(vec![
+            pkg,
+            Pkg::new("yarn").from_archive(PKG_ARCHIVE),
+        ]));
+
+        let lock: Value = serde_json::from_str(&gen_flake_lock(&plan, DEFAULT_ARCHIVE, None)?)?;
+        assert_eq!(
+            lock["nodes"]["nixpkgs0"]["locked"]["narHash"],
+            "sha256-CCCC"
+        );
+        assert_eq!(
+            get_unhashed_inputs(&plan, DEFAULT_ARCHIVE, None)?,
+            vec![format!("github:NixOS/nixpkgs/{}", DEFAULT_ARCHIVE)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_unhashed_inputs() -> Result<()> {
+        let plan = get_plan(SetupPhase::new(vec![
+            Pkg::new("nodejs-10_x").from_archive(PKG_ARCHIVE),
+            Pkg::new("rust-bin.stable.latest.default").from_overlay(OVERLAY),
+        ]));
+        assert_eq!(
+            get_unhashed_inputs(&plan, DEFAULT_ARCHIVE, None)?,
+            vec![
+                format!("github:NixOS/nixpkgs/{}", DEFAULT_ARCHIVE),
+                format!("github:NixOS/nixpkgs/{}", PKG_ARCHIVE),
+                OVERLAY.to_string(),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_branch_archive_is_not_locked() {
+        let mut setup = SetupPhase::new(vec![Pkg::new("nodejs")]);
+        setup.archive = Some("nixos-unstable".to_string());
+        let plan = get_plan(setup);
+        assert!(gen_flake(&plan, DEFAULT_ARCHIVE).is_err());
+        assert!(gen_flake_lock(&plan, DEFAULT_ARCHIVE, None).is_err());
+
+        let plan = get_plan(SetupPhase::new(vec![
+            Pkg::new("nodejs-10_x").from_archive("22.05")
+        ]));
+        assert!(gen_flake_lock(&plan, DEFAULT_ARCHIVE, None).is_err());
+    }
+
+    #[test]
+    fn test_branch_overlay_is_not_locked() {
+        let plan = get_plan(SetupPhase::new(vec![Pkg::new(
+            "rust-bin.stable.latest.default",
+        )
+        .from_overlay("https://github.com/oxalica/rust-overlay/archive/master.tar.gz")]));
+        assert!(gen_flake(&plan, DEFAULT_ARCHIVE).is_err());
+        assert!(gen_flake_lock(&plan, DEFAULT_ARCHIVE, None).is_err());
+    }
+}
@@ -0,0 +1,251 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use globset::{GlobBuilder, GlobMatcher};
+
+use super::app::App;
+
+pub const DOCKERIGNORE_FILE_NAME: &str = ".dockerignore";
+pub const GITIGNORE_FILE_NAME: &str = ".gitignore";
+
+/// Files that are not copied into the image unless the app adds them back
+///
+/// Example and template `.env` files hold no secrets and are read by some builds (e.g.
+/// dotenv-safe), so they are kept.
+pub const DEFAULT_IGNORE_PATTERNS: &[&str] = &[
+    ".git",
+    "**/.env",
+    "**/.env.*",
+    "!**/.env.example",
+    "!**/.env.sample",
+    "!**/.env.template",
+];
+
+#[derive(Debug, Clone)]
+struct IgnorePattern {
+    pattern: String,
+    exception: bool,
+    matcher: GlobMatcher,
+}
+
+/// Files excluded from the build context, using the same rules as a `.dockerignore` file
+///
+/// Patterns are relative to the app root. A pattern that matches a directory excludes everything
+/// inside of it, and patterns starting with `!` add back files excluded by an earlier pattern. When
+/// several patterns match a file, the last one wins.
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreRules {
+    patterns: Vec<IgnorePattern>,
+}
+
+impl IgnoreRules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The built-in defaults followed by the `.dockerignore` (and optionally `.gitignore`) of the app
+    pub fn from_app(app: &App, extra_patterns: Vec<String>, use_gitignore: bool) -> Result<Self> {
+        let mut rules = IgnoreRules::new();
+        for pattern in DEFAULT_IGNORE_PATTERNS {
+            rules.add(pattern)?;
+        }
+        for pattern in extra_patterns {
+            rules.add(&pattern)?;
+        }
+
+        if use_gitignore && app.includes_file(GITIGNORE_FILE_NAME) {
+            let contents = app.read_file(GITIGNORE_FILE_NAME)?;
+            rules
+                .add_gitignore(&contents)
+                .with_context(|| format!("Reading {}", GITIGNORE_FILE_NAME))?;
+        }
+
+        if app.includes_file(DOCKERIGNORE_FILE_NAME) {
+            let contents = app.read_file(DOCKERIGNORE_FILE_NAME)?;
+            rules
+                .add_dockerignore(&contents)
+                .with_context(|| format!("Reading {}", DOCKERIGNORE_FILE_NAME))?;
+        }
+
+        Ok(rules)
+    }
+
+    /// Add a single pattern in `.dockerignore` syntax
+    pub fn add(&mut self, pattern: &str) -> Result<()> {
+        let pattern = pattern.trim();
+        if pattern.is_empty() || pattern.starts_with('#') {
+            return Ok(());
+        }
+
+        let (exception, pattern) = match pattern.strip_prefix('!') {
+            Some(pattern) => (true, pattern.trim()),
+            None => (false, pattern),
+        };
+
+        let pattern = clean_pattern(pattern);
+        if pattern.is_empty() {
+            return Ok(());
+        }
+
+        let matcher = GlobBuilder::new(&pattern)
+            .literal_separator(true)
+            .build()
+            .with_context(|| format!("Invalid ignore pattern `{}`", pattern))?
+            .compile_matcher();
+
+        self.patterns.push(IgnorePattern {
+            pattern,
+            exception,
+            matcher,
+        });
+
+        Ok(())
+    }
+
+    pub fn add_dockerignore(&mut self, contents: &str) -> Result<()> {
+        for line in contents.lines() {
+            self.add(line)?;
+        }
+        Ok(())
+    }
+
+    /// Add the patterns of a `.gitignore` file, converted to `.dockerignore` semantics
+    pub fn add_gitignore(&mut self, contents: &str) -> Result<()> {
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (prefix, pattern) = match line.strip_prefix('!') {
+                Some(pattern) => ("!", pattern),
+                None => ("", line),
+            };
+
+            // Git matches patterns without a slash at any depth
+            let pattern = pattern.trim_end_matches('/');
+            let pattern = if pattern.contains('/') {
+                pattern.to_string()
+            } else {
+                format!("**/{}", pattern)
+            };
+
+            self.add(&format!("{}{}", prefix, pattern))?;
+        }
+        Ok(())
+    }
+
+    /// Whether any pattern can add back files excluded by an earlier pattern
+    pub fn has_exceptions(&self) -> bool {
+        self.patterns.iter().any(|p| p.exception)
+    }
+
+    /// Check if a path relative to the app root is excluded
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        let mut ignored = false;
+        for pattern in &self.patterns {
+            // A pattern matching a parent directory also matches the files inside of it
+            if path.ancestors().any(|p| pattern.matcher.is_match(p)) {
+                ignored = !pattern.exception;
+            }
+        }
+        ignored
+    }
+
+    /// Contents of a `.dockerignore` file with the same rules
+    pub fn to_dockerignore(&self) -> String {
+        self.patterns
+            .iter()
+            .map(|p| match p.exception {
+                true => format!("!{}\n", p.pattern),
+                false => format!("{}\n", p.pattern),
+            })
+            .collect()
+    }
+}
+
+/// Docker treats patterns as relative paths from the root of the build context
+fn clean_pattern(pattern: &str) -> String {
+    pattern
+        .split('/')
+        .filter(|part| !part.is_empty() && *part != ".")
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(contents: &str) -> IgnoreRules {
+        let mut rules = IgnoreRules::new();
+        rules.add_dockerignore(contents).unwrap();
+        rules
+    }
+
+    #[test]
+    fn test_ignore_directory_contents() {
+        let rules = rules("node_modules\n/target/\n");
+        assert!(rules.is_ignored(Path::new("node_modules")));
+        assert!(rules.is_ignored(Path::new("node_modules/express/index.js")));
+        assert!(rules.is_ignored(Path::new("target/release/app")));
+        assert!(!rules.is_ignored(Path::new("src/node_modules/index.js")));
+        assert!(!rules.is_ignored(Path::new("index.js")));
+    }
+
+    #[test]
+    fn test_ignore_globs() {
+        let rules = rules("# comment\n*.log\n**/*.tmp\ndocs/*.md\n");
+        assert!(rules.is_ignored(Path::new("debug.log")));
+        assert!(!rules.is_ignored(Path::new("logs/debug.log")));
+        assert!(rules.is_ignored(Path::new("a/b/c.tmp")));
+        assert!(rules.is_ignored(Path::new("docs/README.md")));
+        assert!(!rules.is_ignored(Path::new("docs/api/README.md")));
+    }
+
+    #[test]
+    fn test_ignore_exceptions() {
+        let rules = rules("*.md\n!README.md\nREADME.md\n!CHANGELOG.md\n");
+        assert!(rules.has_exceptions());
+        assert!(rules.is_ignored(Path::new("CONTRIBUTING.md")));
+        assert!(!rules.is_ignored(Path::new("CHANGELOG.md")));
+        // The last matching pattern wins
+        assert!(rules.is_ignored(Path::new("README.md")));
+    }
+
+    #[test]
+    fn test_gitignore_patterns() -> Result<()> {
+        let mut rules = IgnoreRules::new();
+        rules.add_gitignore("dist/\n/build\n!dist/keep\n")?;
+        assert!(rules.is_ignored(Path::new("dist/index.js")));
+        assert!(rules.is_ignored(Path::new("packages/web/dist/index.js")));
+        assert!(!rules.is_ignored(Path::new("dist/keep")));
+        assert!(rules.is_ignored(Path::new("build/out")));
+        assert!(!rules.is_ignored(Path::new("src/build/out")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_patterns() -> Result<()> {
+        let mut rules = IgnoreRules::new();
+        for pattern in DEFAULT_IGNORE_PATTERNS {
+            rules.add(pattern)?;
+        }
+        assert!(rules.is_ignored(Path::new(".git/HEAD")));
+        assert!(rules.is_ignored(Path::new(".env")));
+        assert!(rules.is_ignored(Path::new(".env.production")));
+        assert!(rules.is_ignored(Path::new("packages/api/.env")));
+        assert!(rules.is_ignored(Path::new("packages/api/.env.local")));
+        assert!(!rules.is_ignored(Path::new("src/env.js")));
+        assert!(!rules.is_ignored(Path::new(".env.example")));
+        assert!(!rules.is_ignored(Path::new("packages/api/.env.sample")));
+        assert!(!rules.is_ignored(Path::new(".env.template")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_to_dockerignore() {
+        let rules = rules("./node_modules\n!keep.log\n");
+        assert_eq!(rules.to_dockerignore(), "node_modules\n!keep.log\n");
+    }
+}
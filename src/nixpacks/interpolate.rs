@@ -0,0 +1,212 @@
+use anyhow::{bail, Result};
+
+use super::environment::EnvironmentVariables;
+
+/// Replace `${NAME}` and `${NAME:-default}` references with the value of the variable
+///
+/// The default is used when the variable is unset or empty. `$${` is an escaped `${`, and plain
+/// `$NAME` references and other shell parameter expansions (e.g. `${f%.js}` or `${PORT:?}`) are
+/// left for the shell. References to unknown variables are left for the shell of the container,
+/// with their names added to `unresolved`, or are an error when `unresolved` is `None`.
+pub fn interpolate(
+    input: &str,
+    variables: &EnvironmentVariables,
+    mut unresolved: Option<&mut Vec<String>>,
+) -> Result<String> {
+    let mut output = String::new();
+    let mut rest = input;
+
+    while let Some(start) = rest.find('$') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if rest.starts_with("$${") {
+            output.push_str("${");
+            rest = &rest[3..];
+        } else if rest.starts_with("${") {
+            let end = match find_closing_brace(rest) {
+                Some(end) => end,
+                None if unresolved.is_some() => break,
+                None => bail!("Unterminated variable reference in `{}`", input),
+            };
+            let reference = &rest[2..end];
+            output.push_str(&resolve(reference, variables, unresolved.as_deref_mut())?);
+            rest = &rest[end + 1..];
+        } else {
+            output.push('$');
+            rest = &rest[1..];
+        }
+    }
+    output.push_str(rest);
+
+    Ok(output)
+}
+
+fn resolve(
+    reference: &str,
+    variables: &EnvironmentVariables,
+    unresolved: Option<&mut Vec<String>>,
+) -> Result<String> {
+    let (name, default) = match reference.split_once(":-") {
+        Some((name, default)) => (name, Some(default)),
+        None => (reference, None),
+    };
+
+    // Other parameter expansions are for the shell
+    let valid_name = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid_name {
+        return Ok(format!("${{{}}}", reference));
+    }
+
+    match (variables.get(name), default, unresolved) {
+        (Some(value), _, _) if !value.is_empty() => Ok(value.clone()),
+        (_, Some(default), unresolved) => interpolate(default, variables, unresolved),
+        (Some(value), None, _) => Ok(value.clone()),
+        (None, None, Some(unresolved)) => {
+            if !unresolved.iter().any(|n| n == name) {
+                unresolved.push(name.to_string());
+            }
+            Ok(format!("${{{}}}", name))
+        }
+        (None, None, None) => bail!(
+            "Variable `{}` is not set. Set it with --env, or use `${{{}:-default}}` to provide a default",
+            name,
+            name
+        ),
+    }
+}
+
+/// Index of the `}` closing the reference at the start of `input`, allowing nested references
+/// in defaults
+fn find_closing_brace(input: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in input.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables() -> EnvironmentVariables {
+        EnvironmentVariables::from([
+            ("APP_BIN".to_string(), "server".to_string()),
+            ("EMPTY".to_string(), "".to_string()),
+        ])
+    }
+
+    #[test]
+    fn test_interpolate_variables() -> Result<()> {
+        assert_eq!(
+            interpolate("cargo build --bin ${APP_BIN}", &variables(), None)?,
+            "cargo build --bin server"
+        );
+        assert_eq!(
+            interpolate("./target/release/${APP_BIN}-${APP_BIN}", &variables(), None)?,
+            "./target/release/server-server"
+        );
+        assert_eq!(
+            interpolate("no variables", &variables(), None)?,
+            "no variables"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_interpolate_defaults() -> Result<()> {
+        assert_eq!(interpolate("${PORT:-3000}", &variables(), None)?, "3000");
+        assert_eq!(
+            interpolate("${EMPTY:-fallback}", &variables(), None)?,
+            "fallback"
+        );
+        assert_eq!(
+            interpolate("${MISSING:-${APP_BIN}}", &variables(), None)?,
+            "server"
+        );
+        assert_eq!(interpolate("${EMPTY}", &variables(), None)?, "");
+        Ok(())
+    }
+
+    #[test]
+    fn test_shell_references() -> Result<()> {
+        assert_eq!(
+            interpolate("echo $HOME $${HOME} $$", &variables(), None)?,
+            "echo $HOME ${HOME} $$"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unresolved_variables() -> Result<()> {
+        let mut unresolved = Vec::new();
+        assert_eq!(
+            interpolate(
+                "${MISSING} ${APP_BIN} ${MISSING}",
+                &variables(),
+                Some(&mut unresolved)
+            )?,
+            "${MISSING} server ${MISSING}"
+        );
+        assert_eq!(unresolved, vec!["MISSING".to_string()]);
+        assert_eq!(
+            interpolate("echo ${APP_BIN", &variables(), Some(&mut unresolved))?,
+            "echo ${APP_BIN"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_strict_unresolved_variables() {
+        assert!(interpolate("${MISSING}", &variables(), None).is_err());
+        assert!(interpolate("${APP_BIN", &variables(), None).is_err());
+    }
+
+    #[test]
+    fn test_shell_parameter_expansions() -> Result<()> {
+        for strict in [false, true] {
+            let mut unresolved = Vec::new();
+            let mut unresolved = match strict {
+                true => None,
+                false => Some(&mut unresolved),
+            };
+            assert_eq!(
+                interpolate(
+                    "for f in *.js; do echo ${f%.js}; done",
+                    &variables(),
+                    unresolved.as_deref_mut()
+                )?,
+                "for f in *.js; do echo ${f%.js}; done"
+            );
+            assert_eq!(
+                interpolate(
+                    "node index.js --port ${PORT:?required} ${X:?}",
+                    &variables(),
+                    unresolved.as_deref_mut()
+                )?,
+                "node index.js --port ${PORT:?required} ${X:?}"
+            );
+            assert_eq!(
+                interpolate(
+                    "${#APP_BIN} ${NOT-VALID}",
+                    &variables(),
+                    unresolved.as_deref_mut()
+                )?,
+                "${#APP_BIN} ${NOT-VALID}"
+            );
+            assert!(unresolved.is_none_or(|unresolved| unresolved.is_empty()));
+        }
+        Ok(())
+    }
+}
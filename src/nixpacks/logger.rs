@@ -1,23 +1,325 @@
+use std::{cell::Cell, env, str::FromStr};
+
+use anyhow::{bail, Result};
 use colored::Colorize;
+use serde::Serialize;
+
+use super::plan::BuildPlan;
+
+/// Something that happened while planning or building an app
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "event", rename_all = "camelCase")]
+pub enum LogEvent {
+    /// Start of a part of the build, such as building or a successful build
+    Section {
+        message: String,
+    },
+    Step {
+        message: String,
+    },
+    /// Something that may make the build fail, printed to stderr
+    Warning {
+        message: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    ProviderDetected {
+        provider: String,
+        score: u32,
+        reasons: Vec<String>,
+    },
+    Plan {
+        plan: Box<BuildPlan>,
+    },
+    /// A phase of the plan started running in the image builder
+    PhaseStarted {
+        phase: String,
+    },
+    /// Line printed by the image builder
+    BuildOutput {
+        line: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    BuildSucceeded {
+        image: String,
+        run_command: Option<String>,
+    },
+    OciArchiveSaved {
+        path: String,
+        manifests: usize,
+        layers: usize,
+    },
+    OutputSaved {
+        path: String,
+    },
+}
+
+/// Receives the events of a build, e.g. to print them or forward them to a UI
+pub trait Logger {
+    fn log(&self, event: LogEvent);
+
+    /// Whether the output of the image builder should be sent as `BuildOutput` events instead of
+    /// being printed directly
+    fn captures_build_output(&self) -> bool {
+        true
+    }
+
+    fn log_section(&self, message: &str) {
+        self.log(LogEvent::Section {
+            message: message.to_string(),
+        });
+    }
+
+    fn log_step(&self, message: &str) {
+        self.log(LogEvent::Step {
+            message: message.to_string(),
+        });
+    }
+
+    fn log_warning(&self, message: &str) {
+        self.log(LogEvent::Warning {
+            message: message.to_string(),
+        });
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Human,
+    Plain,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        match format {
+            "human" => Ok(LogFormat::Human),
+            "plain" => Ok(LogFormat::Plain),
+            "json" => Ok(LogFormat::Json),
+            _ => bail!(
+                "Unknown log format `{}`. Expected one of human, plain, json",
+                format
+            ),
+        }
+    }
+}
+
+/// Create a logger for a format, using the plain format instead of colors when `NO_COLOR` is set
+pub fn get_logger(format: LogFormat) -> Box<dyn Logger> {
+    let no_color = env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    match format {
+        LogFormat::Human if !no_color => Box::new(HumanLogger {}),
+        LogFormat::Human | LogFormat::Plain => Box::new(PlainLogger {}),
+        LogFormat::Json => Box::new(JsonLogger {}),
+    }
+}
+
+/// Colored output for a terminal
+#[derive(Debug, Default)]
+pub struct HumanLogger {}
+
+impl Logger for HumanLogger {
+    fn log(&self, event: LogEvent) {
+        match event {
+            LogEvent::Section { message } => println!("=== {} ===", message.magenta().bold()),
+            LogEvent::Warning { message } => {
+                eprintln!("{} {}", "warning:".yellow().bold(), message)
+            }
+            event => print_event(event),
+        }
+    }
+
+    fn captures_build_output(&self) -> bool {
+        false
+    }
+}
+
+/// Human readable output without colors, e.g. for log files
+#[derive(Debug, Default)]
+pub struct PlainLogger {}
+
+impl Logger for PlainLogger {
+    fn log(&self, event: LogEvent) {
+        match event {
+            LogEvent::Section { message } => println!("=== {} ===", message),
+            event => print_event(event),
+        }
+    }
+}
+
+/// One JSON object per line for every event, printed to stderr so that stdout only has the output
+/// of the command, e.g. the plan
+#[derive(Debug, Default)]
+pub struct JsonLogger {}
 
-pub struct Logger {}
+impl Logger for JsonLogger {
+    fn log(&self, event: LogEvent) {
+        if let Ok(json) = serde_json::to_string(&event) {
+            eprintln!("{}", json);
+        }
+    }
+}
+
+/// Forwards events to another logger, adding a `PhaseStarted` event when the output of the image
+/// builder shows that the command of a phase started running
+pub struct PhaseLogger<'a> {
+    logger: &'a dyn Logger,
+    /// Name and command of the phases, in the order they run
+    phases: Vec<(String, String)>,
+    next_phase: Cell<usize>,
+}
 
-impl Logger {
-    pub fn new() -> Logger {
-        Logger {}
+impl<'a> PhaseLogger<'a> {
+    pub fn new(logger: &'a dyn Logger, phases: Vec<(String, String)>) -> Self {
+        Self {
+            logger,
+            phases,
+            next_phase: Cell::new(0),
+        }
     }
+}
+
+impl<'a> Logger for PhaseLogger<'a> {
+    fn log(&self, event: LogEvent) {
+        if let LogEvent::BuildOutput { line } = &event {
+            if line.contains("RUN ") {
+                let next_phase = self.next_phase.get();
+                let started = self.phases[next_phase..]
+                    .iter()
+                    .position(|(_, cmd)| line.contains(cmd.as_str()));
+                if let Some(position) = started {
+                    let (name, _) = &self.phases[next_phase + position];
+                    self.logger.log(LogEvent::PhaseStarted {
+                        phase: name.clone(),
+                    });
+                    self.next_phase.set(next_phase + position + 1);
+                }
+            }
+        }
 
-    pub fn log_section(&self, msg: &str) {
-        println!("=== {} ===", msg.magenta().bold());
+        self.logger.log(event);
     }
 
-    pub fn log_step(&self, msg: &str) {
-        println!("=> {}", msg);
+    fn captures_build_output(&self) -> bool {
+        self.logger.captures_build_output()
     }
 }
 
-impl Default for Logger {
-    fn default() -> Self {
-        Self::new()
+fn print_event(event: LogEvent) {
+    match event {
+        LogEvent::Section { message } => println!("=== {} ===", message),
+        LogEvent::Step { message } => println!("=> {}", message),
+        LogEvent::Warning { message } => eprintln!("warning: {}", message),
+        LogEvent::Plan { plan } => {
+            if let Ok(build_string) = plan.get_build_string() {
+                println!("{}", build_string);
+            }
+        }
+        LogEvent::BuildOutput { line } => println!("{}", line),
+        LogEvent::BuildSucceeded { run_command, .. } => {
+            if let Some(run_command) = run_command {
+                println!("\nRun:");
+                println!("  {}", run_command);
+            }
+        }
+        LogEvent::OciArchiveSaved {
+            path,
+            manifests,
+            layers,
+        } => {
+            println!(
+                "\nSaved OCI image ({} manifests, {} layers) to:",
+                manifests, layers
+            );
+            println!("  {}", path);
+        }
+        LogEvent::OutputSaved { path } => {
+            println!("\nSaved output to:");
+            println!("  {}", path);
+        }
+        // Already summarized by steps, or visible in the output of the image builder
+        LogEvent::ProviderDetected { .. } | LogEvent::PhaseStarted { .. } => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    #[test]
+    fn test_parse_log_format() {
+        assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+
+    #[derive(Default)]
+    struct TestLogger {
+        events: RefCell<Vec<String>>,
+    }
+
+    impl Logger for TestLogger {
+        fn log(&self, event: LogEvent) {
+            self.events
+                .borrow_mut()
+                .push(serde_json::to_string(&event).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_phase_logger() {
+        let logger = TestLogger::default();
+        let phase_logger = PhaseLogger::new(
+            &logger,
+            vec![
+                ("install".to_string(), "npm ci".to_string()),
+                ("build".to_string(), "npm run build".to_string()),
+            ],
+        );
+
+        for line in [
+            "#8 [stage-0 4/9] COPY . /app/",
+            "#9 [stage-0 5/9] RUN --mount=type=cache,target=/root/.npm npm ci",
+            "#9 CACHED",
+            "Step 6/9 : RUN npm run build",
+            "Step 7/9 : RUN npm ci",
+        ] {
+            phase_logger.log(LogEvent::BuildOutput {
+                line: line.to_string(),
+            });
+        }
+
+        let events = logger.events.borrow();
+        let phases = events
+            .iter()
+            .filter(|event| event.contains("phaseStarted"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            phases,
+            vec![
+                r#"{"event":"phaseStarted","phase":"install"}"#,
+                r#"{"event":"phaseStarted","phase":"build"}"#
+            ]
+        );
+        assert_eq!(events.len(), 7);
+    }
+
+    #[test]
+    fn test_serialize_events() -> Result<()> {
+        assert_eq!(
+            serde_json::to_string(&LogEvent::BuildSucceeded {
+                image: "my-app".to_string(),
+                run_command: Some("docker run -it my-app".to_string()),
+            })?,
+            r#"{"event":"buildSucceeded","image":"my-app","runCommand":"docker run -it my-app"}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&LogEvent::PhaseStarted {
+                phase: "install".to_string()
+            })?,
+            r#"{"event":"phaseStarted","phase":"install"}"#
+        );
+        Ok(())
     }
 }
@@ -1,31 +1,62 @@
 use anyhow::{bail, Context, Ok, Result};
 use indoc::formatdoc;
 use std::{
+    cell::OnceCell,
+    env,
     fs::{self, File},
     io::{self, Write},
     path::{Path, PathBuf},
-    process::Command,
 };
 use tempdir::TempDir;
 use uuid::Uuid;
 use walkdir::WalkDir;
 pub mod app;
+pub mod builders;
+pub mod config;
+pub mod diff;
+pub mod dockerfile;
+pub mod dotenv;
 pub mod environment;
+pub mod flake;
+pub mod ignore;
 pub mod images;
+pub mod interpolate;
 pub mod logger;
 pub mod nix;
+pub mod oci;
 pub mod phase;
+pub mod pkg_index;
 pub mod plan;
+pub mod schema;
+pub mod shell;
+pub mod versions;
 
-use crate::providers::Provider;
+use crate::providers::{Detection, Provider};
 
 use self::{
     app::App,
-    environment::{Environment, EnvironmentVariables},
-    logger::Logger,
-    nix::Pkg,
-    phase::{BuildPhase, InstallPhase, SetupPhase, StartPhase},
+    builders::{get_image_builder, ImageBuildOptions, ImageBuilder, DEFAULT_IMAGE_BUILDER},
+    config::{NixpacksConfig, CONFIG_FILE_NAME},
+    dockerfile::{Dockerfile, Instruction, Mount, Stage},
+    environment::{Environment, EnvironmentVariables, VariableScope, VariableScopes},
+    flake::{
+        gen_flake, gen_flake_lock, get_unhashed_inputs, FLAKE_DIR, FLAKE_FILE_NAME,
+        FLAKE_LOCK_FILE_NAME, FLAKE_PROFILE,
+    },
+    ignore::{IgnoreRules, DOCKERIGNORE_FILE_NAME},
+    images::DEFAULT_BASE_IMAGE,
+    logger::{LogEvent, Logger, PhaseLogger},
+    nix::{
+        archive_import_name, get_archive_hash, get_pkg_archives, is_revision, nixpkgs_import, Pkg,
+    },
+    oci::{verify_oci_archive, OciArchive},
+    phase::{
+        BuildPhase, InstallPhase, SetupPhase, StartPhase, BUILD_PHASE_NAME, INSTALL_PHASE_NAME,
+    },
+    pkg_index::PkgIndex,
     plan::BuildPlan,
+    schema::PLAN_SCHEMA_VERSION,
+    versions::{get_language, parse_pkg_version, resolve_version},
 };
 
 const NIX_PACKS_VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -33,7 +64,11 @@ const NIX_PACKS_VERSION: &str = env!("CARGO_PKG_VERSION");
 // https://status.nixos.org/
 static NIXPKGS_ARCHIVE: &str = "41cc1d5d9584103be4108c1815c350e07c807036";
 
-#[derive(Debug)]
+/// NAR hash of `NIXPKGS_ARCHIVE`, written into flake.lock and pinned plans so the archive is
+/// verified when it is fetched. See CONTRIBUTING.md for how to generate it.
+static NIXPKGS_ARCHIVE_HASH: Option<&str> = None;
+
+#[derive(Debug, Clone)]
 pub struct AppBuilderOptions {
     pub custom_build_cmd: Option<String>,
     pub custom_start_cmd: Option<String>,
@@ -41,9 +76,18 @@ pub struct AppBuilderOptions {
     pub pin_pkgs: bool,
     pub out_dir: Option<String>,
     pub plan_path: Option<String>,
+    pub plan_override_path: Option<String>,
     pub tags: Vec<String>,
     pub labels: Vec<String>,
     pub quiet: bool,
+    pub no_cache_mounts: bool,
+    pub use_gitignore: bool,
+    pub secrets: Vec<String>,
+    pub strict_variables: bool,
+    pub backend: Option<String>,
+    pub oci_output: Option<String>,
+    pub use_flake: bool,
+    pub skip_pkg_validation: bool,
 }
 
 impl AppBuilderOptions {
@@ -55,20 +99,51 @@ impl AppBuilderOptions {
             pin_pkgs: false,
             out_dir: None,
             plan_path: None,
+            plan_override_path: None,
             tags: Vec::new(),
             labels: Vec::new(),
             quiet: false,
+            no_cache_mounts: false,
+            use_gitignore: false,
+            secrets: Vec::new(),
+            strict_variables: false,
+            backend: None,
+            oci_output: None,
+            use_flake: false,
+            skip_pkg_validation: false,
         }
     }
 }
 
+/// Where the result of a build was saved
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildOutput {
+    /// Image stored by the image builder
+    Image { name: String },
+    /// OCI image layout tarball
+    OciArchive { path: PathBuf, archive: OciArchive },
+    /// Directory with the Dockerfile and app source, which was not built
+    Directory(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+pub struct BuildResult {
+    pub plan: BuildPlan,
+    pub output: BuildOutput,
+}
+
 pub struct AppBuilder<'a> {
     name: Option<String>,
     app: &'a App,
     environment: &'a Environment,
-    logger: &'a Logger,
+    logger: &'a dyn Logger,
     options: &'a AppBuilderOptions,
-    provider: Option<&'a dyn Provider>,
+    /// Loaded when it is first needed, so building from an existing plan doesn't parse it
+    config: OnceCell<NixpacksConfig>,
+    providers: Vec<&'a dyn Provider>,
+    start_provider: Option<&'a dyn Provider>,
+    detections: Vec<(&'a dyn Provider, Detection)>,
+    image_builder: Option<Box<dyn ImageBuilder + 'a>>,
 }
 
 impl<'a> AppBuilder<'a> {
@@ -76,7 +151,7 @@ impl<'a> AppBuilder<'a> {
         name: Option<String>,
         app: &'a App,
         environment: &'a Environment,
-        logger: &'a Logger,
+        logger: &'a dyn Logger,
         options: &'a AppBuilderOptions,
     ) -> Result<AppBuilder<'a>> {
         Ok(AppBuilder {
@@ -85,13 +160,31 @@ impl<'a> AppBuilder<'a> {
             environment,
             logger,
             options,
-            provider: None,
+            config: OnceCell::new(),
+            providers: Vec::new(),
+            start_provider: None,
+            detections: Vec::new(),
+            image_builder: None,
         })
     }
 
+    /// The `nixpacks.toml` of the app, or the default config if it doesn't have one
+    fn config(&self) -> Result<&NixpacksConfig> {
+        if let Some(config) = self.config.get() {
+            return Ok(config);
+        }
+        let config = NixpacksConfig::from_app(self.app)?;
+        Ok(self.config.get_or_init(|| config))
+    }
+
+    /// Build the image with a custom backend instead of one of the built-in image builders
+    pub fn set_image_builder(&mut self, image_builder: Box<dyn ImageBuilder + 'a>) {
+        self.image_builder = Some(image_builder);
+    }
+
     pub fn plan(&mut self, providers: Vec<&'a dyn Provider>) -> Result<BuildPlan> {
-        // Load options from the best matching provider
-        self.detect(providers).context("Detecting provider")?;
+        // Load options from all matching providers
+        self.detect(providers).context("Detecting providers")?;
 
         let setup_phase = self.get_setup_phase().context("Getting setup phase")?;
         let install_phase = self
@@ -99,44 +192,121 @@ impl<'a> AppBuilder<'a> {
             .context("Generating install phase")?;
         let build_phase = self.get_build_phase().context("Generating build phase")?;
         let start_phase = self.get_start_phase().context("Generating start phase")?;
-        let variables = self.get_variables().context("Getting plan variables")?;
+        let secrets = self.get_secrets().context("Getting plan secrets")?;
+        let mut variables = self.get_variables().context("Getting plan variables")?;
+
+        // Secrets must never be saved as variables in the image
+        variables.retain(|name, _| !secrets.contains(name));
 
         let plan = BuildPlan {
             version: Some(NIX_PACKS_VERSION.to_string()),
+            schema_version: Some(PLAN_SCHEMA_VERSION),
             setup: Some(setup_phase),
             install: Some(install_phase),
             build: Some(build_phase),
+            phases: self.config()?.phases.clone(),
             start: Some(start_phase),
+            variable_scopes: self.get_variable_scopes(&variables)?,
             variables: Some(variables),
+            secrets: match secrets.is_empty() {
+                true => None,
+                false => Some(secrets),
+            },
         };
 
+        let mut plan = self.apply_plan_override(plan)?;
+        let unresolved = plan
+            .interpolate_variables(self.options.strict_variables)
+            .context("Interpolating variables")?;
+        for name in unresolved {
+            self.logger.log_warning(&format!(
+                "Variable `{}` is not set, so `${{{}}}` is left for the shell of the container. Pass --strict-vars to make this an error",
+                name, name
+            ));
+        }
+        plan.get_ordered_phases().context("Ordering phases")?;
+
+        if !self.options.skip_pkg_validation {
+            self.validate_pkgs(&plan);
+        }
+
         Ok(plan)
     }
 
-    pub fn build(&mut self, providers: Vec<&'a dyn Provider>) -> Result<()> {
+    /// Catch typos in package names before they fail the build in the image builder
+    fn validate_pkgs(&self, plan: &BuildPlan) {
+        let setup_phase = match &plan.setup {
+            Some(setup_phase) => setup_phase,
+            None => return,
+        };
+        let index = PkgIndex::bundled();
+        for pkg in &setup_phase.pkgs {
+            // The index only lists the packages of the pinned archive
+            let archive = pkg.archive.as_ref().or(setup_phase.archive.as_ref());
+            if archive.is_some_and(|archive| archive != NIXPKGS_ARCHIVE) {
+                continue;
+            }
+
+            if let Some(warning) = index.validate(pkg) {
+                self.logger.log_warning(&warning);
+            }
+        }
+    }
+
+    pub fn build(&mut self, providers: Vec<&'a dyn Provider>) -> Result<BuildResult> {
         self.logger
             .log_section(format!("Building (nixpacks v{})", NIX_PACKS_VERSION).as_str());
 
         let plan = match &self.options.plan_path {
             Some(plan_path) => {
                 self.logger.log_step("Building from existing plan");
-                let plan_json = fs::read_to_string(plan_path).context("Reading build plan")?;
-                let plan: BuildPlan =
-                    serde_json::from_str(&plan_json).context("Deserializing build plan")?;
-                plan
+                let plan = BuildPlan::from_file(plan_path).context("Loading build plan")?;
+                let plan = self.apply_plan_override(plan)?;
+                self.add_secrets(plan)
+                    .context("Adding secrets to build plan")?
             }
             None => {
+                let plan = self.plan(providers).context("Creating build plan")?;
                 self.logger.log_step("Generated new build plan");
-                self.plan(providers).context("Creating build plan")?
+                self.log_detections();
+                plan
             }
         };
 
-        println!("{}", plan.get_build_string());
+        self.logger.log(LogEvent::Plan {
+            plan: Box::new(plan.clone()),
+        });
 
-        self.do_build(&plan)
+        let output = self.do_build(&plan)?;
+        Ok(BuildResult { plan, output })
     }
 
-    pub fn do_build(&mut self, plan: &BuildPlan) -> Result<()> {
+    pub fn do_build(&mut self, plan: &BuildPlan) -> Result<BuildOutput> {
+        // Cache mounts are left out for image builders that can't build them, instead of failing
+        let mut options = self.options.clone();
+        if !self.image_builder_supports_mounts()? {
+            if plan
+                .secrets
+                .as_ref()
+                .is_some_and(|secrets| !secrets.is_empty())
+            {
+                bail!("The image builder doesn't support secret mounts, which build secrets need");
+            }
+            options.no_cache_mounts = true;
+        }
+
+        let dockerfile =
+            AppBuilder::create_dockerfile(plan, &options).context("Generating Dockerfile")?;
+        self.do_build_with_dockerfile(plan, &dockerfile)
+    }
+
+    /// Build the plan with a Dockerfile from `create_dockerfile` that was changed by the caller,
+    /// e.g. to add instructions to the final stage
+    pub fn do_build_with_dockerfile(
+        &mut self,
+        plan: &BuildPlan,
+        dockerfile: &Dockerfile,
+    ) -> Result<BuildOutput> {
         let id = Uuid::new_v4();
 
         let dir = match &self.options.out_dir {
@@ -148,66 +318,177 @@ impl<'a> AppBuilder<'a> {
         };
         let dir_path_str = dir.to_str().context("Invalid temp directory path")?;
 
-        // Copy files into temp directory
-        Self::recursive_copy_dir(&self.app.source, &dir)?;
-
-        AppBuilder::write_build_plan(plan, dir_path_str).context("Writing build plan")?;
-        self.logger.log_step("Building image with Docker");
+        // Copy files into temp directory, leaving out anything that shouldn't be in the image
+        let mut ignore_rules = self.get_ignore_rules()?;
+        Self::recursive_copy_dir(&self.app.source, &dir, &ignore_rules)?;
+
+        // The generated files have to be in the build context, even if the app ignores them
+        for file in [
+            "Dockerfile".to_string(),
+            "environment.nix".to_string(),
+            format!("{}/{}", FLAKE_DIR, FLAKE_FILE_NAME),
+            format!("{}/{}", FLAKE_DIR, FLAKE_LOCK_FILE_NAME),
+        ] {
+            ignore_rules.add(&format!("!{}", file))?;
+        }
+        fs::write(
+            dir.join(DOCKERIGNORE_FILE_NAME),
+            ignore_rules.to_dockerignore(),
+        )
+        .context("Writing .dockerignore")?;
+
+        if self.options.use_flake {
+            for url in get_unhashed_inputs(plan, NIXPKGS_ARCHIVE, NIXPKGS_ARCHIVE_HASH)? {
+                self.logger.log_warning(&format!(
+                    "Flake input `{}` is locked without a NAR hash, so its contents are not verified",
+                    url
+                ));
+            }
+        }
 
+        AppBuilder::write_build_plan_with_dockerfile(plan, dockerfile, dir_path_str, self.options)
+            .context("Writing build plan")?;
         let name = self.name.clone().unwrap_or_else(|| id.to_string());
 
         if self.options.out_dir.is_none() {
-            let mut docker_build_cmd = Command::new("docker");
-            docker_build_cmd
-                .arg("build")
-                .arg(dir)
-                .arg("-t")
-                .arg(name.clone());
-
-            if self.options.quiet {
-                docker_build_cmd.arg("--quiet");
+            // The image builder reads the secret values from the environment of the build command,
+            // which gets the values given with `--env` or an env file
+            let secrets = plan.secrets.clone().unwrap_or_default();
+            let mut secret_values = Vec::new();
+            for name in &secrets {
+                match self.environment.get_variable(name) {
+                    Some(value) => secret_values.push((name.clone(), value.clone())),
+                    None if env::var_os(name).is_some() => {}
+                    None => bail!(
+                        "Secret {} is not set. Set it in the environment or with --env",
+                        name
+                    ),
+                }
             }
 
-            // Add build environment variables
-            for (name, value) in plan.variables.clone().unwrap_or_default().iter() {
-                docker_build_cmd
-                    .arg("--build-arg")
-                    .arg(format!("{}={}", name, value));
-            }
+            let mut build_args = plan
+                .variables
+                .clone()
+                .unwrap_or_default()
+                .into_iter()
+                .collect::<Vec<_>>();
+            build_args.sort();
+
+            // The image builder may not run in the current directory
+            let oci_output = match &self.options.oci_output {
+                Some(path) => Some(env::current_dir()?.join(path)),
+                None => None,
+            };
 
-            // Add user defined tags and labels to the image
-            for t in self.options.tags.clone() {
-                docker_build_cmd.arg("-t").arg(t);
-            }
-            for l in self.options.labels.clone() {
-                docker_build_cmd.arg("--label").arg(l);
-            }
+            let build_options = ImageBuildOptions {
+                name: name.clone(),
+                tags: self.options.tags.clone(),
+                labels: self.options.labels.clone(),
+                build_args,
+                secrets,
+                secret_values,
+                quiet: self.options.quiet,
+                buildkit: dockerfile.uses_mounts(),
+                oci_output: oci_output.clone(),
+            };
 
-            let build_result = docker_build_cmd.spawn()?.wait().context("Building image")?;
+            let default_builder;
+            let image_builder = match &self.image_builder {
+                Some(image_builder) => image_builder.as_ref(),
+                None => {
+                    default_builder = get_image_builder(&self.get_backend()?)?;
+                    default_builder.as_ref()
+                }
+            };
 
-            if !build_result.success() {
-                bail!("Docker build failed")
+            // Phases are recognized by their commands in the output of the image builder
+            let phases = plan
+                .get_ordered_phases()?
+                .into_iter()
+                .filter_map(|phase| phase.cmd.map(|cmd| (phase.name, cmd)))
+                .collect();
+            let phase_logger = PhaseLogger::new(self.logger, phases);
+
+            self.logger
+                .log_step(&format!("Building image with {}", image_builder.name()));
+            image_builder.build_image(&dir, &build_options, &phase_logger)?;
+
+            if let Some(oci_output) = oci_output {
+                let archive = verify_oci_archive(&oci_output).context("Verifying OCI archive")?;
+                self.logger.log_section("Successfully Built!");
+                self.logger.log(LogEvent::OciArchiveSaved {
+                    path: oci_output.display().to_string(),
+                    manifests: archive.manifests,
+                    layers: archive.layers,
+                });
+
+                return Ok(BuildOutput::OciArchive {
+                    path: oci_output,
+                    archive,
+                });
             }
 
             self.logger.log_section("Successfully Built!");
+            self.logger.log(LogEvent::BuildSucceeded {
+                image: name.clone(),
+                run_command: image_builder.run_command(&name),
+            });
 
-            println!("\nRun:");
-            println!("  docker run -it {}", name);
+            Ok(BuildOutput::Image { name })
         } else {
-            println!("\nSaved output to:");
-            println!("  {}", dir_path_str);
-        };
+            self.logger.log(LogEvent::OutputSaved {
+                path: dir_path_str.to_string(),
+            });
 
-        Ok(())
+            Ok(BuildOutput::Directory(dir))
+        }
+    }
+
+    /// Merge the partial plan file from the options onto the plan
+    fn apply_plan_override(&self, plan: BuildPlan) -> Result<BuildPlan> {
+        match &self.options.plan_override_path {
+            Some(path) => {
+                let partial_plan =
+                    BuildPlan::partial_from_file(path).context("Loading build plan override")?;
+                plan.merge(&partial_plan)
+            }
+            None => Ok(plan),
+        }
+    }
+
+    fn get_ignore_rules(&self) -> Result<IgnoreRules> {
+        let mut provider_patterns = Vec::new();
+        for provider in &self.providers {
+            provider_patterns.append(&mut provider.ignore_patterns(self.app, self.environment)?);
+        }
+
+        IgnoreRules::from_app(self.app, provider_patterns, self.options.use_gitignore)
     }
 
-    fn recursive_copy_dir<T: AsRef<Path>, Q: AsRef<Path>>(source: T, dest: Q) -> Result<()> {
-        let walker = WalkDir::new(&source).follow_links(true);
+    fn recursive_copy_dir<T: AsRef<Path>, Q: AsRef<Path>>(
+        source: T,
+        dest: Q,
+        ignore_rules: &IgnoreRules,
+    ) -> Result<()> {
+        // Ignored directories need to be searched if a pattern can add back files inside of them
+        let skip_ignored_dirs = !ignore_rules.has_exceptions();
+        let walker = WalkDir::new(&source)
+            .follow_links(true)
+            .into_iter()
+            .filter_entry(|entry| {
+                let path = entry.path().strip_prefix(&source).unwrap_or(entry.path());
+                !(skip_ignored_dirs && ignore_rules.is_ignored(path))
+            });
+
         for entry in walker {
             let entry = entry?;
 
             let from = entry.path();
-            let to = dest.as_ref().join(from.strip_prefix(&source)?);
+            let relative_path = from.strip_prefix(&source)?;
+            if ignore_rules.is_ignored(relative_path) {
+                continue;
+            }
+            let to = dest.as_ref().join(relative_path);
 
             // create directories
             if entry.file_type().is_dir() {
@@ -220,6 +501,10 @@ impl<'a> AppBuilder<'a> {
             }
             // copy files
             else if entry.file_type().is_file() {
+                // The parent directory may have been ignored when the file was added back
+                if let Some(parent) = to.parent() {
+                    fs::create_dir_all(parent)?;
+                }
                 fs::copy(from, to)?;
             }
         }
@@ -227,12 +512,18 @@ impl<'a> AppBuilder<'a> {
     }
 
     fn get_setup_phase(&self) -> Result<SetupPhase> {
-        let mut setup_phase: SetupPhase = match self.provider {
-            Some(provider) => provider
-                .setup(self.app, self.environment)?
-                .unwrap_or_default(),
-            None => SetupPhase::default(),
-        };
+        let config = self.config()?;
+        let mut setup_phase: Option<SetupPhase> = None;
+        for provider in &self.providers {
+            if let Some(provider_phase) = provider.setup(self.app, self.environment)? {
+                setup_phase = Some(match setup_phase {
+                    Some(phase) => merge_setup_phases(phase, provider_phase)
+                        .with_context(|| format!("Combining the {} provider", provider.name()))?,
+                    None => provider_phase,
+                });
+            }
+        }
+        let mut setup_phase = setup_phase.unwrap_or_default();
 
         let env_var_pkgs = self
             .environment
@@ -240,56 +531,126 @@ impl<'a> AppBuilder<'a> {
             .map(|pkg_string| pkg_string.split(' ').map(Pkg::new).collect::<Vec<_>>())
             .unwrap_or_default();
 
+        let config_pkgs = config
+            .get_pkgs()
+            .iter()
+            .map(|name| Pkg::new(name))
+            .collect::<Vec<_>>();
+
         // Add custom user packages
-        let mut pkgs = [self.options.custom_pkgs.clone(), env_var_pkgs].concat();
+        let mut pkgs = [config_pkgs, self.options.custom_pkgs.clone(), env_var_pkgs].concat();
         setup_phase.add_pkgs(&mut pkgs);
 
         if self.options.pin_pkgs {
-            setup_phase.set_archive(NIXPKGS_ARCHIVE.to_string())
+            setup_phase.set_archive(NIXPKGS_ARCHIVE.to_string());
+            setup_phase.archive_hash = NIXPKGS_ARCHIVE_HASH.map(str::to_string);
+        } else if let Some(archive) = config.get_archive() {
+            setup_phase.set_archive(archive);
+            setup_phase.archive_hash = config.get_archive_hash();
+        }
+
+        // Packages requested as `name@version` are looked up in the version index, and replace
+        // the package that a provider chose for the same language
+        let resolved = setup_phase
+            .pkgs
+            .into_iter()
+            .map(resolve_pkg_version)
+            .collect::<Result<Vec<_>>>()?;
+        let requested_languages = resolved
+            .iter()
+            .filter_map(|(_, language)| language.clone())
+            .collect::<Vec<_>>();
+        setup_phase.pkgs = resolved
+            .into_iter()
+            .filter(|(pkg, language)| {
+                language.is_some()
+                    || get_language(&pkg.name)
+                        .is_none_or(|language| !requested_languages.contains(&language))
+            })
+            .map(|(pkg, _)| pkg)
+            .collect();
+
+        let pins = config.get_archive_pins();
+        for pkg in &mut setup_phase.pkgs {
+            if let Some(pin) = pins.get(&pkg.name) {
+                pkg.archive = Some(pin.archive.clone());
+                pkg.archive_hash.clone_from(&pin.archive_hash);
+            }
         }
 
         Ok(setup_phase)
     }
 
     fn get_install_phase(&self) -> Result<InstallPhase> {
-        let install_phase = match self.provider {
-            Some(provider) => provider
-                .install(self.app, self.environment)?
-                .unwrap_or_default(),
-            None => InstallPhase::default(),
-        };
+        let config = self.config()?;
+        let mut install_phase: Option<InstallPhase> = None;
+        for provider in &self.providers {
+            if let Some(provider_phase) = provider.install(self.app, self.environment)? {
+                install_phase = Some(match install_phase {
+                    Some(phase) => merge_install_phases(phase, provider_phase),
+                    None => provider_phase,
+                });
+            }
+        }
+        let mut install_phase = install_phase.unwrap_or_default();
+
+        // Install command priority
+        // - config file
+        // - provider
+        install_phase.cmd = config.get_install_cmd().or(install_phase.cmd);
+        install_phase.depends_on = config.get_install_depends_on().or(install_phase.depends_on);
+        install_phase.cache_directories = merge_lists(
+            install_phase.cache_directories,
+            config.get_install_cache_directories(),
+            false,
+        );
 
         Ok(install_phase)
     }
 
     fn get_build_phase(&self) -> Result<BuildPhase> {
-        let mut build_phase = match self.provider {
-            Some(provider) => provider
-                .build(self.app, self.environment)?
-                .unwrap_or_default(),
-            None => BuildPhase::default(),
-        };
+        let config = self.config()?;
+        let mut build_phase: Option<BuildPhase> = None;
+        for provider in &self.providers {
+            if let Some(provider_phase) = provider.build(self.app, self.environment)? {
+                build_phase = Some(match build_phase {
+                    Some(phase) => merge_build_phases(phase, provider_phase),
+                    None => provider_phase,
+                });
+            }
+        }
+        let mut build_phase = build_phase.unwrap_or_default();
 
         let env_build_cmd = self.environment.get_config_variable("BUILD_CMD").cloned();
 
         // Build command priority
         // - custom build command
         // - environment variable
+        // - config file
         // - provider
         build_phase.cmd = self
             .options
             .custom_build_cmd
             .clone()
             .or(env_build_cmd)
+            .or_else(|| config.get_build_cmd())
             .or(build_phase.cmd);
+        build_phase.depends_on = config.get_build_depends_on().or(build_phase.depends_on);
+        build_phase.cache_directories = merge_lists(
+            build_phase.cache_directories,
+            config.get_build_cache_directories(),
+            false,
+        );
 
         Ok(build_phase)
     }
 
     fn get_start_phase(&self) -> Result<StartPhase> {
+        let config = self.config()?;
         let procfile_cmd = self.parse_procfile()?;
 
-        let mut start_phase = match self.provider {
+        // Only a single provider can decide how the app is started
+        let mut start_phase = match self.start_provider {
             Some(provider) => provider
                 .start(self.app, self.environment)?
                 .unwrap_or_default(),
@@ -301,20 +662,27 @@ impl<'a> AppBuilder<'a> {
         // Start command priority
         // - custom start command
         // - environment variable
+        // - config file
         // - procfile
         // - provider
-        start_phase.cmd = self
-            .options
-            .custom_start_cmd
-            .clone()
-            .or_else(|| env_start_cmd.or_else(|| procfile_cmd.or(start_phase.cmd)));
-
-        // Allow the user to override the run image with an environment variable
-        if let Some(env_run_image) = self.environment.get_config_variable("RUN_IMAGE") {
-            // If the env var is "falsy", then unset the run image on the start phase
-            start_phase.run_image = match env_run_image.as_str() {
-                "0" | "false" => None,
-                img if img.is_empty() => None,
+        start_phase.cmd = self.options.custom_start_cmd.clone().or_else(|| {
+            env_start_cmd.or_else(|| {
+                config
+                    .get_start_cmd()
+                    .or_else(|| procfile_cmd.or(start_phase.cmd))
+            })
+        });
+
+        // Allow the user to override the run image with an environment variable or config file
+        let run_image = self
+            .environment
+            .get_config_variable("RUN_IMAGE")
+            .cloned()
+            .or_else(|| config.get_run_image());
+        if let Some(run_image) = run_image {
+            // If the value is "falsy", then unset the run image on the start phase
+            start_phase.run_image = match run_image.as_str() {
+                "0" | "false" | "" => None,
                 img => Some(img.to_owned()),
             };
         }
@@ -323,35 +691,244 @@ impl<'a> AppBuilder<'a> {
     }
 
     fn get_variables(&self) -> Result<EnvironmentVariables> {
-        // Get a copy of the variables in the environment
-        let variables = Environment::clone_variables(self.environment);
-
-        let new_variables = match self.provider {
-            Some(provider) => {
-                // Merge provider variables
-                let provider_variables = provider
-                    .environment_variables(self.app, self.environment)?
-                    .unwrap_or_default();
-                provider_variables.into_iter().chain(variables).collect()
-            }
-            None => variables,
-        };
+        // Variables from the config file are overridden by the environment
+        let variables = self
+            .config()?
+            .variables
+            .clone()
+            .unwrap_or_default()
+            .into_iter()
+            .chain(Environment::clone_variables(self.environment))
+            .collect::<EnvironmentVariables>();
+
+        // Merge provider variables, with earlier providers taking priority
+        let mut provider_variables = EnvironmentVariables::new();
+        for provider in self.providers.iter().rev() {
+            if let Some(new_variables) =
+                provider.environment_variables(self.app, self.environment)?
+            {
+                provider_variables.extend(new_variables);
+            }
+        }
+
+        Ok(provider_variables.into_iter().chain(variables).collect())
+    }
 
-        Ok(new_variables)
+    /// Scopes of the variables in the plan, declared by the providers or the config file
+    fn get_variable_scopes(
+        &self,
+        variables: &EnvironmentVariables,
+    ) -> Result<Option<VariableScopes>> {
+        // Earlier providers take priority
+        let mut scopes = VariableScopes::new();
+        for provider in self.providers.iter().rev() {
+            let provider_scopes = provider.variable_scopes(self.app, self.environment)?;
+            let provider_variables = provider
+                .environment_variables(self.app, self.environment)?
+                .unwrap_or_default();
+            for name in provider_scopes.keys() {
+                if !provider_variables.contains_key(name) {
+                    bail!(
+                        "Provider {} sets the scope of {}, which is not one of its variables",
+                        provider.name(),
+                        name
+                    );
+                }
+            }
+            scopes.extend(provider_scopes);
+        }
+
+        let config_scopes = self.config()?.variable_scopes.clone().unwrap_or_default();
+        for name in config_scopes.keys() {
+            if !variables.contains_key(name) {
+                self.logger.log_warning(&format!(
+                    "`variableScopes` in {} sets the scope of {}, which is not a variable of the plan",
+                    CONFIG_FILE_NAME, name
+                ));
+            }
+        }
+        scopes.extend(config_scopes);
+
+        scopes.retain(|name, scope| variables.contains_key(name) && scope != &VariableScope::Both);
+
+        Ok(match scopes.is_empty() {
+            true => None,
+            false => Some(scopes),
+        })
+    }
+
+    /// Whether the image builder that will build the app supports `RUN --mount`
+    ///
+    /// The Dockerfile saved to an out dir keeps its mounts, since any builder may be used for it.
+    fn image_builder_supports_mounts(&self) -> Result<bool> {
+        match &self.image_builder {
+            Some(image_builder) => Ok(image_builder.supports_mounts()),
+            None if self.options.out_dir.is_some() => Ok(true),
+            None => Ok(get_image_builder(&self.get_backend()?)?.supports_mounts()),
+        }
+    }
+
+    /// Name of the built-in image builder, from the options, environment or config file
+    fn get_backend(&self) -> Result<String> {
+        let backend = self
+            .options
+            .backend
+            .clone()
+            .or_else(|| self.environment.get_config_variable("BACKEND").cloned());
+        match backend {
+            Some(backend) => Ok(backend),
+            None => Ok(self
+                .config()?
+                .backend
+                .clone()
+                .unwrap_or_else(|| DEFAULT_IMAGE_BUILDER.to_string())),
+        }
+    }
+
+    fn get_secrets(&self) -> Result<Vec<String>> {
+        let mut secrets = self.config()?.secrets.clone().unwrap_or_default();
+        for name in &self.options.secrets {
+            if !secrets.contains(name) {
+                secrets.push(name.clone());
+            }
+        }
+
+        validate_secret_names(&secrets)?;
+        Ok(secrets)
+    }
+
+    /// Add the secrets of the options to a plan that was loaded from a file
+    fn add_secrets(&self, mut plan: BuildPlan) -> Result<BuildPlan> {
+        if self.options.secrets.is_empty() {
+            return Ok(plan);
+        }
+
+        let mut secrets = plan.secrets.take().unwrap_or_default();
+        for name in &self.options.secrets {
+            if !secrets.contains(name) {
+                secrets.push(name.clone());
+            }
+        }
+        validate_secret_names(&secrets)?;
+
+        // Secrets must never be saved as variables in the image
+        if let Some(variables) = &mut plan.variables {
+            variables.retain(|name, _| !secrets.contains(name));
+        }
+        plan.secrets = Some(secrets);
+        Ok(plan)
     }
 
     fn detect(&mut self, providers: Vec<&'a dyn Provider>) -> Result<()> {
+        // Use the providers set in the config file instead of detecting one
+        let config = self.config()?;
+        let names = match (&config.provider, &config.providers) {
+            (Some(_), Some(_)) => bail!(
+                "Only one of `provider` and `providers` can be set in {}",
+                CONFIG_FILE_NAME
+            ),
+            (Some(name), None) => Some(vec![name.clone()]),
+            (None, names) => names.clone(),
+        };
+        if let Some(names) = names {
+            let mut configured = Vec::new();
+            for name in &names {
+                match providers.iter().find(|provider| provider.name() == name) {
+                    Some(provider) => configured.push(*provider),
+                    None => bail!("Unknown provider `{}` in {}", name, CONFIG_FILE_NAME),
+                }
+            }
+            self.start_provider = configured.first().copied();
+            self.providers = configured;
+            return Ok(());
+        }
+
+        let mut detections = Vec::new();
         for provider in providers {
-            let matches = provider.detect(self.app, self.environment)?;
-            if matches {
-                self.provider = Some(provider);
-                break;
+            let detection = provider.detect(self.app, self.environment)?;
+            if detection.matches() {
+                detections.push((provider, detection));
             }
         }
 
+        // Equal scores are ordered by name so the result doesn't depend on the provider order
+        detections.sort_by(|(a, a_detection), (b, b_detection)| {
+            b_detection
+                .score
+                .cmp(&a_detection.score)
+                .then_with(|| a.name().cmp(b.name()))
+        });
+
+        // Other matches are often tooling for the best match (e.g. a package.json next to a
+        // go.mod), so providers are only combined when they are listed with `providers`
+        self.providers = detections
+            .iter()
+            .take(1)
+            .map(|(provider, _)| *provider)
+            .collect();
+        self.start_provider = self.providers.first().copied();
+        self.detections = detections;
+
         Ok(())
     }
 
+    fn log_detections(&self) {
+        for (provider, detection) in &self.detections {
+            self.logger.log(LogEvent::ProviderDetected {
+                provider: provider.name().to_string(),
+                score: detection.score,
+                reasons: detection.reasons.clone(),
+            });
+        }
+
+        if self.detections.len() > 1 {
+            self.logger.log_step(
+                format!(
+                    "Detected providers: {}",
+                    self.detections
+                        .iter()
+                        .map(|(provider, detection)| format!(
+                            "{} ({})",
+                            provider.name(),
+                            detection.score
+                        ))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+                .as_str(),
+            );
+            self.logger.log_step(
+                format!(
+                    "Using {}. Set `providers = [...]` in {} to combine providers",
+                    self.detections[0].0.name(),
+                    CONFIG_FILE_NAME
+                )
+                .as_str(),
+            );
+        }
+
+        if let Some((start_provider, best)) = self.detections.first() {
+            let tied = self
+                .detections
+                .iter()
+                .filter(|(_, detection)| detection.score == best.score)
+                .map(|(provider, _)| provider.name())
+                .collect::<Vec<_>>();
+
+            if tied.len() > 1 {
+                self.logger.log_step(
+                    format!(
+                        "Providers {} matched with the same score ({}), using {} to start the app",
+                        tied.join(", "),
+                        best.score,
+                        start_provider.name()
+                    )
+                    .as_str(),
+                );
+            }
+        }
+    }
+
     fn parse_procfile(&self) -> Result<Option<String>> {
         if self.app.includes_file("Procfile") {
             let contents = self.app.read_file("Procfile")?;
@@ -367,41 +944,65 @@ impl<'a> AppBuilder<'a> {
         }
     }
 
-    pub fn write_build_plan(plan: &BuildPlan, dest: &str) -> Result<()> {
-        let nix_expression = AppBuilder::gen_nix(plan).context("Generating Nix expression")?;
-        let dockerfile = AppBuilder::gen_dockerfile(plan).context("Generating Dockerfile")?;
+    pub fn write_build_plan(
+        plan: &BuildPlan,
+        dest: &str,
+        options: &AppBuilderOptions,
+    ) -> Result<()> {
+        let dockerfile =
+            AppBuilder::create_dockerfile(plan, options).context("Generating Dockerfile")?;
+        AppBuilder::write_build_plan_with_dockerfile(plan, &dockerfile, dest, options)
+    }
 
-        let nix_path = PathBuf::from(dest).join(PathBuf::from("environment.nix"));
-        let mut nix_file = File::create(nix_path).context("Creating Nix environment file")?;
-        nix_file
-            .write_all(nix_expression.as_bytes())
-            .context("Unable to write Nix expression")?;
+    /// Write the files of the plan with a Dockerfile that was changed after `create_dockerfile`
+    pub fn write_build_plan_with_dockerfile(
+        plan: &BuildPlan,
+        dockerfile: &Dockerfile,
+        dest: &str,
+        options: &AppBuilderOptions,
+    ) -> Result<()> {
+        if options.use_flake {
+            let flake_dir = PathBuf::from(dest).join(FLAKE_DIR);
+            fs::create_dir_all(&flake_dir).context("Creating flake directory")?;
+
+            let flake = gen_flake(plan, NIXPKGS_ARCHIVE).context("Generating flake")?;
+            fs::write(flake_dir.join(FLAKE_FILE_NAME), flake).context("Writing flake.nix")?;
+
+            let flake_lock = gen_flake_lock(plan, NIXPKGS_ARCHIVE, NIXPKGS_ARCHIVE_HASH)
+                .context("Generating flake lock")?;
+            fs::write(flake_dir.join(FLAKE_LOCK_FILE_NAME), flake_lock)
+                .context("Writing flake.lock")?;
+        } else {
+            let nix_expression = AppBuilder::gen_nix(plan).context("Generating Nix expression")?;
+            let nix_path = PathBuf::from(dest).join(PathBuf::from("environment.nix"));
+            let mut nix_file = File::create(nix_path).context("Creating Nix environment file")?;
+            nix_file
+                .write_all(nix_expression.as_bytes())
+                .context("Unable to write Nix expression")?;
+        }
 
         let dockerfile_path = PathBuf::from(dest).join(PathBuf::from("Dockerfile"));
         File::create(dockerfile_path.clone()).context("Creating Dockerfile file")?;
-        fs::write(dockerfile_path, dockerfile).context("Writing Dockerfile")?;
+        fs::write(dockerfile_path, dockerfile.to_string()).context("Writing Dockerfile")?;
 
         Ok(())
     }
 
     pub fn gen_nix(plan: &BuildPlan) -> Result<String> {
         let setup_phase = plan.setup.clone().unwrap_or_default();
+        let archives = get_pkg_archives(&setup_phase.pkgs, setup_phase.archive.as_deref());
 
         let nixpkgs = setup_phase
             .pkgs
             .iter()
-            .map(|p| p.to_nix_string())
+            .map(|p| p.to_nix_string_with_archives(&archives))
             .collect::<Vec<String>>()
             .join(" ");
 
-        let nix_archive = setup_phase.archive.clone();
-        let pkg_import = match nix_archive {
-            Some(archive) => format!(
-                "import (fetchTarball \"https://github.com/NixOS/nixpkgs/archive/{}.tar.gz\")",
-                archive
-            ),
-            None => "import <nixpkgs>".to_string(),
-        };
+        let pkg_import = nixpkgs_import(
+            setup_phase.archive.as_deref(),
+            setup_phase.archive_hash.as_deref(),
+        );
 
         let mut overlays: Vec<String> = Vec::new();
         for pkg in &setup_phase.pkgs {
@@ -415,6 +1016,23 @@ impl<'a> AppBuilder<'a> {
             .collect::<Vec<String>>()
             .join("\n");
 
+        // Every distinct archive that packages are pinned to is imported once
+        let archive_imports = archives
+            .iter()
+            .enumerate()
+            .map(|(i, archive)| {
+                format!(
+                    "\n  {} = {} {{ overlays = [ {}]; }};",
+                    archive_import_name(i),
+                    nixpkgs_import(Some(archive), get_archive_hash(&setup_phase.pkgs, archive)),
+                    overlays
+                        .iter()
+                        .map(|url| format!("(import (builtins.fetchTarball \"{}\")) ", url))
+                        .collect::<String>()
+                )
+            })
+            .collect::<String>();
+
         let nix_expression = formatdoc! {"
             {{ }}:
 
@@ -423,7 +1041,7 @@ impl<'a> AppBuilder<'a> {
                 overlays = [
                   {overlays}
                 ];
-              }};
+              }};{archive_imports}
             in with pkgs;
             buildEnv {{
               name = \"env\";
@@ -433,177 +1051,302 @@ impl<'a> AppBuilder<'a> {
             }}
         ",
         pkg_import=pkg_import,
+        archive_imports=archive_imports,
         pkgs=nixpkgs,
         overlays=overlays_string};
 
         Ok(nix_expression)
     }
 
-    pub fn gen_dockerfile(plan: &BuildPlan) -> Result<String> {
+    pub fn gen_dockerfile(plan: &BuildPlan, options: &AppBuilderOptions) -> Result<String> {
+        let dockerfile = AppBuilder::create_dockerfile(plan, options)?;
+        Ok(dockerfile.to_string())
+    }
+
+    /// Lower the plan into Dockerfile instructions
+    pub fn create_dockerfile(plan: &BuildPlan, options: &AppBuilderOptions) -> Result<Dockerfile> {
         let app_dir = "/app/";
 
         let setup_phase = plan.setup.clone().unwrap_or_default();
-        let install_phase = plan.install.clone().unwrap_or_default();
-        let build_phase = plan.build.clone().unwrap_or_default();
         let start_phase = plan.start.clone().unwrap_or_default();
-        let variables = plan.variables.clone().unwrap_or_default();
+        let secrets = plan.secrets.clone().unwrap_or_default();
 
-        // -- Variables
-        let args_string = if !variables.is_empty() {
-            format!(
-                "ARG {}\nENV {}",
-                // Pull the variables in from docker `--build-arg`
-                variables
-                    .iter()
-                    .map(|var| var.0.to_string())
-                    .collect::<Vec<_>>()
-                    .join(" "),
-                // Make the variables available at runtime
-                variables
-                    .iter()
-                    .map(|var| format!("{}=${}", var.0, var.0))
-                    .collect::<Vec<_>>()
-                    .join(" ")
-            )
-        } else {
-            "".to_string()
-        };
+        let mut dockerfile = Dockerfile::new();
+        let mut stage = Stage::new(&setup_phase.base_image);
+        stage.add(Instruction::Workdir(app_dir.to_string()));
 
         // -- Setup
-        let mut setup_files: Vec<String> = vec!["environment.nix".to_string()];
+        stage.add(Instruction::Comment("Setup".to_string()));
+        let mut setup_files = match options.use_flake {
+            true => Vec::new(),
+            false => vec!["environment.nix".to_string()],
+        };
         if let Some(mut setup_file_deps) = setup_phase.only_include_files {
             setup_files.append(&mut setup_file_deps);
         }
-        let setup_copy_cmd = format!("COPY {} {}", setup_files.join(" "), app_dir);
-
-        // -- Install
-        let install_cmd = install_phase
-            .cmd
-            .clone()
-            .map(|cmd| format!("RUN {}", cmd))
-            .unwrap_or_else(|| "".to_string());
-
-        let path_env = if let Some(paths) = install_phase.paths {
-            format!("ENV PATH {}:$PATH", paths.join(":"))
+        if options.use_flake {
+            stage.add_copy(
+                vec![
+                    format!("{}/{}", FLAKE_DIR, FLAKE_FILE_NAME),
+                    format!("{}/{}", FLAKE_DIR, FLAKE_LOCK_FILE_NAME),
+                ],
+                &format!("{}{}/", app_dir, FLAKE_DIR),
+            );
+        }
+        stage.add_copy(setup_files, app_dir);
+        if options.use_flake {
+            stage.add(Instruction::run(&format!(
+                "nix --extra-experimental-features 'nix-command flakes' profile install --profile {} ./{}#default",
+                FLAKE_PROFILE, FLAKE_DIR
+            )));
+            stage.add(Instruction::EnvExpand(vec![(
+                "PATH".to_string(),
+                format!("{}/bin:$PATH", FLAKE_PROFILE),
+            )]));
         } else {
-            "".to_string()
-        };
+            stage.add(Instruction::run("nix-env -if environment.nix"));
+        }
 
-        // Files to copy for install phase
-        // If none specified, copy over the entire app
-        let install_files = install_phase
-            .only_include_files
-            .clone()
-            .unwrap_or_else(|| vec![".".to_string()]);
+        // -- Variables
+        let build_variables = plan.get_variable_names(VariableScope::is_build);
+        if !build_variables.is_empty() {
+            stage.add(Instruction::Comment(
+                "Load environment variables".to_string(),
+            ));
+            // Variables that are also needed at runtime are kept in the image
+            let image_variables = plan.get_variable_names(|scope| scope == &VariableScope::Both);
+            add_variables(&mut stage, build_variables, image_variables);
+        }
 
-        // -- Build
-        let build_cmd = build_phase
-            .cmd
-            .clone()
-            .map(|cmd| format!("RUN {}", cmd))
-            .unwrap_or_else(|| "".to_string());
+        // -- Phases
+        let mut copied_all_files = false;
+        for phase in plan.get_ordered_phases()? {
+            let title = match phase.name.as_str() {
+                INSTALL_PHASE_NAME => "Install".to_string(),
+                BUILD_PHASE_NAME => "Build".to_string(),
+                name => name.to_string(),
+            };
+            stage.add(Instruction::Comment(title));
+
+            // Files to copy for the phase
+            // If none specified, copy over the entire app if we haven't already
+            let files = match phase.only_include_files {
+                Some(files) => files,
+                None if !copied_all_files => {
+                    copied_all_files = true;
+                    vec![".".to_string()]
+                }
+                None => Vec::new(),
+            };
+            stage.add_copy(files, app_dir);
+
+            if let Some(cmd) = phase.cmd {
+                // Keep package manager caches between builds
+                let mut mounts: Vec<Mount> = match phase.cache_directories {
+                    Some(dirs) if !options.no_cache_mounts => dirs
+                        .into_iter()
+                        .map(|target| Mount::Cache { target })
+                        .collect(),
+                    _ => Vec::new(),
+                };
+                mounts.extend(secrets.iter().map(|id| Mount::Secret { id: id.clone() }));
+                stage.add(Instruction::Run { mounts, cmd });
+            }
 
-        let build_files = build_phase.only_include_files.unwrap_or_else(|| {
-            // Only copy over the entire app if we haven't already in the install phase
-            if install_phase.only_include_files.is_none() {
-                Vec::new()
-            } else {
-                vec![".".to_string()]
+            if let Some(paths) = phase.paths {
+                stage.add(Instruction::EnvExpand(vec![(
+                    "PATH".to_string(),
+                    format!("{}:$PATH", paths.join(":")),
+                )]));
             }
-        });
+        }
 
         // -- Start
-        let start_cmd = start_phase
-            .cmd
-            .map(|cmd| format!("CMD {}", cmd))
-            .unwrap_or_else(|| "".to_string());
-
-        // If we haven't yet copied over the entire app, do that before starting
         let start_files = start_phase.only_include_files.clone();
-
-        let run_image_setup = match start_phase.run_image {
+        match start_phase.run_image {
             Some(run_image) => {
+                dockerfile.add_stage(stage);
+
+                stage = Stage::new(&run_image);
+                stage.add(Instruction::Comment("Start".to_string()));
+                stage.add(Instruction::Workdir(app_dir.to_string()));
+                stage.add(Instruction::Copy {
+                    from: Some("0".to_string()),
+                    sources: vec!["/etc/ssl/certs".to_string()],
+                    dest: "/etc/ssl/certs".to_string(),
+                });
                 // RUN true to prevent a Docker bug https://github.com/moby/moby/issues/37965#issuecomment-426853382
-                formatdoc! {"
-                FROM {run_image}
-                WORKDIR {app_dir}
-                COPY --from=0 /etc/ssl/certs /etc/ssl/certs
-                RUN true
-                {copy_cmd}
-            ",
-                    run_image=run_image,
-                    app_dir=app_dir,
-                    copy_cmd=get_copy_from_command("0", &start_files.unwrap_or_default(), app_dir)
-                }
+                stage.add(Instruction::run("true"));
+
+                // If no files specified, copy everything in /app/ over
+                let files = match start_files {
+                    Some(files) if !files.is_empty() => files
+                        .iter()
+                        .map(|f| f.replace("./", app_dir))
+                        .collect::<Vec<_>>(),
+                    _ => vec![app_dir.to_string()],
+                };
+                stage.add(Instruction::Copy {
+                    from: Some("0".to_string()),
+                    sources: files,
+                    dest: app_dir.to_string(),
+                });
+
+                // Variables are not carried over from the build stage
+                let runtime_variables = plan.get_variable_names(VariableScope::is_runtime);
+                add_variables(&mut stage, runtime_variables.clone(), runtime_variables);
             }
-            None => get_copy_command(
+            None => {
+                stage.add(Instruction::Comment("Start".to_string()));
                 // If no files specified and no run image, copy everything in /app/ over
-                &start_files.unwrap_or_else(|| vec![".".to_string()]),
-                app_dir,
-            ),
-        };
+                stage.add_copy(
+                    start_files.unwrap_or_else(|| vec![".".to_string()]),
+                    app_dir,
+                );
+
+                let runtime_variables =
+                    plan.get_variable_names(|scope| scope == &VariableScope::Runtime);
+                add_variables(&mut stage, runtime_variables.clone(), runtime_variables);
+            }
+        }
+
+        if let Some(cmd) = start_phase.cmd {
+            stage.add(Instruction::Cmd(cmd));
+        }
+        dockerfile.add_stage(stage);
+
+        Ok(dockerfile)
+    }
+}
 
-        let dockerfile = formatdoc! {"
-          FROM {base_image}
+fn validate_secret_names(secrets: &[String]) -> Result<()> {
+    for name in secrets {
+        let valid = !name.is_empty()
+            && !name.starts_with(|c: char| c.is_ascii_digit())
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            bail!("Invalid secret name `{}`", name);
+        }
+    }
+    Ok(())
+}
 
-          WORKDIR {app_dir}
+/// Pull the variables in from docker `--build-arg`, setting the ones in `env_names` in the image
+fn add_variables(stage: &mut Stage, arg_names: Vec<String>, env_names: Vec<String>) {
+    if !arg_names.is_empty() {
+        stage.add(Instruction::Arg(arg_names));
+    }
+    if !env_names.is_empty() {
+        stage.add(Instruction::EnvExpand(
+            env_names
+                .into_iter()
+                .map(|name| {
+                    let value = format!("${}", name);
+                    (name, value)
+                })
+                .collect(),
+        ));
+    }
+}
+
+/// Replace a package requested as `name@version` with the attribute from the version index, pinned
+/// to the nixpkgs archive that carries it, or pin a package requested as `name@<nixpkgs revision>`
+/// to that revision
+///
+/// Returns the language of packages that were found in the version index.
+fn resolve_pkg_version(pkg: Pkg) -> Result<(Pkg, Option<String>)> {
+    let (name, requirement) = match parse_pkg_version(&pkg.name) {
+        Some(split) => split,
+        None => return Ok((pkg, None)),
+    };
+    if is_revision(requirement) {
+        return Ok((Pkg::new(name).from_archive(requirement), None));
+    }
+    let resolved = resolve_version(name, requirement)?;
+
+    Ok((
+        Pkg {
+            name: resolved.pkg.name,
+            archive: resolved.pkg.archive.or(pkg.archive),
+            ..pkg
+        },
+        Some(resolved.language),
+    ))
+}
 
-          # Setup
-          {setup_copy_cmd}
-          RUN nix-env -if environment.nix
+fn merge_setup_phases(mut phase: SetupPhase, other: SetupPhase) -> Result<SetupPhase> {
+    // A provider that needs a specific base image wins over the default one
+    if other.base_image != phase.base_image {
+        if phase.base_image == DEFAULT_BASE_IMAGE {
+            phase.base_image = other.base_image;
+        } else if other.base_image != DEFAULT_BASE_IMAGE {
+            bail!(
+                "Providers need different base images, {} and {}",
+                phase.base_image,
+                other.base_image
+            );
+        }
+    }
+    for pkg in other.pkgs {
+        if !phase.pkgs.contains(&pkg) {
+            phase.pkgs.push(pkg);
+        }
+    }
+    if phase.archive.is_none() {
+        phase.archive = other.archive;
+        phase.archive_hash = other.archive_hash;
+    }
+    phase.only_include_files =
+        merge_lists(phase.only_include_files, other.only_include_files, false);
 
-          # Load environment variables
-          {args_string}
+    Ok(phase)
+}
 
-          # Install
-          {install_copy_cmd}
-          {install_cmd}
-          {path_env}
+fn merge_install_phases(mut phase: InstallPhase, other: InstallPhase) -> InstallPhase {
+    phase.cmd = merge_cmds(phase.cmd, other.cmd);
+    phase.depends_on = merge_lists(phase.depends_on, other.depends_on, false);
+    phase.only_include_files =
+        merge_lists(phase.only_include_files, other.only_include_files, true);
+    phase.paths = merge_lists(phase.paths, other.paths, false);
+    phase.cache_directories = merge_lists(phase.cache_directories, other.cache_directories, false);
 
-          # Build
-          {build_copy_cmd}
-          {build_cmd}
+    phase
+}
 
-          # Start
-          {run_image_setup}
-          {start_cmd}
-        ",
-        base_image=setup_phase.base_image,
-        setup_copy_cmd=setup_copy_cmd,
-        args_string=args_string,
-        install_copy_cmd=get_copy_command(&install_files, app_dir),
-        install_cmd=install_cmd,
-        path_env=path_env,
-        build_copy_cmd=get_copy_command(&build_files, app_dir),
-        build_cmd=build_cmd,
-        run_image_setup=run_image_setup,
-        start_cmd=start_cmd};
+fn merge_build_phases(mut phase: BuildPhase, other: BuildPhase) -> BuildPhase {
+    phase.cmd = merge_cmds(phase.cmd, other.cmd);
+    phase.depends_on = merge_lists(phase.depends_on, other.depends_on, false);
+    phase.only_include_files =
+        merge_lists(phase.only_include_files, other.only_include_files, true);
+    phase.cache_directories = merge_lists(phase.cache_directories, other.cache_directories, false);
 
-        Ok(dockerfile)
-    }
+    phase
 }
 
-fn get_copy_command(files: &[String], app_dir: &str) -> String {
-    if files.is_empty() {
-        "".to_owned()
-    } else {
-        format!("COPY {} {}", files.join(" "), app_dir)
+fn merge_cmds(cmd: Option<String>, other: Option<String>) -> Option<String> {
+    match (cmd, other) {
+        (Some(cmd), Some(other)) => Some(format!("{} && {}", cmd, other)),
+        (cmd, other) => cmd.or(other),
     }
 }
 
-fn get_copy_from_command(from: &str, files: &[String], app_dir: &str) -> String {
-    if files.is_empty() {
-        format!("COPY --from=0 {} {}", app_dir, app_dir)
-    } else {
-        format!(
-            "COPY --from={} {} {}",
-            from,
-            files
-                .iter()
-                .map(|f| f.replace("./", app_dir))
-                .collect::<Vec<_>>()
-                .join(" "),
-            app_dir
-        )
+/// Combine two optional lists of files. If `none_includes_all` is set, a missing list means
+/// every file is needed, so the combined phase depends on every file as well.
+fn merge_lists(
+    files: Option<Vec<String>>,
+    other: Option<Vec<String>>,
+    none_includes_all: bool,
+) -> Option<Vec<String>> {
+    match (files, other) {
+        (Some(mut files), Some(other)) => {
+            for file in other {
+                if !files.contains(&file) {
+                    files.push(file);
+                }
+            }
+            Some(files)
+        }
+        (files, other) if !none_includes_all => files.or(other),
+        _ => None,
     }
 }
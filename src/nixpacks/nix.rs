@@ -1,13 +1,22 @@
 use std::collections::HashMap;
 
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 #[serde_with::skip_serializing_none]
-#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Clone, Debug)]
 pub struct Pkg {
     pub name: String,
     pub overlay: Option<String>,
+    /// NAR hash of the overlay tarball (e.g. `sha256-...`), used to lock it in a flake
+    #[serde(rename = "overlayHash")]
+    pub overlay_hash: Option<String>,
     pub overrides: Option<HashMap<String, String>>,
+    /// nixpkgs revision to install this package from, instead of the archive of the setup phase
+    pub archive: Option<String>,
+    /// NAR hash of the archive (e.g. `sha256-...`), used to verify it when it is fetched
+    #[serde(rename = "archiveHash")]
+    pub archive_hash: Option<String>,
 }
 
 #[serde_with::skip_serializing_none]
@@ -23,6 +32,9 @@ impl Pkg {
             name: name.to_string(),
             overrides: None,
             overlay: None,
+            overlay_hash: None,
+            archive: None,
+            archive_hash: None,
         }
     }
 
@@ -56,7 +68,37 @@ impl Pkg {
         self
     }
 
+    /// Nix expression for the package, selected from the import of its archive when it is one of
+    /// `archives` (see `get_pkg_archives`)
+    pub fn to_nix_string_with_archives(&self, archives: &[String]) -> String {
+        let archive_index = self
+            .archive
+            .as_ref()
+            .and_then(|archive| archives.iter().position(|a| a == archive));
+        match archive_index {
+            Some(i) => Pkg {
+                name: format!("{}.{}", archive_import_name(i), self.name),
+                ..self.clone()
+            }
+            .to_nix_string(),
+            None => self.to_nix_string(),
+        }
+    }
+
+    pub fn from_archive(mut self, archive: &str) -> Self {
+        self.archive = Some(archive.to_string());
+        self
+    }
+
     pub fn to_pretty_string(&self) -> String {
+        let pkg_string = self.to_pretty_string_without_archive();
+        match &self.archive {
+            Some(archive) => format!("{} (nixpkgs {})", pkg_string, short_archive(archive)),
+            None => pkg_string,
+        }
+    }
+
+    fn to_pretty_string_without_archive(&self) -> String {
         match &self.overrides {
             Some(overrides) => {
                 let override_string = overrides
@@ -71,6 +113,60 @@ impl Pkg {
     }
 }
 
+/// Archives that packages are pinned to, other than the archive of the whole setup phase, in the
+/// order they are first used
+pub fn get_pkg_archives(pkgs: &[Pkg], setup_archive: Option<&str>) -> Vec<String> {
+    let mut archives: Vec<String> = Vec::new();
+    for archive in pkgs.iter().filter_map(|pkg| pkg.archive.as_ref()) {
+        if Some(archive.as_str()) != setup_archive && !archives.contains(archive) {
+            archives.push(archive.clone());
+        }
+    }
+    archives
+}
+
+/// NAR hash of an archive, from the first package pinned to it that has one
+pub fn get_archive_hash<'a>(pkgs: &'a [Pkg], archive: &str) -> Option<&'a str> {
+    pkgs.iter()
+        .filter(|pkg| pkg.archive.as_deref() == Some(archive))
+        .find_map(|pkg| pkg.archive_hash.as_deref())
+}
+
+/// Nix expression importing nixpkgs from an archive, or from the channel of the environment
+///
+/// The archive is only verified when its NAR hash is known.
+pub fn nixpkgs_import(archive: Option<&str>, hash: Option<&str>) -> String {
+    match (archive, hash) {
+        (Some(archive), Some(hash)) => format!(
+            "import (fetchTarball {{ url = \"https://github.com/NixOS/nixpkgs/archive/{}.tar.gz\"; sha256 = \"{}\"; }})",
+            archive, hash
+        ),
+        (Some(archive), None) => format!(
+            "import (fetchTarball \"https://github.com/NixOS/nixpkgs/archive/{}.tar.gz\")",
+            archive
+        ),
+        (None, _) => "import <nixpkgs>".to_string(),
+    }
+}
+
+/// Name of the nixpkgs import for the archive at `index` of `get_pkg_archives`
+pub fn archive_import_name(index: usize) -> String {
+    format!("archive{}", index)
+}
+
+/// Whether a string is a full commit hash, which is how archives are named
+pub fn is_revision(s: &str) -> bool {
+    s.len() == 40 && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// First 7 characters of a commit hash, like `git log --oneline`
+fn short_archive(archive: &str) -> &str {
+    match is_revision(archive) {
+        true => &archive[..7],
+        false => archive,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -89,4 +185,53 @@ mod tests {
             "(cowsay.override { hello = hello_1.1; })".to_string()
         );
     }
+
+    #[test]
+    fn test_pkg_with_archive_to_string() {
+        let archive = "41cc1d5d9584103be4108c1815c350e07c807036";
+        let pkg = Pkg::new("nodejs-10_x").from_archive(archive);
+        assert_eq!(pkg.to_pretty_string(), "nodejs-10_x (nixpkgs 41cc1d5)");
+        assert_eq!(
+            pkg.to_nix_string_with_archives(&[archive.to_string()]),
+            "archive0.nodejs-10_x"
+        );
+        assert_eq!(pkg.to_nix_string_with_archives(&[]), "nodejs-10_x");
+    }
+
+    #[test]
+    fn test_get_pkg_archives() {
+        let pkgs = vec![
+            Pkg::new("nodejs-10_x").from_archive("a"),
+            Pkg::new("postgresql"),
+            Pkg::new("python27").from_archive("b"),
+            Pkg::new("yarn").from_archive("a"),
+            Pkg::new("redis").from_archive("setup"),
+        ];
+        assert_eq!(
+            get_pkg_archives(&pkgs, Some("setup")),
+            vec!["a".to_string(), "b".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_nixpkgs_import() {
+        assert_eq!(nixpkgs_import(None, None), "import <nixpkgs>");
+        assert_eq!(
+            nixpkgs_import(Some("a0b1c2"), None),
+            "import (fetchTarball \"https://github.com/NixOS/nixpkgs/archive/a0b1c2.tar.gz\")"
+        );
+        assert_eq!(
+            nixpkgs_import(Some("a0b1c2"), Some("sha256-AAAA")),
+            "import (fetchTarball { url = \"https://github.com/NixOS/nixpkgs/archive/a0b1c2.tar.gz\"; sha256 = \"sha256-AAAA\"; })"
+        );
+    }
+
+    #[test]
+    fn test_get_archive_hash() {
+        let mut hashed = Pkg::new("yarn").from_archive("a");
+        hashed.archive_hash = Some("sha256-AAAA".to_string());
+        let pkgs = vec![Pkg::new("nodejs-10_x").from_archive("a"), hashed];
+        assert_eq!(get_archive_hash(&pkgs, "a"), Some("sha256-AAAA"));
+        assert_eq!(get_archive_hash(&pkgs, "b"), None);
+    }
 }
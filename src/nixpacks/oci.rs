@@ -0,0 +1,376 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{de::DeserializeOwned, Deserialize};
+
+pub const OCI_LAYOUT_FILE_NAME: &str = "oci-layout";
+pub const OCI_INDEX_FILE_NAME: &str = "index.json";
+
+const OCI_IMAGE_INDEX_MEDIA_TYPE: &str = "application/vnd.oci.image.index.v1+json";
+const DOCKER_MANIFEST_LIST_MEDIA_TYPE: &str =
+    "application/vnd.docker.distribution.manifest.list.v2+json";
+
+const TAR_BLOCK_SIZE: usize = 512;
+
+#[derive(Deserialize, Debug)]
+struct OciLayout {
+    #[serde(rename = "imageLayoutVersion")]
+    image_layout_version: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct Descriptor {
+    #[serde(rename = "mediaType")]
+    media_type: Option<String>,
+    digest: String,
+    size: u64,
+}
+
+#[derive(Deserialize, Debug)]
+struct Index {
+    manifests: Vec<Descriptor>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Manifest {
+    config: Descriptor,
+    layers: Vec<Descriptor>,
+}
+
+/// Summary of a valid OCI image layout archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OciArchive {
+    pub manifests: usize,
+    pub layers: usize,
+}
+
+/// Check that a tarball is an OCI image layout where every blob referenced from `index.json`
+/// (manifests, configs, and layers) is present with the expected size
+///
+/// Only the headers of the tar entries are read, so layers are never loaded into memory.
+pub fn verify_oci_archive<P: AsRef<Path>>(path: P) -> Result<OciArchive> {
+    let path = path.as_ref();
+    let file =
+        File::open(path).with_context(|| format!("Reading OCI archive {}", path.display()))?;
+    let mut tar = TarArchive::open(BufReader::new(file))
+        .with_context(|| format!("Reading {}", path.display()))?;
+    verify_oci_layout(&mut tar)
+}
+
+fn verify_oci_layout<R: Read + Seek>(tar: &mut TarArchive<R>) -> Result<OciArchive> {
+    let layout: OciLayout = read_json(tar, OCI_LAYOUT_FILE_NAME)?;
+    if layout.image_layout_version != "1.0.0" {
+        bail!(
+            "Unsupported OCI image layout version {}",
+            layout.image_layout_version
+        );
+    }
+
+    let index: Index = read_json(tar, OCI_INDEX_FILE_NAME)?;
+    if index.manifests.is_empty() {
+        bail!("{} does not reference any manifests", OCI_INDEX_FILE_NAME);
+    }
+
+    let mut archive = OciArchive {
+        manifests: 0,
+        layers: 0,
+    };
+
+    let mut descriptors = index.manifests;
+    while let Some(descriptor) = descriptors.pop() {
+        let blob = read_blob(tar, &descriptor)?;
+        match descriptor.media_type.as_deref() {
+            // Multi-platform images nest an index for every platform
+            Some(OCI_IMAGE_INDEX_MEDIA_TYPE) | Some(DOCKER_MANIFEST_LIST_MEDIA_TYPE) => {
+                let index: Index = serde_json::from_slice(&blob)
+                    .with_context(|| format!("Parsing image index {}", descriptor.digest))?;
+                descriptors.extend(index.manifests);
+            }
+            _ => {
+                let manifest: Manifest = serde_json::from_slice(&blob)
+                    .with_context(|| format!("Parsing image manifest {}", descriptor.digest))?;
+                check_blob(tar, &manifest.config)?;
+                for layer in &manifest.layers {
+                    check_blob(tar, layer)?;
+                }
+
+                archive.manifests += 1;
+                archive.layers += manifest.layers.len();
+            }
+        }
+    }
+
+    Ok(archive)
+}
+
+fn read_json<T: DeserializeOwned, R: Read + Seek>(
+    tar: &mut TarArchive<R>,
+    name: &str,
+) -> Result<T> {
+    let contents = tar
+        .read(name)?
+        .with_context(|| format!("OCI archive is missing {}", name))?;
+    serde_json::from_slice(&contents).with_context(|| format!("Parsing {}", name))
+}
+
+fn blob_path(descriptor: &Descriptor) -> Result<String> {
+    let (algorithm, hash) = descriptor
+        .digest
+        .split_once(':')
+        .with_context(|| format!("Invalid digest {}", descriptor.digest))?;
+    Ok(format!("blobs/{}/{}", algorithm, hash))
+}
+
+/// Check that a blob is in the archive with the size of its descriptor, without reading it
+fn check_blob<R>(tar: &TarArchive<R>, descriptor: &Descriptor) -> Result<()> {
+    let entry = tar
+        .entries
+        .get(&blob_path(descriptor)?)
+        .with_context(|| format!("OCI archive is missing blob {}", descriptor.digest))?;
+    if entry.size != descriptor.size {
+        bail!(
+            "Blob {} is {} bytes, expected {}",
+            descriptor.digest,
+            entry.size,
+            descriptor.size
+        );
+    }
+    Ok(())
+}
+
+/// Contents of a manifest or index blob, after checking its size
+fn read_blob<R: Read + Seek>(tar: &mut TarArchive<R>, descriptor: &Descriptor) -> Result<Vec<u8>> {
+    check_blob(tar, descriptor)?;
+    let blob = tar.read(&blob_path(descriptor)?)?.unwrap_or_default();
+    Ok(blob)
+}
+
+/// Position and size of the data of a tar entry
+#[derive(Debug, Clone, Copy)]
+struct TarEntry {
+    offset: u64,
+    size: u64,
+}
+
+/// Regular files in a tar archive, by path, read on demand
+struct TarArchive<R> {
+    reader: R,
+    entries: HashMap<String, TarEntry>,
+}
+
+impl<R: Read + Seek> TarArchive<R> {
+    /// Index the entries of an archive by walking its headers and seeking past the data
+    fn open(mut reader: R) -> Result<Self> {
+        let len = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(0))?;
+
+        let mut entries = HashMap::new();
+        let mut long_name: Option<String> = None;
+        let mut offset = 0;
+        let mut header = [0; TAR_BLOCK_SIZE];
+
+        while offset + TAR_BLOCK_SIZE as u64 <= len {
+            reader.read_exact(&mut header)?;
+            // The archive ends with empty blocks
+            if header.iter().all(|b| *b == 0) {
+                break;
+            }
+
+            let size = parse_octal(&header[124..136]).context("Invalid tar entry size")?;
+            let start = offset + TAR_BLOCK_SIZE as u64;
+            if start + size > len {
+                bail!("Unexpected end of tar archive");
+            }
+
+            let name = match long_name.take() {
+                Some(name) => name,
+                None => {
+                    let prefix = parse_string(&header[345..500]);
+                    let name = parse_string(&header[0..100]);
+                    match prefix.is_empty() {
+                        true => name,
+                        false => format!("{}/{}", prefix, name),
+                    }
+                }
+            };
+
+            match header[156] {
+                // GNU extension for paths longer than 100 bytes, naming the next entry
+                b'L' => {
+                    let mut data = vec![0; size as usize];
+                    reader.read_exact(&mut data)?;
+                    long_name = Some(parse_string(&data));
+                }
+                b'0' | 0 => {
+                    let name = name.trim_start_matches("./").to_string();
+                    entries.insert(
+                        name,
+                        TarEntry {
+                            offset: start,
+                            size,
+                        },
+                    );
+                }
+                _ => {}
+            }
+
+            offset = start + size.div_ceil(TAR_BLOCK_SIZE as u64) * TAR_BLOCK_SIZE as u64;
+            reader.seek(SeekFrom::Start(offset))?;
+        }
+
+        Ok(TarArchive { reader, entries })
+    }
+
+    /// Contents of a file, or `None` if the archive doesn't have it
+    fn read(&mut self, name: &str) -> Result<Option<Vec<u8>>> {
+        let entry = match self.entries.get(name) {
+            Some(entry) => *entry,
+            None => return Ok(None),
+        };
+
+        let mut contents = vec![0; entry.size as usize];
+        self.reader.seek(SeekFrom::Start(entry.offset))?;
+        self.reader
+            .read_exact(&mut contents)
+            .with_context(|| format!("Reading {}", name))?;
+        Ok(Some(contents))
+    }
+}
+
+fn parse_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).to_string()
+}
+
+fn parse_octal(bytes: &[u8]) -> Result<u64> {
+    let value = parse_string(bytes);
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(0);
+    }
+    Ok(u64::from_str_radix(value, 8)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn tar_entry(name: &str, data: &[u8]) -> Vec<u8> {
+        let mut header = vec![0; TAR_BLOCK_SIZE];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        let size = format!("{:011o}", data.len());
+        header[124..135].copy_from_slice(size.as_bytes());
+        header[156] = b'0';
+
+        let mut entry = header;
+        entry.extend_from_slice(data);
+        entry.resize(
+            TAR_BLOCK_SIZE + data.len().div_ceil(TAR_BLOCK_SIZE) * TAR_BLOCK_SIZE,
+            0,
+        );
+        entry
+    }
+
+    fn tar(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut tar = Vec::new();
+        for (name, data) in entries {
+            tar.extend(tar_entry(name, data));
+        }
+        tar.extend(vec![0; TAR_BLOCK_SIZE * 2]);
+        tar
+    }
+
+    fn manifest(layer: &[u8]) -> String {
+        format!(
+            r#"{{"schemaVersion":2,"config":{{"mediaType":"application/vnd.oci.image.config.v1+json","digest":"sha256:c0ffee","size":2}},"layers":[{{"mediaType":"application/vnd.oci.image.layer.v1.tar+gzip","digest":"sha256:1a7e2","size":{}}}]}}"#,
+            layer.len()
+        )
+    }
+
+    fn index(manifest: &str) -> String {
+        format!(
+            r#"{{"schemaVersion":2,"manifests":[{{"mediaType":"application/vnd.oci.image.manifest.v1+json","digest":"sha256:3a41f","size":{}}}]}}"#,
+            manifest.len()
+        )
+    }
+
+    #[test]
+    fn test_verify_oci_layout() -> Result<()> {
+        let manifest = manifest(b"layer");
+        let index = index(&manifest);
+        let contents = tar(&[
+            ("oci-layout", br#"{"imageLayoutVersion":"1.0.0"}"#),
+            ("./index.json", index.as_bytes()),
+            ("blobs/sha256/3a41f", manifest.as_bytes()),
+            ("blobs/sha256/c0ffee", b"{}"),
+            ("blobs/sha256/1a7e2", b"layer"),
+        ]);
+
+        let mut tar = TarArchive::open(Cursor::new(contents))?;
+        assert_eq!(
+            verify_oci_layout(&mut tar)?,
+            OciArchive {
+                manifests: 1,
+                layers: 1
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_missing_blob() -> Result<()> {
+        let manifest = manifest(b"layer");
+        let index = index(&manifest);
+        let contents = tar(&[
+            ("oci-layout", br#"{"imageLayoutVersion":"1.0.0"}"#),
+            ("index.json", index.as_bytes()),
+            ("blobs/sha256/3a41f", manifest.as_bytes()),
+            ("blobs/sha256/c0ffee", b"{}"),
+        ]);
+
+        let mut tar = TarArchive::open(Cursor::new(contents))?;
+        let error = verify_oci_layout(&mut tar).unwrap_err();
+        assert!(error.to_string().contains("sha256:1a7e2"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_not_oci_layout() -> Result<()> {
+        let contents = tar(&[("manifest.json", b"[]")]);
+        let mut tar = TarArchive::open(Cursor::new(contents))?;
+        assert!(verify_oci_layout(&mut tar).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_oci_archive_file() -> Result<()> {
+        let manifest = manifest(b"layer");
+        let index = index(&manifest);
+        let contents = tar(&[
+            ("oci-layout", br#"{"imageLayoutVersion":"1.0.0"}"#),
+            ("index.json", index.as_bytes()),
+            ("blobs/sha256/3a41f", manifest.as_bytes()),
+            ("blobs/sha256/c0ffee", b"{}"),
+            ("blobs/sha256/1a7e2", b"layer"),
+        ]);
+
+        let dir = tempdir::TempDir::new("nixpacks-oci")?;
+        let path = dir.path().join("image.tar");
+        std::fs::write(&path, contents)?;
+        assert_eq!(verify_oci_archive(&path)?.layers, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncated_tar() {
+        let mut contents = tar_entry("blobs/sha256/1a7e2", &[1; 1024]);
+        contents.truncate(TAR_BLOCK_SIZE * 2);
+        assert!(TarArchive::open(Cursor::new(contents)).is_err());
+    }
+}
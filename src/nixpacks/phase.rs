@@ -1,3 +1,4 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 use super::{
@@ -5,12 +6,21 @@ use super::{
     nix::Pkg,
 };
 
+pub const SETUP_PHASE_NAME: &str = "setup";
+pub const INSTALL_PHASE_NAME: &str = "install";
+pub const BUILD_PHASE_NAME: &str = "build";
+pub const START_PHASE_NAME: &str = "start";
+
 #[serde_with::skip_serializing_none]
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
 pub struct SetupPhase {
     pub pkgs: Vec<Pkg>,
     pub archive: Option<String>,
 
+    /// NAR hash of the nixpkgs archive (e.g. `sha256-...`), used to lock it in a flake
+    #[serde(rename = "archiveHash")]
+    pub archive_hash: Option<String>,
+
     #[serde(rename = "onlyIncludeFiles")]
     pub only_include_files: Option<Vec<String>>,
 
@@ -23,6 +33,7 @@ impl SetupPhase {
         Self {
             pkgs,
             archive: None,
+            archive_hash: None,
             only_include_files: None,
             base_image: DEFAULT_BASE_IMAGE.to_string(),
         }
@@ -51,6 +62,7 @@ impl Default for SetupPhase {
         Self {
             pkgs: Default::default(),
             archive: Default::default(),
+            archive_hash: Default::default(),
             only_include_files: Default::default(),
             base_image: DEFAULT_BASE_IMAGE.to_string(),
         }
@@ -58,22 +70,31 @@ impl Default for SetupPhase {
 }
 
 #[serde_with::skip_serializing_none]
-#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Default, Clone, Debug)]
 pub struct InstallPhase {
     pub cmd: Option<String>,
 
+    #[serde(rename = "dependsOn")]
+    pub depends_on: Option<Vec<String>>,
+
     #[serde(rename = "onlyIncludeFiles")]
     pub only_include_files: Option<Vec<String>>,
 
     pub paths: Option<Vec<String>>,
+
+    /// Directories that are cached between builds (e.g. package manager caches)
+    #[serde(rename = "cacheDirectories")]
+    pub cache_directories: Option<Vec<String>>,
 }
 
 impl InstallPhase {
     pub fn new(cmd: String) -> Self {
         Self {
             cmd: Some(cmd),
+            depends_on: None,
             only_include_files: None,
             paths: None,
+            cache_directories: None,
         }
     }
 
@@ -94,22 +115,40 @@ impl InstallPhase {
             self.paths = Some(vec![path]);
         }
     }
+
+    pub fn add_cache_directory(&mut self, dir: String) {
+        if let Some(mut dirs) = self.cache_directories.clone() {
+            dirs.push(dir);
+            self.cache_directories = Some(dirs);
+        } else {
+            self.cache_directories = Some(vec![dir]);
+        }
+    }
 }
 
 #[serde_with::skip_serializing_none]
-#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Default, Clone, Debug)]
 pub struct BuildPhase {
     pub cmd: Option<String>,
 
+    #[serde(rename = "dependsOn")]
+    pub depends_on: Option<Vec<String>>,
+
     #[serde(rename = "onlyIncludeFiles")]
     pub only_include_files: Option<Vec<String>>,
+
+    /// Directories that are cached between builds (e.g. package manager caches)
+    #[serde(rename = "cacheDirectories")]
+    pub cache_directories: Option<Vec<String>>,
 }
 
 impl BuildPhase {
     pub fn new(cmd: String) -> Self {
         Self {
             cmd: Some(cmd),
+            depends_on: None,
             only_include_files: None,
+            cache_directories: None,
         }
     }
 
@@ -121,10 +160,19 @@ impl BuildPhase {
             self.only_include_files = Some(vec![file]);
         }
     }
+
+    pub fn add_cache_directory(&mut self, dir: String) {
+        if let Some(mut dirs) = self.cache_directories.clone() {
+            dirs.push(dir);
+            self.cache_directories = Some(dirs);
+        } else {
+            self.cache_directories = Some(vec![dir]);
+        }
+    }
 }
 
 #[serde_with::skip_serializing_none]
-#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Default, Clone, Debug)]
 pub struct StartPhase {
     pub cmd: Option<String>,
 
@@ -165,3 +213,101 @@ impl StartPhase {
         }
     }
 }
+
+/// A named step that runs between setting up the environment and starting the app
+///
+/// The install and build phases are phases named `install` and `build`. Phases run after the
+/// phases listed in `dependsOn`, otherwise in the order they are declared.
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, JsonSchema, Default, Clone, Debug, PartialEq)]
+pub struct Phase {
+    pub name: String,
+    pub cmd: Option<String>,
+
+    #[serde(rename = "dependsOn")]
+    pub depends_on: Option<Vec<String>>,
+
+    #[serde(rename = "onlyIncludeFiles")]
+    pub only_include_files: Option<Vec<String>>,
+
+    pub paths: Option<Vec<String>>,
+
+    /// Directories that are cached between builds (e.g. package manager caches)
+    #[serde(rename = "cacheDirectories")]
+    pub cache_directories: Option<Vec<String>>,
+}
+
+impl Phase {
+    pub fn new(name: &str, cmd: String) -> Self {
+        Self {
+            name: name.to_string(),
+            cmd: Some(cmd),
+            ..Default::default()
+        }
+    }
+
+    pub fn depends_on_phase(&mut self, name: &str) {
+        if let Some(mut depends_on) = self.depends_on.clone() {
+            depends_on.push(name.to_string());
+            self.depends_on = Some(depends_on);
+        } else {
+            self.depends_on = Some(vec![name.to_string()]);
+        }
+    }
+
+    pub fn add_file_dependency(&mut self, file: String) {
+        if let Some(mut files) = self.only_include_files.clone() {
+            files.push(file);
+            self.only_include_files = Some(files);
+        } else {
+            self.only_include_files = Some(vec![file]);
+        }
+    }
+
+    pub fn add_path(&mut self, path: String) {
+        if let Some(mut paths) = self.paths.clone() {
+            paths.push(path);
+            self.paths = Some(paths);
+        } else {
+            self.paths = Some(vec![path]);
+        }
+    }
+
+    pub fn add_cache_directory(&mut self, dir: String) {
+        if let Some(mut dirs) = self.cache_directories.clone() {
+            dirs.push(dir);
+            self.cache_directories = Some(dirs);
+        } else {
+            self.cache_directories = Some(vec![dir]);
+        }
+    }
+}
+
+impl From<InstallPhase> for Phase {
+    fn from(install_phase: InstallPhase) -> Self {
+        Self {
+            name: INSTALL_PHASE_NAME.to_string(),
+            cmd: install_phase.cmd,
+            depends_on: install_phase.depends_on,
+            only_include_files: install_phase.only_include_files,
+            paths: install_phase.paths,
+            cache_directories: install_phase.cache_directories,
+        }
+    }
+}
+
+impl From<BuildPhase> for Phase {
+    fn from(build_phase: BuildPhase) -> Self {
+        Self {
+            name: BUILD_PHASE_NAME.to_string(),
+            cmd: build_phase.cmd,
+            // The build phase runs after the install phase unless told otherwise
+            depends_on: build_phase
+                .depends_on
+                .or_else(|| Some(vec![INSTALL_PHASE_NAME.to_string()])),
+            only_include_files: build_phase.only_include_files,
+            paths: None,
+            cache_directories: build_phase.cache_directories,
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::BTreeSet;
+
+use super::nix::Pkg;
+
+/// Nix package attributes in the pinned nixpkgs archive, one per line
+const PKG_INDEX: &str = include_str!("pkgs.txt");
+
+/// Most similar packages suggested for an unknown package name
+const MAX_SUGGESTIONS: usize = 3;
+
+/// Package attributes that are known to exist in the pinned nixpkgs archive
+pub struct PkgIndex {
+    attrs: BTreeSet<&'static str>,
+}
+
+impl PkgIndex {
+    pub fn bundled() -> Self {
+        let attrs = PKG_INDEX
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .collect();
+        PkgIndex { attrs }
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.attrs.contains(name)
+    }
+
+    /// Known attributes that are only a typo away from a name, most similar first
+    pub fn suggestions(&self, name: &str) -> Vec<&'static str> {
+        let name = name.to_lowercase();
+        // Short names are too similar to each other to guess what was meant
+        let max_distance = name.chars().count().saturating_sub(1) / 3;
+
+        let mut suggestions = self
+            .attrs
+            .iter()
+            .map(|attr| (edit_distance(&name, &attr.to_lowercase()), *attr))
+            .filter(|(distance, _)| *distance <= max_distance)
+            .collect::<Vec<_>>();
+        suggestions.sort();
+
+        suggestions
+            .into_iter()
+            .take(MAX_SUGGESTIONS)
+            .map(|(_, attr)| attr)
+            .collect()
+    }
+
+    /// Check that a package exists, returning a warning with suggestions when the name looks
+    /// like a typo of a known package, or a plain warning when it isn't in the index
+    ///
+    /// The bundled index may not list every attribute of the archive, so unknown names are never
+    /// an error. Packages from overlays and Nix expressions (e.g.
+    /// `(rust-bin.fromRustupToolchainFile ./rust-toolchain.toml)`) can't be checked and are
+    /// always valid.
+    pub fn validate(&self, pkg: &Pkg) -> Option<String> {
+        if pkg.overlay.is_some() || !is_attr_path(&pkg.name) || self.contains(&pkg.name) {
+            return None;
+        }
+
+        let mut suggestions = self
+            .suggestions(&pkg.name)
+            .iter()
+            .map(|attr| format!("`{}`", attr))
+            .collect::<Vec<_>>();
+        match suggestions.pop() {
+            Some(last) => {
+                let suggestions = match suggestions.is_empty() {
+                    true => last,
+                    false => format!("{}, or {}", suggestions.join(", "), last),
+                };
+                Some(format!(
+                    "Nix package `{}` is not in the package index. Did you mean {}?",
+                    pkg.name, suggestions
+                ))
+            }
+            None => Some(format!(
+                "Nix package `{}` is not in the package index and may fail to install",
+                pkg.name
+            )),
+        }
+    }
+}
+
+fn is_attr_path(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "._-+'".contains(c))
+}
+
+/// Number of insertions, deletions, substitutions, and swaps of adjacent characters needed to
+/// turn one string into the other
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a = a.chars().collect::<Vec<_>>();
+    let b = b.chars().collect::<Vec<_>>();
+
+    // distances[i][j] is the distance between the first i chars of a and the first j chars of b
+    let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in distances.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, distance) in distances[0].iter_mut().enumerate() {
+        *distance = j;
+    }
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            let mut distance = (distances[i - 1][j] + 1)
+                .min(distances[i][j - 1] + 1)
+                .min(distances[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(distances[i - 2][j - 2] + 1);
+            }
+            distances[i][j] = distance;
+        }
+    }
+
+    distances[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("nodejs", "nodejs"), 0);
+        assert_eq!(edit_distance("nodjes", "nodejs"), 1);
+        assert_eq!(edit_distance("cowsy", "cowsay"), 1);
+        assert_eq!(edit_distance("", "go"), 2);
+    }
+
+    #[test]
+    fn test_validate_known_pkgs() {
+        let index = PkgIndex::bundled();
+        assert_eq!(index.validate(&Pkg::new("nodejs")), None);
+        assert_eq!(index.validate(&Pkg::new("nodePackages.pnpm")), None);
+        assert_eq!(
+            index.validate(
+                &Pkg::new("rust-bin.stable.latest.default").from_overlay("https://example.com")
+            ),
+            None
+        );
+        assert_eq!(
+            index.validate(&Pkg::new(
+                "(rust-bin.fromRustupToolchainFile ./rust-toolchain.toml)"
+            )),
+            None
+        );
+    }
+
+    #[test]
+    fn test_validate_typo() {
+        let index = PkgIndex::bundled();
+        assert_eq!(
+            index.validate(&Pkg::new("nodjes")),
+            Some(
+                "Nix package `nodjes` is not in the package index. Did you mean `nodejs`?"
+                    .to_string()
+            )
+        );
+        assert!(index
+            .validate(&Pkg::new("nodePackages.pnmp"))
+            .unwrap()
+            .contains("Did you mean"));
+        assert!(index
+            .validate(&Pkg::new("Cowsay"))
+            .unwrap()
+            .contains("Did you mean"));
+    }
+
+    #[test]
+    fn test_validate_unknown_pkg() {
+        let index = PkgIndex::bundled();
+        // Not in the index, but not close to a known package either
+        assert_eq!(
+            index.validate(&Pkg::new("jd")),
+            Some(
+                "Nix package `jd` is not in the package index and may fail to install".to_string()
+            )
+        );
+        assert!(index.validate(&Pkg::new("libpostal")).is_some());
+        // Real attributes that are missing from the index are only a warning, even when they
+        // are close to a listed one
+        for name in [
+            "python39Packages.numpy",
+            "python311",
+            "gcc10",
+            "openssl_3",
+            "libpng12",
+            "nodejs-17_x",
+        ] {
+            assert!(index.validate(&Pkg::new(name)).is_some());
+        }
+    }
+}
@@ -1,24 +1,330 @@
+use std::{fs, path::Path, str::FromStr};
+
+use anyhow::{bail, Context, Result};
 use indoc::formatdoc;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 use super::{
-    environment::EnvironmentVariables,
-    phase::{BuildPhase, InstallPhase, SetupPhase, StartPhase},
+    diff::PlanDiff,
+    environment::{EnvironmentVariables, VariableScope, VariableScopes},
+    interpolate::interpolate,
+    phase::{
+        BuildPhase, InstallPhase, Phase, SetupPhase, StartPhase, BUILD_PHASE_NAME,
+        INSTALL_PHASE_NAME, SETUP_PHASE_NAME, START_PHASE_NAME,
+    },
+    schema,
 };
 
 #[serde_with::skip_serializing_none]
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone)]
 pub struct BuildPlan {
+    /// Version of nixpacks that created the plan
     pub version: Option<String>,
+
+    /// Version of the plan format, used to upgrade plans created by older versions of nixpacks
+    #[serde(rename = "schemaVersion")]
+    pub schema_version: Option<u32>,
+
     pub setup: Option<SetupPhase>,
     pub install: Option<InstallPhase>,
     pub build: Option<BuildPhase>,
+
+    /// Additional named phases
+    pub phases: Option<Vec<Phase>>,
+
     pub start: Option<StartPhase>,
     pub variables: Option<EnvironmentVariables>,
+
+    /// When each variable is available. Variables that aren't listed are available while building
+    /// and running the app.
+    #[serde(rename = "variableScopes")]
+    pub variable_scopes: Option<VariableScopes>,
+
+    /// Names of environment variables that are only available while running the phases between
+    /// setup and start. Their values are never saved in the plan or the image.
+    pub secrets: Option<Vec<String>>,
+}
+
+/// File format of a serialized build plan
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlanFormat {
+    Json,
+    Yaml,
+    Toml,
 }
 
+impl PlanFormat {
+    /// Format for a file extension, if it is one of the supported formats
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<PlanFormat> {
+        let extension = path.as_ref().extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "json" => Some(PlanFormat::Json),
+            "yaml" | "yml" => Some(PlanFormat::Yaml),
+            "toml" => Some(PlanFormat::Toml),
+            _ => None,
+        }
+    }
+
+    /// Parse a plan, or a partial plan, in this format
+    pub fn parse(&self, contents: &str) -> Result<Value> {
+        Ok(match self {
+            PlanFormat::Json => {
+                serde_json::from_str(contents).context("Parsing build plan JSON")?
+            }
+            PlanFormat::Yaml => {
+                serde_yaml::from_str(contents).context("Parsing build plan YAML")?
+            }
+            PlanFormat::Toml => toml::from_str(contents).context("Parsing build plan TOML")?,
+        })
+    }
+}
+
+impl FromStr for PlanFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        match format {
+            "json" => Ok(PlanFormat::Json),
+            "yaml" => Ok(PlanFormat::Yaml),
+            "toml" => Ok(PlanFormat::Toml),
+            _ => bail!(
+                "Unknown plan format `{}`. Expected one of json, yaml, toml",
+                format
+            ),
+        }
+    }
+}
+
+/// List entry in a partial plan that is replaced with the existing items
+const MERGE_EXISTING_ITEMS: &str = "...";
+
 impl BuildPlan {
-    pub fn get_build_string(&self) -> String {
+    /// Merge a partial plan on top of this one
+    ///
+    /// Only the fields set in the partial plan are overridden. Lists are replaced, unless they
+    /// contain a `"..."` entry, which is expanded to the items already in the plan. For example
+    /// `{ "setup": { "pkgs": ["...", { "name": "cowsay" }] } }` adds a package.
+    pub fn merge(&self, partial: &Value) -> Result<BuildPlan> {
+        let base = serde_json::to_value(self).context("Serializing build plan")?;
+        let merged = merge_values(base, partial);
+        // Partial plans are always written for the current format
+        schema::deserialize_plan(merged, true).context("Merging partial build plan")
+    }
+
+    /// Load a plan from JSON, upgrading plans created by older versions of nixpacks
+    pub fn from_json(json: &str) -> Result<BuildPlan> {
+        BuildPlan::from_format(json, PlanFormat::Json)
+    }
+
+    /// Load a plan serialized in any of the plan formats
+    pub fn from_format(contents: &str, format: PlanFormat) -> Result<BuildPlan> {
+        let value = format.parse(contents)?;
+        let deny_unknown_fields = schema::is_current(&value);
+        let value = schema::migrate(value)?;
+        schema::deserialize_plan(value, deny_unknown_fields)
+    }
+
+    /// Load a plan file, using the format of its extension and falling back to JSON
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<BuildPlan> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Reading build plan {}", path.display()))?;
+        let format = PlanFormat::from_path(path).unwrap_or(PlanFormat::Json);
+        BuildPlan::from_format(&contents, format)
+    }
+
+    /// Load a partial plan file for `merge`, using the format of its extension and falling back
+    /// to JSON
+    pub fn partial_from_file<P: AsRef<Path>>(path: P) -> Result<Value> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Reading partial build plan {}", path.display()))?;
+        PlanFormat::from_path(path)
+            .unwrap_or(PlanFormat::Json)
+            .parse(&contents)
+    }
+
+    /// Serialize the plan, with the same field names in every format
+    pub fn to_format(&self, format: PlanFormat) -> Result<String> {
+        match format {
+            PlanFormat::Json => {
+                serde_json::to_string_pretty(self).context("Serializing build plan to JSON")
+            }
+            PlanFormat::Yaml => {
+                serde_yaml::to_string(self).context("Serializing build plan to YAML")
+            }
+            PlanFormat::Toml => {
+                // TOML needs tables after values, which only `toml::Value` reorders automatically
+                let value =
+                    toml::Value::try_from(self).context("Serializing build plan to TOML")?;
+                toml::to_string_pretty(&value).context("Serializing build plan to TOML")
+            }
+        }
+    }
+
+    /// Changes to packages, commands, variables, and the run image from this plan to `new`
+    pub fn diff(&self, new: &BuildPlan) -> PlanDiff {
+        PlanDiff::new(self, new)
+    }
+
+    pub fn get_variable_scope(&self, name: &str) -> VariableScope {
+        self.variable_scopes
+            .as_ref()
+            .and_then(|scopes| scopes.get(name).copied())
+            .unwrap_or_default()
+    }
+
+    /// Names of the variables in a scope, sorted by name
+    pub fn get_variable_names(&self, filter: fn(&VariableScope) -> bool) -> Vec<String> {
+        let mut names = self
+            .variables
+            .clone()
+            .unwrap_or_default()
+            .into_keys()
+            .filter(|name| filter(&self.get_variable_scope(name)))
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    /// Replace `${NAME}` references in the commands, paths, and files of every phase with the
+    /// plan variables, returning the names of unset variables whose references were left for the
+    /// shell
+    ///
+    /// With `strict`, a reference to an unset variable is an error instead. Unresolved references
+    /// in the start command are always left for the shell, since variables like `PORT` are often
+    /// only set when the container runs.
+    pub fn interpolate_variables(&mut self, strict: bool) -> Result<Vec<String>> {
+        let variables = self.variables.clone().unwrap_or_default();
+
+        // Every value that may have references, with the field it is reported by
+        let mut fields: Vec<(String, &mut String)> = Vec::new();
+        if let Some(setup) = &mut self.setup {
+            add_list_fields(
+                &mut fields,
+                "setup.onlyIncludeFiles",
+                &mut setup.only_include_files,
+            );
+        }
+        if let Some(install) = &mut self.install {
+            add_field(&mut fields, "install.cmd", &mut install.cmd);
+            add_list_fields(&mut fields, "install.paths", &mut install.paths);
+            add_list_fields(
+                &mut fields,
+                "install.onlyIncludeFiles",
+                &mut install.only_include_files,
+            );
+        }
+        if let Some(build) = &mut self.build {
+            add_field(&mut fields, "build.cmd", &mut build.cmd);
+            add_list_fields(
+                &mut fields,
+                "build.onlyIncludeFiles",
+                &mut build.only_include_files,
+            );
+        }
+        for phase in self.phases.iter_mut().flatten() {
+            add_field(&mut fields, &format!("{}.cmd", phase.name), &mut phase.cmd);
+            add_list_fields(
+                &mut fields,
+                &format!("{}.paths", phase.name),
+                &mut phase.paths,
+            );
+            add_list_fields(
+                &mut fields,
+                &format!("{}.onlyIncludeFiles", phase.name),
+                &mut phase.only_include_files,
+            );
+        }
+        let mut start_cmd = None;
+        if let Some(start) = &mut self.start {
+            start_cmd = start.cmd.as_mut();
+            add_list_fields(
+                &mut fields,
+                "start.onlyIncludeFiles",
+                &mut start.only_include_files,
+            );
+        }
+
+        let mut unresolved = Vec::new();
+        for (field, value) in fields {
+            let unresolved = match strict {
+                true => None,
+                false => Some(&mut unresolved),
+            };
+            *value = interpolate(value, &variables, unresolved)
+                .with_context(|| format!("Interpolating `{}`", field))?;
+        }
+        if let Some(cmd) = start_cmd {
+            *cmd = interpolate(cmd, &variables, Some(&mut Vec::new()))
+                .context("Interpolating `start.cmd`")?;
+        }
+
+        Ok(unresolved)
+    }
+
+    /// All phases between setup and start, in the order they should run
+    ///
+    /// # Errors
+    /// A phase depends on a phase that doesn't exist, or the dependencies form a cycle
+    pub fn get_ordered_phases(&self) -> Result<Vec<Phase>> {
+        let mut phases: Vec<Phase> = vec![
+            self.install.clone().unwrap_or_default().into(),
+            self.build.clone().unwrap_or_default().into(),
+        ];
+
+        for phase in self.phases.clone().unwrap_or_default() {
+            if [SETUP_PHASE_NAME, START_PHASE_NAME].contains(&phase.name.as_str())
+                || phases.iter().any(|p| p.name == phase.name)
+            {
+                bail!("Phase `{}` is defined more than once", phase.name);
+            }
+            phases.push(phase);
+        }
+
+        for phase in &phases {
+            for dependency in phase.depends_on.clone().unwrap_or_default() {
+                if !phases.iter().any(|p| p.name == dependency) {
+                    bail!(
+                        "Phase `{}` depends on unknown phase `{}`",
+                        phase.name,
+                        dependency
+                    );
+                }
+            }
+        }
+
+        // Repeatedly take the first declared phase whose dependencies have all run
+        let mut ordered: Vec<Phase> = Vec::new();
+        while !phases.is_empty() {
+            let next = phases.iter().position(|phase| {
+                phase
+                    .depends_on
+                    .clone()
+                    .unwrap_or_default()
+                    .iter()
+                    .all(|dependency| ordered.iter().any(|p| &p.name == dependency))
+            });
+
+            match next {
+                Some(index) => ordered.push(phases.remove(index)),
+                None => bail!(
+                    "Phases {} have circular dependencies",
+                    phases
+                        .iter()
+                        .map(|phase| format!("`{}`", phase.name))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            }
+        }
+
+        Ok(ordered)
+    }
+
+    pub fn get_build_string(&self) -> Result<String> {
         let setup_phase = self.setup.clone();
         let packages_string = get_phase_string(
             "Packages",
@@ -32,24 +338,30 @@ impl BuildPlan {
             }),
         );
 
-        let install_phase = self.install.clone();
-        let install_string = get_phase_string("Install", install_phase.and_then(|build| build.cmd));
-
-        let build_phase = self.build.clone();
-        let build_string = get_phase_string("Build", build_phase.and_then(|build| build.cmd));
+        let phases_string = self
+            .get_ordered_phases()?
+            .into_iter()
+            .map(|phase| {
+                let title = match phase.name.as_str() {
+                    INSTALL_PHASE_NAME => "Install".to_string(),
+                    BUILD_PHASE_NAME => "Build".to_string(),
+                    name => name.to_string(),
+                };
+                get_phase_string(&title, phase.cmd)
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
 
         let start_phase = self.start.clone();
         let start_string = get_phase_string("Start", start_phase.and_then(|start| start.cmd));
 
-        return formatdoc! {"
+        Ok(formatdoc! {"
           {packages_string}
-          {install_string}
-          {build_string}
+          {phases_string}
           {start_string}",
             packages_string=packages_string,
-            install_string=install_string,
-            build_string=build_string,
-        start_string=start_string};
+            phases_string=phases_string,
+        start_string=start_string})
     }
 }
 
@@ -63,3 +375,295 @@ fn get_phase_string(phase: &str, content: Option<String>) -> String {
         }
     }
 }
+
+fn merge_values(base: Value, partial: &Value) -> Value {
+    match (base, partial) {
+        (Value::Object(mut base), Value::Object(partial)) => {
+            for (key, value) in partial {
+                let existing = base.remove(key).unwrap_or(Value::Null);
+                base.insert(key.clone(), merge_values(existing, value));
+            }
+            Value::Object(base)
+        }
+        (base, Value::Array(partial)) => {
+            let existing = match base {
+                Value::Array(items) => items,
+                _ => Vec::new(),
+            };
+
+            let mut items = Vec::new();
+            for value in partial {
+                if value.as_str() == Some(MERGE_EXISTING_ITEMS) {
+                    items.append(&mut existing.clone());
+                } else {
+                    items.push(value.clone());
+                }
+            }
+            Value::Array(items)
+        }
+        (_, partial) => partial.clone(),
+    }
+}
+
+fn add_field<'a>(
+    fields: &mut Vec<(String, &'a mut String)>,
+    name: &str,
+    value: &'a mut Option<String>,
+) {
+    if let Some(value) = value {
+        fields.push((name.to_string(), value));
+    }
+}
+
+fn add_list_fields<'a>(
+    fields: &mut Vec<(String, &'a mut String)>,
+    name: &str,
+    values: &'a mut Option<Vec<String>>,
+) {
+    for value in values.iter_mut().flatten() {
+        fields.push((name.to_string(), value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nixpacks::nix::Pkg;
+    use serde_json::json;
+
+    fn get_plan() -> BuildPlan {
+        BuildPlan {
+            version: None,
+            schema_version: None,
+            setup: Some(SetupPhase::new(vec![Pkg::new("nodejs")])),
+            install: Some(InstallPhase::new("npm ci".to_string())),
+            build: Some(BuildPhase::new("npm run build".to_string())),
+            phases: None,
+            start: Some(StartPhase::new("npm run start".to_string())),
+            variables: Some(EnvironmentVariables::from([(
+                "NODE_ENV".to_string(),
+                "production".to_string(),
+            )])),
+            variable_scopes: None,
+            secrets: None,
+        }
+    }
+
+    #[test]
+    fn test_plan_formats_roundtrip() -> Result<()> {
+        let mut plan = get_plan();
+        plan.schema_version = Some(schema::PLAN_SCHEMA_VERSION);
+        plan.phases = Some(vec![Phase {
+            name: "test".to_string(),
+            cmd: Some("npm test".to_string()),
+            depends_on: Some(vec!["build".to_string()]),
+            ..Default::default()
+        }]);
+        let json = plan.to_format(PlanFormat::Json)?;
+
+        for format in [PlanFormat::Json, PlanFormat::Yaml, PlanFormat::Toml] {
+            let serialized = plan.to_format(format)?;
+            assert!(serialized.contains("dependsOn"));
+            let parsed = BuildPlan::from_format(&serialized, format)?;
+            assert_eq!(parsed.to_format(PlanFormat::Json)?, json);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_format_from_path() {
+        assert_eq!(PlanFormat::from_path("plan.YML"), Some(PlanFormat::Yaml));
+        assert_eq!(
+            PlanFormat::from_path("out/plan.toml"),
+            Some(PlanFormat::Toml)
+        );
+        assert_eq!(PlanFormat::from_path("plan"), None);
+        assert!("xml".parse::<PlanFormat>().is_err());
+    }
+
+    #[test]
+    fn test_merge_start_cmd() -> Result<()> {
+        let plan = get_plan().merge(&json!({ "start": { "cmd": "node index.js" } }))?;
+        assert_eq!(plan.start.unwrap().cmd, Some("node index.js".to_string()));
+        assert_eq!(plan.build.unwrap().cmd, Some("npm run build".to_string()));
+        assert_eq!(plan.setup.unwrap().pkgs, vec![Pkg::new("nodejs")]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_append_pkgs() -> Result<()> {
+        let plan =
+            get_plan().merge(&json!({ "setup": { "pkgs": ["...", { "name": "cowsay" }] } }))?;
+        assert_eq!(
+            plan.setup.unwrap().pkgs,
+            vec![Pkg::new("nodejs"), Pkg::new("cowsay")]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_replace_pkgs() -> Result<()> {
+        let plan =
+            get_plan().merge(&json!({ "setup": { "pkgs": [{ "name": "nodejs-16_x" }] } }))?;
+        assert_eq!(plan.setup.unwrap().pkgs, vec![Pkg::new("nodejs-16_x")]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_variables() -> Result<()> {
+        let plan = get_plan().merge(&json!({ "variables": { "HELLO": "world" } }))?;
+        let variables = plan.variables.unwrap();
+        assert_eq!(variables.get("HELLO"), Some(&"world".to_string()));
+        assert_eq!(variables.get("NODE_ENV"), Some(&"production".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_unset_field() -> Result<()> {
+        let plan = get_plan().merge(&json!({ "build": { "cmd": null } }))?;
+        assert_eq!(plan.build.unwrap().cmd, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_ordered_phases_default() -> Result<()> {
+        let phases = get_plan().get_ordered_phases()?;
+        assert_eq!(
+            phases.iter().map(|p| p.name.as_str()).collect::<Vec<_>>(),
+            vec!["install", "build"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_ordered_phases_with_dependencies() -> Result<()> {
+        let mut plan = get_plan();
+
+        let mut test_phase = Phase::new("test", "npm test".to_string());
+        test_phase.depends_on_phase("build");
+        let mut codegen_phase = Phase::new("codegen", "npm run codegen".to_string());
+        codegen_phase.depends_on_phase("install");
+        plan.phases = Some(vec![test_phase, codegen_phase]);
+
+        let mut build_phase = plan.build.clone().unwrap();
+        build_phase.depends_on = Some(vec!["codegen".to_string()]);
+        plan.build = Some(build_phase);
+
+        let phases = plan.get_ordered_phases()?;
+        assert_eq!(
+            phases.iter().map(|p| p.name.as_str()).collect::<Vec<_>>(),
+            vec!["install", "codegen", "build", "test"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_ordered_phases_unknown_dependency() {
+        let mut plan = get_plan();
+        let mut phase = Phase::new("test", "npm test".to_string());
+        phase.depends_on_phase("lint");
+        plan.phases = Some(vec![phase]);
+
+        assert!(plan.get_ordered_phases().is_err());
+    }
+
+    #[test]
+    fn test_ordered_phases_cycle() {
+        let mut plan = get_plan();
+        let mut phase = Phase::new("codegen", "npm run codegen".to_string());
+        phase.depends_on_phase("build");
+        plan.phases = Some(vec![phase]);
+
+        let mut install_phase = plan.install.clone().unwrap();
+        install_phase.depends_on = Some(vec!["codegen".to_string()]);
+        plan.install = Some(install_phase);
+
+        assert!(plan.get_ordered_phases().is_err());
+    }
+
+    #[test]
+    fn test_ordered_phases_duplicate_name() {
+        let mut plan = get_plan();
+        plan.phases = Some(vec![Phase::new("build", "make".to_string())]);
+
+        assert!(plan.get_ordered_phases().is_err());
+    }
+
+    #[test]
+    fn test_variable_scopes() {
+        let mut plan = get_plan();
+        plan.variables = Some(EnvironmentVariables::from([
+            ("NODE_ENV".to_string(), "production".to_string()),
+            ("NPM_CONFIG_PRODUCTION".to_string(), "false".to_string()),
+            ("PORT".to_string(), "3000".to_string()),
+        ]));
+        plan.variable_scopes = Some(VariableScopes::from([
+            ("NPM_CONFIG_PRODUCTION".to_string(), VariableScope::Build),
+            ("PORT".to_string(), VariableScope::Runtime),
+        ]));
+
+        assert_eq!(
+            plan.get_variable_names(VariableScope::is_build),
+            vec!["NODE_ENV", "NPM_CONFIG_PRODUCTION"]
+        );
+        assert_eq!(
+            plan.get_variable_names(VariableScope::is_runtime),
+            vec!["NODE_ENV", "PORT"]
+        );
+        assert_eq!(
+            plan.get_variable_names(|scope| scope == &VariableScope::Runtime),
+            vec!["PORT"]
+        );
+    }
+
+    #[test]
+    fn test_interpolate_variables() -> Result<()> {
+        let mut plan = get_plan();
+        plan.build = Some(BuildPhase::new(
+            "npm run build -- --mode ${NODE_ENV}".to_string(),
+        ));
+        plan.start = Some(StartPhase::new(
+            "node ${MAIN:-index.js} --port $PORT".to_string(),
+        ));
+        assert!(plan.interpolate_variables(true)?.is_empty());
+
+        assert_eq!(
+            plan.build.unwrap().cmd,
+            Some("npm run build -- --mode production".to_string())
+        );
+        assert_eq!(
+            plan.start.unwrap().cmd,
+            Some("node index.js --port $PORT".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_interpolate_unresolved_variables() -> Result<()> {
+        let mut plan = get_plan();
+        plan.build = Some(BuildPhase::new("cargo build --bin ${APP_BIN}".to_string()));
+        plan.start = Some(StartPhase::new("./server --port ${PORT}".to_string()));
+        assert!(plan.clone().interpolate_variables(true).is_err());
+
+        assert_eq!(
+            plan.interpolate_variables(false)?,
+            vec!["APP_BIN".to_string()]
+        );
+        assert_eq!(
+            plan.build.unwrap().cmd,
+            Some("cargo build --bin ${APP_BIN}".to_string())
+        );
+        assert_eq!(
+            plan.start.unwrap().cmd,
+            Some("./server --port ${PORT}".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_invalid_partial() {
+        assert!(get_plan()
+            .merge(&json!({ "setup": { "pkgs": "cowsay" } }))
+            .is_err());
+    }
+}
@@ -0,0 +1,299 @@
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Map, Value};
+
+use super::{images::DEFAULT_BASE_IMAGE, plan::BuildPlan};
+
+/// Version of the build plan format written by this version of nixpacks
+pub const PLAN_SCHEMA_VERSION: u32 = 1;
+
+/// Upgrades a plan from the version at the same index to the next version
+const MIGRATIONS: &[fn(Value) -> Result<Value>] = &[migrate_v0_to_v1];
+
+/// JSON Schema describing the build plan format
+pub fn get_plan_schema() -> Result<String> {
+    let schema = schemars::schema_for!(BuildPlan);
+    Ok(serde_json::to_string_pretty(&schema)?)
+}
+
+/// Upgrade a plan created by an older version of nixpacks to the current format
+pub fn migrate(mut plan: Value) -> Result<Value> {
+    let schema_version = get_schema_version(&plan)?;
+
+    if schema_version > PLAN_SCHEMA_VERSION {
+        let created_by = plan
+            .get("version")
+            .and_then(Value::as_str)
+            .unwrap_or("unknown");
+
+        bail!(
+            "Build plan schema version {} is newer than the supported version {} (created by nixpacks v{}). Please upgrade nixpacks",
+            schema_version,
+            PLAN_SCHEMA_VERSION,
+            created_by
+        );
+    }
+
+    for migration in &MIGRATIONS[schema_version as usize..] {
+        plan = migration(plan)?;
+    }
+
+    if let Value::Object(fields) = &mut plan {
+        fields.insert("schemaVersion".to_string(), json!(PLAN_SCHEMA_VERSION));
+    }
+
+    Ok(plan)
+}
+
+/// Whether the plan was written in the current format, rather than upgraded from an older one
+pub fn is_current(plan: &Value) -> bool {
+    plan.get("schemaVersion").and_then(Value::as_u64) == Some(u64::from(PLAN_SCHEMA_VERSION))
+}
+
+/// Deserialize a plan in the current format, reporting the path of any invalid field
+///
+/// With `deny_unknown_fields`, fields that aren't part of the format are an error instead of
+/// being ignored, so typos in a plan written for this version of nixpacks are caught.
+pub fn deserialize_plan(plan: Value, deny_unknown_fields: bool) -> Result<BuildPlan> {
+    let parsed: BuildPlan = match serde_path_to_error::deserialize(plan.clone()) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            let path = e.path().to_string();
+            match path.as_str() {
+                "." => bail!("Invalid build plan: {}", e.into_inner()),
+                _ => bail!("Invalid build plan at `{}`: {}", path, e.into_inner()),
+            }
+        }
+    };
+
+    if deny_unknown_fields {
+        let known = serde_json::to_value(&parsed).context("Serializing build plan")?;
+        if let Some(path) = find_unknown_field(&plan, &known, "") {
+            bail!(
+                "Invalid build plan at `{}`: unknown field for schema version {}",
+                path,
+                PLAN_SCHEMA_VERSION
+            );
+        }
+    }
+
+    Ok(parsed)
+}
+
+/// Path of the first field of `plan` that is missing from `known`, the deserialized plan
+/// serialized again
+fn find_unknown_field(plan: &Value, known: &Value, path: &str) -> Option<String> {
+    match (plan, known) {
+        (Value::Object(fields), Value::Object(known_fields)) => {
+            fields.iter().find_map(|(key, value)| {
+                let field_path = match path {
+                    "" => key.clone(),
+                    _ => format!("{}.{}", path, key),
+                };
+                match known_fields.get(key) {
+                    Some(known) => find_unknown_field(value, known, &field_path),
+                    // Unset fields are left out when the plan is serialized
+                    None if value.is_null() => None,
+                    None => Some(field_path),
+                }
+            })
+        }
+        (Value::Array(items), Value::Array(known_items)) => items
+            .iter()
+            .zip(known_items)
+            .enumerate()
+            .find_map(|(i, (item, known))| {
+                find_unknown_field(item, known, &format!("{}[{}]", path, i))
+            }),
+        _ => None,
+    }
+}
+
+fn get_schema_version(plan: &Value) -> Result<u32> {
+    let fields = match plan {
+        Value::Object(fields) => fields,
+        _ => bail!("Invalid build plan: expected a JSON object"),
+    };
+
+    match fields.get("schemaVersion") {
+        Some(Value::Number(n)) => match n.as_u64() {
+            Some(version) => u32::try_from(version).with_context(|| {
+                format!(
+                    "Invalid build plan at `schemaVersion`: {} is not a supported version",
+                    version
+                )
+            }),
+            None => bail!("Invalid build plan at `schemaVersion`: expected a positive integer"),
+        },
+        Some(_) => bail!("Invalid build plan at `schemaVersion`: expected a positive integer"),
+        // Plans created before the schema was versioned
+        None if is_v0_plan(fields) => Ok(0),
+        None => Ok(1),
+    }
+}
+
+fn is_v0_plan(fields: &Map<String, Value>) -> bool {
+    ["nixConfig", "installCmd", "buildCmd", "startCmd"]
+        .iter()
+        .any(|key| fields.contains_key(*key))
+}
+
+/// Plans used to be a flat list of commands with a single Nix config
+///
+/// ```json
+/// { "nixConfig": { "pkgs": ["nodejs"] }, "installCmd": "npm ci", "startCmd": "npm start" }
+/// ```
+fn migrate_v0_to_v1(plan: Value) -> Result<Value> {
+    let mut fields = match plan {
+        Value::Object(fields) => fields,
+        _ => bail!("Invalid build plan: expected a JSON object"),
+    };
+
+    let nix_config = fields.remove("nixConfig").unwrap_or_else(|| json!({}));
+    let pkgs = nix_config
+        .get("pkgs")
+        .and_then(Value::as_array)
+        .cloned()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|pkg| match pkg {
+            // Packages could be listed by name
+            Value::String(name) => json!({ "name": name }),
+            pkg => pkg,
+        })
+        .collect::<Vec<_>>();
+
+    let mut setup = json!({ "pkgs": pkgs, "baseImage": DEFAULT_BASE_IMAGE });
+    if let Some(archive) = nix_config.get("archive").filter(|a| !a.is_null()) {
+        setup["archive"] = archive.clone();
+    }
+    fields.insert("setup".to_string(), setup);
+
+    for (old_key, phase) in [
+        ("installCmd", "install"),
+        ("buildCmd", "build"),
+        ("startCmd", "start"),
+    ] {
+        let cmd = fields.remove(old_key).unwrap_or(Value::Null);
+        fields.insert(phase.to_string(), json!({ "cmd": cmd }));
+    }
+
+    Ok(Value::Object(fields))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nixpacks::nix::Pkg;
+
+    #[test]
+    fn test_load_current_plan() -> Result<()> {
+        let plan = BuildPlan::from_json(
+            r#"{
+                "version": "0.0.18",
+                "schemaVersion": 1,
+                "setup": { "pkgs": [{ "name": "nodejs" }], "baseImage": "debian" },
+                "start": { "cmd": "npm start" }
+            }"#,
+        )?;
+        assert_eq!(plan.setup.unwrap().pkgs, vec![Pkg::new("nodejs")]);
+        assert_eq!(plan.start.unwrap().cmd, Some("npm start".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_unversioned_plan() -> Result<()> {
+        let plan = BuildPlan::from_json(
+            r#"{ "setup": { "pkgs": [], "baseImage": "debian" }, "build": { "cmd": "make" } }"#,
+        )?;
+        assert_eq!(plan.schema_version, Some(PLAN_SCHEMA_VERSION));
+        assert_eq!(plan.build.unwrap().cmd, Some("make".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_v0_plan() -> Result<()> {
+        let plan = BuildPlan::from_json(
+            r#"{
+                "version": "0.0.1",
+                "nixConfig": { "pkgs": ["nodejs", { "name": "yarn" }], "archive": "abc" },
+                "installCmd": "yarn install",
+                "buildCmd": null,
+                "startCmd": "yarn start",
+                "variables": { "NODE_ENV": "production" }
+            }"#,
+        )?;
+
+        let setup = plan.setup.unwrap();
+        assert_eq!(setup.pkgs, vec![Pkg::new("nodejs"), Pkg::new("yarn")]);
+        assert_eq!(setup.archive, Some("abc".to_string()));
+        assert_eq!(setup.base_image, DEFAULT_BASE_IMAGE.to_string());
+        assert_eq!(plan.install.unwrap().cmd, Some("yarn install".to_string()));
+        assert_eq!(plan.build.unwrap().cmd, None);
+        assert_eq!(plan.start.unwrap().cmd, Some("yarn start".to_string()));
+        assert_eq!(
+            plan.variables.unwrap().get("NODE_ENV"),
+            Some(&"production".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_field_error() {
+        let error = BuildPlan::from_json(
+            r#"{ "setup": { "pkgs": [{ "name": 5 }], "baseImage": "debian" } }"#,
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("`setup.pkgs[0].name`"));
+    }
+
+    #[test]
+    fn test_unknown_field_in_current_plan() {
+        let error = BuildPlan::from_json(
+            r#"{
+                "schemaVersion": 1,
+                "setup": { "pkgs": [{ "name": "nodejs", "overlays": "x" }], "baseImage": "debian" }
+            }"#,
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("`setup.pkgs[0].overlays`"));
+
+        let error =
+            BuildPlan::from_json(r#"{ "schemaVersion": 1, "strat": { "cmd": "npm start" } }"#)
+                .unwrap_err();
+        assert!(error.to_string().contains("`strat`"));
+    }
+
+    #[test]
+    fn test_unknown_field_in_older_plan() -> Result<()> {
+        // Plans that are upgraded may have fields that were dropped from the format
+        let plan = BuildPlan::from_json(
+            r#"{ "version": "0.0.1", "startCmd": "npm start", "nixOverlay": "x" }"#,
+        )?;
+        assert_eq!(plan.start.unwrap().cmd, Some("npm start".to_string()));
+
+        let plan = BuildPlan::from_json(r#"{ "start": { "cmd": "npm start" }, "extra": 1 }"#)?;
+        assert_eq!(plan.start.unwrap().cmd, Some("npm start".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_newer_schema_version() {
+        let error =
+            BuildPlan::from_json(r#"{ "version": "9.0.0", "schemaVersion": 99 }"#).unwrap_err();
+        assert!(error.to_string().contains("v9.0.0"));
+    }
+
+    #[test]
+    fn test_schema_version_out_of_range() {
+        // Would be read as version 1 if it were truncated to 32 bits
+        let error = BuildPlan::from_json(r#"{ "schemaVersion": 4294967297 }"#).unwrap_err();
+        assert!(format!("{:?}", error).contains("4294967297 is not a supported version"));
+    }
+
+    #[test]
+    fn test_published_schema_is_current() -> Result<()> {
+        let published = include_str!("../../docs/plan.schema.json");
+        assert_eq!(published.trim_end(), get_plan_schema()?);
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+use anyhow::{Context, Result};
+use indoc::formatdoc;
+
+use super::{nix::nixpkgs_import, plan::BuildPlan, AppBuilder};
+
+pub const SHELL_NIX_FILE_NAME: &str = "shell.nix";
+
+/// First line of every generated `shell.nix`, so it can be regenerated without overwriting one
+/// that was written by hand
+pub const SHELL_NIX_HEADER: &str = "# Generated by `nixpacks shell`, changes will be overwritten";
+
+/// Create a `shell.nix` with the environment of the setup phase, the paths added by the phases,
+/// and the variables of the plan, so `nix-shell` gives the same toolchain as the image
+pub fn gen_shell_nix(plan: &BuildPlan) -> Result<String> {
+    let setup_phase = plan.setup.clone().unwrap_or_default();
+    let env = AppBuilder::gen_nix(plan).context("Generating Nix expression")?;
+    let env = env
+        .trim_end()
+        .lines()
+        .map(|line| match line.is_empty() {
+            true => String::new(),
+            false => format!("    {}", line),
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let mut exports = Vec::new();
+    for phase in plan.get_ordered_phases().context("Ordering phases")? {
+        if let Some(paths) = phase.paths {
+            exports.push(format!("export PATH=\"{}:$PATH\"", paths.join(":")));
+        }
+    }
+
+    let mut variables = plan
+        .variables
+        .clone()
+        .unwrap_or_default()
+        .into_iter()
+        .collect::<Vec<_>>();
+    variables.sort();
+    for (name, value) in variables {
+        exports.push(format!("export {}={}", name, shell_quote(&value)));
+    }
+
+    let shell_hook = exports
+        .iter()
+        .map(|export| format!("    {}\n", escape_indented_string(export)))
+        .collect::<String>();
+
+    let shell_nix = formatdoc! {"
+        {header}
+        {{ }}:
+
+        let
+          env = (
+        {env}
+          ) {{ }};
+          pkgs = {pkg_import} {{ }};
+        in
+        pkgs.mkShell {{
+          packages = [ env ];
+          shellHook = ''
+        {shell_hook}  '';
+        }}
+    ",
+    header=SHELL_NIX_HEADER,
+    env=env,
+    pkg_import=nixpkgs_import(setup_phase.archive.as_deref(), setup_phase.archive_hash.as_deref()),
+    shell_hook=shell_hook};
+
+    Ok(shell_nix)
+}
+
+/// Single quote a value for the shell
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Escape text for a Nix `''` string, where `''` and `${` have a special meaning
+fn escape_indented_string(text: &str) -> String {
+    text.replace("''", "'''").replace("${", "''${")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nixpacks::{
+        environment::EnvironmentVariables,
+        nix::Pkg,
+        phase::{InstallPhase, SetupPhase},
+    };
+
+    fn get_plan() -> BuildPlan {
+        let mut install_phase = InstallPhase::new("pip install -r requirements.txt".to_string());
+        install_phase.add_path("/opt/venv/bin".to_string());
+
+        BuildPlan {
+            version: None,
+            schema_version: None,
+            setup: Some(SetupPhase::new(vec![Pkg::new("python38")])),
+            install: Some(install_phase),
+            build: None,
+            phases: None,
+            start: None,
+            variables: Some(EnvironmentVariables::from([
+                ("NODE_ENV".to_string(), "production".to_string()),
+                ("GREETING".to_string(), "it's ${HOME}".to_string()),
+            ])),
+            variable_scopes: None,
+            secrets: None,
+        }
+    }
+
+    #[test]
+    fn test_gen_shell_nix() -> Result<()> {
+        let shell_nix = gen_shell_nix(&get_plan())?;
+
+        assert!(shell_nix.starts_with(SHELL_NIX_HEADER));
+        assert!(shell_nix.contains("  env = (\n    { }:\n"));
+        assert!(shell_nix.contains("        python38\n"));
+        assert!(shell_nix.contains("  pkgs = import <nixpkgs> { };"));
+        assert!(shell_nix.contains(
+            "    export PATH=\"/opt/venv/bin:$PATH\"\n    export GREETING='it'\\'''s ''${HOME}'\n    export NODE_ENV='production'\n  '';"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_gen_shell_nix_with_archive() -> Result<()> {
+        let mut plan = get_plan();
+        plan.setup.as_mut().unwrap().archive = Some("a0b1c2".to_string());
+
+        let shell_nix = gen_shell_nix(&plan)?;
+        assert!(shell_nix.contains(
+            "  pkgs = import (fetchTarball \"https://github.com/NixOS/nixpkgs/archive/a0b1c2.tar.gz\") { };"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_escape_indented_string() {
+        assert_eq!(escape_indented_string("a '' ${b} $c"), "a ''' ''${b} $c");
+    }
+}
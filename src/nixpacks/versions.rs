@@ -0,0 +1,476 @@
+use std::{collections::BTreeMap, str::FromStr};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+use super::nix::Pkg;
+
+/// Versions of languages that are available as nixpkgs attributes, by language
+const VERSION_INDEX: &str = include_str!("versions.json");
+
+#[derive(Deserialize, Debug)]
+struct IndexLanguage {
+    #[serde(default)]
+    aliases: Vec<String>,
+    /// Release line the provider installs when no version is requested
+    default: Option<String>,
+    versions: Vec<IndexVersion>,
+}
+
+#[derive(Deserialize, Debug)]
+struct IndexVersion {
+    /// Release line the attribute provides, e.g. `18` or `3.10`
+    version: String,
+    attr: String,
+    /// nixpkgs revision that carries the attribute, the default archive when not set
+    archive: Option<String>,
+}
+
+/// The nixpkgs attribute chosen for a version requirement
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolvedVersion {
+    /// Name of the language in the index, e.g. `node` for `nodejs`
+    pub language: String,
+    pub version: String,
+    /// Package for the attribute, pinned to the archive that carries it
+    pub pkg: Pkg,
+}
+
+/// Find the version of a language (or one of its aliases, e.g. `nodejs`) in the bundled index that
+/// satisfies a requirement such as `18.x`, `>=14.2 <16`, or `^3.9`
+///
+/// When several versions match, the default version of the language is used if it is one of them,
+/// so that e.g. Python `3` keeps installing the same version. Otherwise requirements without an
+/// upper bound (e.g. `>=14`) get the oldest matching version, so adding a newer version to the
+/// index doesn't upgrade apps that are already deployed, and the other requirements get the
+/// newest one.
+pub fn resolve_version(language: &str, requirement: &str) -> Result<ResolvedVersion> {
+    let index: BTreeMap<String, IndexLanguage> =
+        serde_json::from_str(VERSION_INDEX).context("Parsing version index")?;
+    resolve_version_in(&index, language, requirement)
+}
+
+fn resolve_version_in(
+    index: &BTreeMap<String, IndexLanguage>,
+    language: &str,
+    requirement: &str,
+) -> Result<ResolvedVersion> {
+    let (name, entry) = match index
+        .iter()
+        .find(|(name, entry)| *name == language || entry.aliases.iter().any(|a| a == language))
+    {
+        Some(found) => found,
+        None => bail!(
+            "No versions of `{}` are known. Versions can be requested for {}",
+            language,
+            index.keys().cloned().collect::<Vec<_>>().join(", ")
+        ),
+    };
+
+    let req = requirement
+        .parse::<VersionReq>()
+        .with_context(|| format!("Invalid {} version `{}`", name, requirement))?;
+
+    let mut matching = Vec::new();
+    for version in &entry.versions {
+        let partial = Partial::parse(&version.version)?;
+        if req.matches(&partial) {
+            matching.push((partial.lower(), version));
+        }
+    }
+    matching.sort_by_key(|(lower, _)| *lower);
+
+    let default = matching
+        .iter()
+        .find(|(_, version)| entry.default.as_ref() == Some(&version.version));
+    let best = match default {
+        Some(default) => Some(default),
+        None if req.is_open_ended() => matching.first(),
+        None => matching.last(),
+    };
+
+    match best {
+        Some((_, version)) => {
+            let mut pkg = Pkg::new(&version.attr);
+            pkg.archive = version.archive.clone();
+            Ok(ResolvedVersion {
+                language: name.clone(),
+                version: version.version.clone(),
+                pkg,
+            })
+        }
+        None => bail!(
+            "{} version `{}` is not available. Available versions: {}",
+            name,
+            requirement,
+            entry
+                .versions
+                .iter()
+                .map(|v| v.version.clone())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+/// Whether a requirement can be parsed, unlike e.g. `lts/*` or `latest`
+pub fn is_valid_requirement(requirement: &str) -> bool {
+    requirement.parse::<VersionReq>().is_ok()
+}
+
+/// Language of a Nix package in the index, either one of its versions (e.g. `nodejs-16_x`), its
+/// name, or one of its aliases (e.g. `nodejs`)
+pub fn get_language(attr: &str) -> Option<String> {
+    let index: BTreeMap<String, IndexLanguage> = serde_json::from_str(VERSION_INDEX).ok()?;
+    index
+        .into_iter()
+        .find(|(name, entry)| {
+            name == attr
+                || entry.aliases.iter().any(|a| a == attr)
+                || entry.versions.iter().any(|v| v.attr == attr)
+        })
+        .map(|(name, _)| name)
+}
+
+/// Split a package requested as `name@version` into the name and the version requirement
+pub fn parse_pkg_version(name: &str) -> Option<(&str, &str)> {
+    name.split_once('@')
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Version {
+    major: u64,
+    minor: u64,
+    patch: u64,
+}
+
+impl Version {
+    fn new(major: u64, minor: u64, patch: u64) -> Self {
+        Version {
+            major,
+            minor,
+            patch,
+        }
+    }
+}
+
+/// A version where trailing parts may be missing or wildcards, e.g. `14`, `3.x`, or `*`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Partial {
+    parts: Vec<u64>,
+}
+
+impl Partial {
+    fn parse(s: &str) -> Result<Partial> {
+        let s = s.trim_start_matches(['v', '=']);
+        let mut parts = Vec::new();
+        for part in s.split('.') {
+            if matches!(part, "x" | "X" | "*") {
+                break;
+            }
+            if parts.len() == 3 {
+                bail!("Too many parts in version `{}`", s);
+            }
+            parts.push(
+                part.parse::<u64>()
+                    .with_context(|| format!("Invalid version `{}`", s))?,
+            );
+        }
+        Ok(Partial { parts })
+    }
+
+    fn part(&self, i: usize) -> u64 {
+        self.parts.get(i).copied().unwrap_or_default()
+    }
+
+    /// Lowest version matching the partial version
+    fn lower(&self) -> Version {
+        Version::new(self.part(0), self.part(1), self.part(2))
+    }
+
+    /// Lowest version above every version matching the partial version
+    fn upper(&self) -> Option<Version> {
+        match self.parts.len() {
+            0 => None,
+            1 => Some(Version::new(self.part(0) + 1, 0, 0)),
+            2 => Some(Version::new(self.part(0), self.part(1) + 1, 0)),
+            _ => Some(Version::new(self.part(0), self.part(1), self.part(2) + 1)),
+        }
+    }
+
+    fn range(&self) -> Range {
+        Range {
+            lower: self.lower(),
+            upper: self.upper(),
+        }
+    }
+}
+
+/// Versions from `lower` up to, but not including, `upper`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Range {
+    lower: Version,
+    upper: Option<Version>,
+}
+
+impl Range {
+    fn all() -> Self {
+        Range {
+            lower: Version::new(0, 0, 0),
+            upper: None,
+        }
+    }
+
+    fn intersect(&self, other: &Range) -> Range {
+        let upper = match (self.upper, other.upper) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        Range {
+            lower: self.lower.max(other.lower),
+            upper,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.upper.is_some_and(|upper| upper <= self.lower)
+    }
+}
+
+/// An npm style version requirement, any of the `||` separated ranges
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct VersionReq {
+    ranges: Vec<Range>,
+}
+
+impl VersionReq {
+    /// Whether any version of a release line satisfies the requirement
+    fn matches(&self, line: &Partial) -> bool {
+        let line = line.range();
+        self.ranges
+            .iter()
+            .any(|range| !range.intersect(&line).is_empty())
+    }
+
+    /// Whether the requirement allows every version above some version, e.g. `>=14`
+    fn is_open_ended(&self) -> bool {
+        self.ranges.iter().any(|range| range.upper.is_none())
+    }
+}
+
+impl FromStr for VersionReq {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let ranges = s.split("||").map(parse_range).collect::<Result<Vec<_>>>()?;
+        Ok(VersionReq { ranges })
+    }
+}
+
+/// Parse space separated comparators (e.g. `>=14.2 <16`) or a hyphen range (e.g. `14 - 16`)
+fn parse_range(s: &str) -> Result<Range> {
+    // Join operators that are separated from their version, e.g. `>= 14`
+    let mut comparators: Vec<String> = Vec::new();
+    let mut op = String::new();
+    for token in s.split_whitespace() {
+        if token.chars().all(|c| "<>=^~".contains(c)) {
+            op.push_str(token);
+        } else {
+            comparators.push(format!("{}{}", op, token));
+            op.clear();
+        }
+    }
+    if !op.is_empty() {
+        bail!("Missing version after `{}`", op);
+    }
+
+    if let [from, hyphen, to] = comparators.as_slice() {
+        if hyphen == "-" {
+            return Ok(Range {
+                lower: Partial::parse(from)?.lower(),
+                upper: Partial::parse(to)?.upper(),
+            });
+        }
+    }
+
+    let mut range = Range::all();
+    for comparator in &comparators {
+        range = range.intersect(&parse_comparator(comparator)?);
+    }
+    Ok(range)
+}
+
+fn parse_comparator(s: &str) -> Result<Range> {
+    let split = s
+        .find(|c: char| !"<>=^~".contains(c))
+        .context("Missing version")?;
+    let (op, version) = s.split_at(split);
+    let partial = Partial::parse(version)?;
+    let lower = partial.lower();
+
+    let range = match op {
+        "" | "=" => partial.range(),
+        ">=" => Range { lower, upper: None },
+        ">" => match partial.upper() {
+            Some(upper) => Range {
+                lower: upper,
+                upper: None,
+            },
+            None => bail!("No version is greater than `{}`", version),
+        },
+        "<" => {
+            if partial.parts.is_empty() {
+                bail!("No version is less than `{}`", version);
+            }
+            Range {
+                lower: Version::new(0, 0, 0),
+                upper: Some(lower),
+            }
+        }
+        "<=" => Range {
+            lower: Version::new(0, 0, 0),
+            upper: partial.upper(),
+        },
+        // Patch updates, or minor updates when only the major version is given
+        "~" => match partial.parts.len() {
+            0 | 1 => partial.range(),
+            _ => Range {
+                lower,
+                upper: Some(Version::new(lower.major, lower.minor + 1, 0)),
+            },
+        },
+        // Updates that don't change the first non-zero part
+        "^" => {
+            let upper = if lower.major > 0 || partial.parts.len() == 1 {
+                Version::new(lower.major + 1, 0, 0)
+            } else if lower.minor > 0 || partial.parts.len() == 2 {
+                Version::new(0, lower.minor + 1, 0)
+            } else {
+                Version::new(0, 0, lower.patch + 1)
+            };
+            match partial.parts.is_empty() {
+                true => Range::all(),
+                false => Range {
+                    lower,
+                    upper: Some(upper),
+                },
+            }
+        }
+        _ => bail!("Unknown version operator `{}`", op),
+    };
+
+    Ok(range)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nixpacks::{nix::is_revision, pkg_index::PkgIndex};
+
+    fn attr(language: &str, requirement: &str) -> String {
+        resolve_version(language, requirement).unwrap().pkg.name
+    }
+
+    #[test]
+    fn test_version_index_is_valid() -> Result<()> {
+        let index: BTreeMap<String, IndexLanguage> = serde_json::from_str(VERSION_INDEX)?;
+        let pkg_index = PkgIndex::bundled();
+        for language in index.values() {
+            for version in &language.versions {
+                Partial::parse(&version.version)?;
+                match &version.archive {
+                    // The package index only lists the attributes of the default archive
+                    Some(archive) => assert!(is_revision(archive), "{}", archive),
+                    None => assert!(pkg_index.contains(&version.attr), "{}", version.attr),
+                }
+            }
+            if let Some(default) = &language.default {
+                assert!(language.versions.iter().any(|v| &v.version == default));
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_node_versions() {
+        assert_eq!(attr("node", "14"), "nodejs-14_x");
+        assert_eq!(attr("node", "12.x"), "nodejs-12_x");
+        assert_eq!(attr("node", "16.13.1"), "nodejs-16_x");
+        assert_eq!(attr("node", ">=14.10.3 <16"), "nodejs-14_x");
+        assert_eq!(attr("node", ">= 14"), "nodejs-14_x");
+        assert_eq!(attr("node", ">16"), "nodejs-18_x");
+        assert_eq!(attr("node", "^16.1"), "nodejs-16_x");
+        assert_eq!(attr("node", "~14.2"), "nodejs-14_x");
+        assert_eq!(attr("node", "12 - 14"), "nodejs-14_x");
+        assert_eq!(attr("node", "10 || 12"), "nodejs-12_x");
+        assert_eq!(attr("node", "*"), "nodejs-10_x");
+        assert_eq!(attr("nodejs", "<=16"), "nodejs-16_x");
+    }
+
+    #[test]
+    fn test_resolve_pinned_version() -> Result<()> {
+        let index: BTreeMap<String, IndexLanguage> = serde_json::from_str(
+            r#"{
+                "node": {
+                    "versions": [
+                        { "version": "10", "attr": "nodejs-10_x", "archive": "a0b1c2d3e4f5a6b7c8d9e0f1a2b3c4d5e6f7a8b9" },
+                        { "version": "18", "attr": "nodejs-18_x" }
+                    ]
+                }
+            }"#,
+        )?;
+
+        let resolved = resolve_version_in(&index, "node", "10")?;
+        assert_eq!(
+            resolved.pkg,
+            Pkg::new("nodejs-10_x").from_archive("a0b1c2d3e4f5a6b7c8d9e0f1a2b3c4d5e6f7a8b9")
+        );
+        assert_eq!(resolve_version_in(&index, "node", "18")?.pkg.archive, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_other_languages() {
+        assert_eq!(attr("python", "2"), "python27Full");
+        assert_eq!(attr("python", "3.8.13"), "python38");
+        assert_eq!(attr("python", "3"), "python38");
+        assert_eq!(attr("python", "^3.9"), "python310");
+        assert_eq!(attr("python", ">=3.9"), "python39");
+        assert_eq!(attr("golang", "1.17"), "go_1_17");
+    }
+
+    #[test]
+    fn test_resolve_unavailable_version() {
+        let error = resolve_version("node", "15").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "node version `15` is not available. Available versions: 10, 12, 14, 16, 18"
+        );
+        assert!(resolve_version("node", ">18").is_err());
+        assert!(resolve_version("node", "latest").is_err());
+        assert!(resolve_version("cobol", "1").is_err());
+    }
+
+    #[test]
+    fn test_is_valid_requirement() {
+        assert!(is_valid_requirement(">=14 <16"));
+        assert!(is_valid_requirement("*"));
+        assert!(!is_valid_requirement("lts/*"));
+        assert!(!is_valid_requirement("latest"));
+    }
+
+    #[test]
+    fn test_get_language() {
+        assert_eq!(get_language("nodejs"), Some("node".to_string()));
+        assert_eq!(get_language("nodejs-16_x"), Some("node".to_string()));
+        assert_eq!(get_language("python38"), Some("python".to_string()));
+        assert_eq!(get_language("go"), Some("go".to_string()));
+        assert_eq!(get_language("cowsay"), None);
+    }
+
+    #[test]
+    fn test_parse_pkg_version() {
+        assert_eq!(parse_pkg_version("node@18"), Some(("node", "18")));
+        assert_eq!(parse_pkg_version("cowsay"), None);
+    }
+}
@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 
-use super::Provider;
+use super::{Detection, Provider};
 use crate::nixpacks::{
     app::App,
     environment::Environment,
@@ -19,13 +19,17 @@ pub struct ShardYaml {
 
 pub struct CrystalProvider {}
 
+const SHARDS_CACHE_DIR: &str = "/root/.cache/shards";
+
 impl Provider for CrystalProvider {
     fn name(&self) -> &str {
         "crystal"
     }
 
-    fn detect(&self, app: &App, _env: &Environment) -> Result<bool> {
-        Ok(app.includes_file("shard.yml"))
+    fn detect(&self, app: &App, _env: &Environment) -> Result<Detection> {
+        let mut detection = Detection::new();
+        detection.add(app.includes_file("shard.yml"), 100, "shard.yml present");
+        Ok(detection)
     }
 
     fn setup(&self, _app: &App, _env: &Environment) -> Result<Option<SetupPhase>> {
@@ -36,7 +40,9 @@ impl Provider for CrystalProvider {
     }
 
     fn install(&self, _app: &App, _env: &Environment) -> Result<Option<InstallPhase>> {
-        Ok(Some(InstallPhase::new("shards install".to_string())))
+        let mut install_phase = InstallPhase::new("shards install".to_string());
+        install_phase.add_cache_directory(SHARDS_CACHE_DIR.to_string());
+        Ok(Some(install_phase))
     }
 
     fn build(&self, _app: &App, _env: &Environment) -> Result<Option<BuildPhase>> {
@@ -49,7 +55,7 @@ impl Provider for CrystalProvider {
         let start_phase = StartPhase::new(format!(
             "./bin/{}",
             target_names
-                .get(0)
+                .first()
                 .ok_or_else(|| anyhow::anyhow!("Unable to get executable name"))?
         ));
 
@@ -1,6 +1,6 @@
 use std::path::PathBuf;
 
-use super::Provider;
+use super::{Detection, Provider};
 use crate::nixpacks::{
     app::App,
     environment::Environment,
@@ -17,11 +17,17 @@ impl Provider for DenoProvider {
         "deno"
     }
 
-    fn detect(&self, app: &App, _env: &Environment) -> Result<bool> {
+    fn detect(&self, app: &App, _env: &Environment) -> Result<Detection> {
         let re = Regex::new(r##"(?m)^import .+ from "https://deno.land/[^"]+\.ts";?$"##).unwrap();
-        Ok(app.includes_file("deno.json")
-            || app.includes_file("deno.jsonc")
-            || app.find_match(&re, "**/*.ts")?)
+        let mut detection = Detection::new();
+        detection.add(app.includes_file("deno.json"), 100, "deno.json present");
+        detection.add(app.includes_file("deno.jsonc"), 100, "deno.jsonc present");
+        detection.add(
+            app.find_match(&re, "**/*.ts")?,
+            100,
+            "deno.land import found in **/*.ts",
+        );
+        Ok(detection)
     }
 
     fn setup(&self, _app: &App, _env: &Environment) -> Result<Option<SetupPhase>> {
@@ -1,48 +1,57 @@
-use super::Provider;
+use super::{Detection, Provider};
 use crate::nixpacks::{
     app::App,
-    environment::{Environment, EnvironmentVariables},
+    environment::{Environment, EnvironmentVariables, VariableScope, VariableScopes},
     nix::Pkg,
     phase::{BuildPhase, InstallPhase, SetupPhase, StartPhase},
+    versions::resolve_version,
 };
 use anyhow::Result;
 
 pub struct GolangProvider {}
 
 pub const BINARY_NAME: &'static &str = &"out";
+pub const DEFAULT_GO_PKG_NAME: &'static &str = &"go";
+
+const GO_BUILD_CACHE_DIR: &str = "/root/.cache/go-build";
+const GO_MOD_CACHE_DIR: &str = "/root/go/pkg/mod";
 
 impl Provider for GolangProvider {
     fn name(&self) -> &str {
         "golang"
     }
 
-    fn detect(&self, app: &App, _env: &Environment) -> Result<bool> {
-        Ok(app.includes_file("main.go") || app.includes_file("go.mod"))
+    fn detect(&self, app: &App, _env: &Environment) -> Result<Detection> {
+        let mut detection = Detection::new();
+        detection.add(app.includes_file("go.mod"), 100, "go.mod present");
+        detection.add(app.includes_file("main.go"), 50, "main.go present");
+        Ok(detection)
     }
 
-    fn setup(&self, _app: &App, _env: &Environment) -> Result<Option<SetupPhase>> {
-        Ok(Some(SetupPhase::new(vec![Pkg::new("go")])))
+    fn setup(&self, app: &App, env: &Environment) -> Result<Option<SetupPhase>> {
+        let go_pkg = GolangProvider::get_nix_go_pkg(app, env)?;
+        Ok(Some(SetupPhase::new(vec![go_pkg])))
     }
 
     fn install(&self, app: &App, _env: &Environment) -> Result<Option<InstallPhase>> {
         if app.includes_file("go.mod") {
-            return Ok(Some(InstallPhase::new("go get".to_string())));
+            let mut install_phase = InstallPhase::new("go get".to_string());
+            install_phase.add_cache_directory(GO_MOD_CACHE_DIR.to_string());
+            return Ok(Some(install_phase));
         }
         Ok(None)
     }
 
     fn build(&self, app: &App, _env: &Environment) -> Result<Option<BuildPhase>> {
-        if app.includes_file("go.mod") {
-            Ok(Some(BuildPhase::new(format!(
-                "go build -o {}",
-                BINARY_NAME
-            ))))
+        let mut build_phase = if app.includes_file("go.mod") {
+            BuildPhase::new(format!("go build -o {}", BINARY_NAME))
         } else {
-            Ok(Some(BuildPhase::new(format!(
-                "go build -o {} main.go",
-                BINARY_NAME
-            ))))
-        }
+            BuildPhase::new(format!("go build -o {} main.go", BINARY_NAME))
+        };
+        build_phase.add_cache_directory(GO_BUILD_CACHE_DIR.to_string());
+        build_phase.add_cache_directory(GO_MOD_CACHE_DIR.to_string());
+
+        Ok(Some(build_phase))
     }
 
     fn start(&self, _app: &App, env: &Environment) -> Result<Option<StartPhase>> {
@@ -71,4 +80,79 @@ impl Provider for GolangProvider {
             "0".to_string(),
         )])))
     }
+
+    fn variable_scopes(&self, _app: &App, _env: &Environment) -> Result<VariableScopes> {
+        Ok(VariableScopes::from([(
+            "CGO_ENABLED".to_string(),
+            VariableScope::Build,
+        )]))
+    }
+}
+
+impl GolangProvider {
+    /// Uses `NIXPACKS_GO_VERSION`, or the `go` directive of go.mod when that version is available
+    pub fn get_nix_go_pkg(app: &App, env: &Environment) -> Result<Pkg> {
+        if let Some(go_version) = env.get_config_variable("GO_VERSION") {
+            return Ok(resolve_version("go", go_version)?.pkg);
+        }
+
+        if app.includes_file("go.mod") {
+            let go_mod = app.read_file("go.mod")?;
+            let go_version = go_mod
+                .lines()
+                .find_map(|line| line.trim().strip_prefix("go "));
+
+            // The directive is only the minimum version, which newer releases of Go can build
+            if let Some(Ok(resolved)) = go_version.map(|v| resolve_version("go", v.trim())) {
+                return Ok(resolved.pkg);
+            }
+        }
+
+        Ok(Pkg::new(DEFAULT_GO_PKG_NAME))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_go_version_from_go_mod() -> Result<()> {
+        assert_eq!(
+            GolangProvider::get_nix_go_pkg(
+                &App::new("./examples/go-node-tooling")?,
+                &Environment::default()
+            )?,
+            Pkg::new("go_1_18")
+        );
+        // go 1.14 is not in the index
+        assert_eq!(
+            GolangProvider::get_nix_go_pkg(
+                &App::new("./examples/go-mod")?,
+                &Environment::default()
+            )?,
+            Pkg::new(DEFAULT_GO_PKG_NAME)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_go_version_from_environment_variable() -> Result<()> {
+        let env = Environment::new(HashMap::from([(
+            "NIXPACKS_GO_VERSION".to_string(),
+            "1.17".to_string(),
+        )]));
+        assert_eq!(
+            GolangProvider::get_nix_go_pkg(&App::new("./examples/go-mod")?, &env)?,
+            Pkg::new("go_1_17")
+        );
+
+        let env = Environment::new(HashMap::from([(
+            "NIXPACKS_GO_VERSION".to_string(),
+            "1.12".to_string(),
+        )]));
+        assert!(GolangProvider::get_nix_go_pkg(&App::new("./examples/go-mod")?, &env).is_err());
+        Ok(())
+    }
 }
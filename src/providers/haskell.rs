@@ -7,7 +7,7 @@ use crate::nixpacks::{
     phase::{BuildPhase, InstallPhase, SetupPhase, StartPhase},
 };
 
-use super::Provider;
+use super::{Detection, Provider};
 
 pub struct HaskellStackProvider {}
 
@@ -20,8 +20,14 @@ impl Provider for HaskellStackProvider {
         &self,
         app: &crate::nixpacks::app::App,
         _env: &crate::nixpacks::environment::Environment,
-    ) -> anyhow::Result<bool> {
-        Ok(app.includes_file("package.yaml") && app.has_match("**/*.hs"))
+    ) -> anyhow::Result<Detection> {
+        let mut detection = Detection::new();
+        detection.add(
+            app.includes_file("package.yaml") && app.has_match("**/*.hs"),
+            100,
+            "package.yaml and **/*.hs present",
+        );
+        Ok(detection)
     }
 
     fn setup(
@@ -61,10 +67,18 @@ impl Provider for HaskellStackProvider {
         Ok(Some(StartPhase::new(format!(
             "stack exec {}",
             exe_names
-                .get(0)
+                .first()
                 .ok_or_else(|| anyhow::anyhow!("Failed to get executable name"))?
         ))))
     }
+
+    fn ignore_patterns(
+        &self,
+        _app: &crate::nixpacks::app::App,
+        _env: &crate::nixpacks::environment::Environment,
+    ) -> anyhow::Result<Vec<String>> {
+        Ok(vec![".stack-work".to_string()])
+    }
 }
 
 #[derive(Deserialize)]
@@ -1,9 +1,10 @@
 use crate::nixpacks::{
     app::App,
-    environment::{Environment, EnvironmentVariables},
+    environment::{Environment, EnvironmentVariables, VariableScopes},
     phase::{BuildPhase, InstallPhase, SetupPhase, StartPhase},
 };
 use anyhow::Result;
+use serde::Serialize;
 
 pub mod crystal;
 pub mod deno;
@@ -13,9 +14,57 @@ pub mod node;
 pub mod python;
 pub mod rust;
 
+/// How strongly a provider matches an app and why
+///
+/// Providers add to the score for every file or pattern that indicates the language. A score of
+/// zero means the provider does not apply to the app.
+#[derive(Serialize, Default, Clone, Debug, PartialEq, Eq)]
+pub struct Detection {
+    pub score: u32,
+    pub reasons: Vec<String>,
+}
+
+impl Detection {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add to the score if `matched` is true
+    pub fn add(&mut self, matched: bool, score: u32, reason: &str) {
+        if matched {
+            self.score += score;
+            self.reasons.push(reason.to_string());
+        }
+    }
+
+    pub fn matches(&self) -> bool {
+        self.score > 0
+    }
+}
+
+/// Detection result of a single provider for an app
+#[derive(Serialize, Clone, Debug)]
+pub struct ProviderDetection {
+    pub provider: String,
+    pub matched: bool,
+    pub score: u32,
+    pub reasons: Vec<String>,
+}
+
+impl ProviderDetection {
+    pub fn new(provider: &dyn Provider, detection: Detection) -> Self {
+        Self {
+            provider: provider.name().to_string(),
+            matched: detection.matches(),
+            score: detection.score,
+            reasons: detection.reasons,
+        }
+    }
+}
+
 pub trait Provider {
     fn name(&self) -> &str;
-    fn detect(&self, app: &App, _env: &Environment) -> Result<bool>;
+    fn detect(&self, app: &App, _env: &Environment) -> Result<Detection>;
     fn setup(&self, _app: &App, _env: &Environment) -> Result<Option<SetupPhase>> {
         Ok(None)
     }
@@ -35,4 +84,34 @@ pub trait Provider {
     ) -> Result<Option<EnvironmentVariables>> {
         Ok(None)
     }
+
+    /// Scope of the variables returned by `environment_variables`. Variables that aren't listed
+    /// are available while building and running the app. Every name must be one of the
+    /// variables, or planning fails.
+    fn variable_scopes(&self, _app: &App, _env: &Environment) -> Result<VariableScopes> {
+        Ok(VariableScopes::new())
+    }
+
+    /// Files that are not copied into the image, in `.dockerignore` syntax (e.g. dependencies
+    /// that are installed during the build)
+    fn ignore_patterns(&self, _app: &App, _env: &Environment) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detection_score() {
+        let mut detection = Detection::new();
+        assert!(!detection.matches());
+
+        detection.add(true, 50, "package.json present");
+        detection.add(false, 20, "lockfile present");
+        assert!(detection.matches());
+        assert_eq!(detection.score, 50);
+        assert_eq!(detection.reasons, vec!["package.json present".to_string()]);
+    }
 }
@@ -1,19 +1,22 @@
 use std::collections::HashMap;
 
-use super::Provider;
+use super::{Detection, Provider};
 use crate::nixpacks::{
     app::App,
-    environment::{Environment, EnvironmentVariables},
+    environment::{Environment, EnvironmentVariables, VariableScope, VariableScopes},
     nix::Pkg,
     phase::{BuildPhase, InstallPhase, SetupPhase, StartPhase},
+    versions::{is_valid_requirement, resolve_version},
 };
-use anyhow::{bail, Result};
-use regex::Regex;
+use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
-const AVAILABLE_NODE_VERSIONS: &[u32] = &[10, 12, 14, 16, 18];
 pub const DEFAULT_NODE_PKG_NAME: &'static &str = &"nodejs";
 
+const NPM_CACHE_DIR: &str = "/root/.npm";
+const YARN_CACHE_DIR: &str = "/usr/local/share/.cache/yarn";
+const PNPM_CACHE_DIR: &str = "/root/.local/share/pnpm/store";
+
 #[derive(Serialize, Deserialize, Default, Debug)]
 pub struct PackageJson {
     pub name: Option<String>,
@@ -30,8 +33,29 @@ impl Provider for NodeProvider {
         "node"
     }
 
-    fn detect(&self, app: &App, _env: &Environment) -> Result<bool> {
-        Ok(app.includes_file("package.json"))
+    fn detect(&self, app: &App, _env: &Environment) -> Result<Detection> {
+        let mut detection = Detection::new();
+        if !app.includes_file("package.json") {
+            return Ok(detection);
+        }
+
+        // A package.json and its lockfile are often only there for tooling (e.g. formatters in a
+        // Go app), so they score low. Every kind of signal counts once.
+        detection.add(true, 10, "package.json present");
+        if let Some(lockfile) = ["package-lock.json", "yarn.lock", "pnpm-lock.yaml"]
+            .into_iter()
+            .find(|lockfile| app.includes_file(lockfile))
+        {
+            detection.add(true, 10, format!("{} present", lockfile).as_str());
+        }
+        match NodeProvider::has_script(app, "start") {
+            Ok(has_start) => detection.add(has_start, 40, "start script in package.json"),
+            // Other providers may still match, the Node provider fails later if it is used
+            Err(_) => detection
+                .reasons
+                .push("package.json could not be parsed".to_string()),
+        }
+        Ok(detection)
     }
 
     fn setup(&self, app: &App, env: &Environment) -> Result<Option<SetupPhase>> {
@@ -59,18 +83,28 @@ impl Provider for NodeProvider {
 
     fn install(&self, app: &App, _env: &Environment) -> Result<Option<InstallPhase>> {
         let mut install_cmd = "npm i";
+        let mut cache_dir = Some(NPM_CACHE_DIR);
         if NodeProvider::get_package_manager(app)? == "pnpm" {
-            install_cmd = "pnpm i --frozen-lockfile"
+            install_cmd = "pnpm i --frozen-lockfile";
+            cache_dir = Some(PNPM_CACHE_DIR);
         } else if NodeProvider::get_package_manager(app)? == "yarn" {
             if app.includes_file(".yarnrc.yml") {
-                install_cmd = "yarn set version berry && yarn install --immutable --check-cache"
+                install_cmd = "yarn set version berry && yarn install --immutable --check-cache";
+                // Berry keeps its cache in the app directory
+                cache_dir = None;
             } else {
-                install_cmd = "yarn install --frozen-lockfile --production=false"
+                install_cmd = "yarn install --frozen-lockfile --production=false";
+                cache_dir = Some(YARN_CACHE_DIR);
             }
         } else if app.includes_file("package-lock.json") {
             install_cmd = "npm ci"
         }
-        Ok(Some(InstallPhase::new(install_cmd.to_string())))
+
+        let mut install_phase = InstallPhase::new(install_cmd.to_string());
+        if let Some(cache_dir) = cache_dir {
+            install_phase.add_cache_directory(cache_dir.to_string());
+        }
+        Ok(Some(install_phase))
     }
 
     fn build(&self, app: &App, _env: &Environment) -> Result<Option<BuildPhase>> {
@@ -100,6 +134,18 @@ impl Provider for NodeProvider {
     ) -> Result<Option<EnvironmentVariables>> {
         Ok(Some(NodeProvider::get_node_environment_variables()))
     }
+
+    fn variable_scopes(&self, _app: &App, _env: &Environment) -> Result<VariableScopes> {
+        // Dev dependencies are only needed to build the app
+        Ok(VariableScopes::from([(
+            "NPM_CONFIG_PRODUCTION".to_string(),
+            VariableScope::Build,
+        )]))
+    }
+
+    fn ignore_patterns(&self, _app: &App, _env: &Environment) -> Result<Vec<String>> {
+        Ok(vec!["node_modules".to_string()])
+    }
 }
 
 impl NodeProvider {
@@ -113,7 +159,7 @@ impl NodeProvider {
     pub fn has_script(app: &App, script: &str) -> Result<bool> {
         let package_json: PackageJson = app.read_json("package.json")?;
         if let Some(scripts) = package_json.scripts {
-            if scripts.get(script).is_some() {
+            if scripts.contains_key(script) {
                 return Ok(true);
             }
         }
@@ -156,24 +202,14 @@ impl NodeProvider {
             None => return Ok(Pkg::new(DEFAULT_NODE_PKG_NAME)),
         };
 
-        // Any version will work, use latest
-        if node_version == "*" {
+        // Any version will work, use latest. Requirements that aren't version ranges (e.g.
+        // `lts/*`) can't be matched against the index, so they get the default version too.
+        if node_version == "*" || !is_valid_requirement(node_version) {
             return Ok(Pkg::new(DEFAULT_NODE_PKG_NAME));
         }
 
-        // Parse `12` or `12.x` into nodejs-12_x
-        let re = Regex::new(r"^(\d+)\.?[x|X]?$").unwrap();
-        if let Some(node_pkg) = parse_regex_into_pkg(&re, node_version)? {
-            return Ok(Pkg::new(node_pkg.as_str()));
-        }
-
-        // Parse `>=14.10.3 <16` into nodejs-14_x
-        let re = Regex::new(r"^>=(\d+)").unwrap();
-        if let Some(node_pkg) = parse_regex_into_pkg(&re, node_version)? {
-            return Ok(Pkg::new(node_pkg.as_str()));
-        }
-
-        Ok(Pkg::new(DEFAULT_NODE_PKG_NAME))
+        // Resolve `12`, `12.x`, or `>=14.10.3 <16` into the matching nodejs-*_x
+        Ok(resolve_version("node", node_version)?.pkg)
     }
 
     pub fn get_package_manager(app: &App) -> Result<String> {
@@ -187,26 +223,6 @@ impl NodeProvider {
     }
 }
 
-fn version_number_to_pkg(version: &u32) -> Result<Option<String>> {
-    if AVAILABLE_NODE_VERSIONS.contains(version) {
-        Ok(Some(format!("nodejs-{}_x", version)))
-    } else {
-        bail!("Node version {} is not available", version);
-    }
-}
-
-fn parse_regex_into_pkg(re: &Regex, node_version: &str) -> Result<Option<String>> {
-    let matches: Vec<_> = re.captures_iter(node_version).collect();
-    if let Some(m) = matches.get(0) {
-        match m[1].parse::<u32>() {
-            Ok(version) => return version_number_to_pkg(&version),
-            Err(_e) => {}
-        }
-    }
-
-    Ok(None)
-}
-
 #[cfg(test)]
 mod test {
     use super::*;
@@ -362,4 +378,25 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn test_engine_not_a_version() -> Result<()> {
+        for version in ["lts/*", "latest", "node"] {
+            assert_eq!(
+                NodeProvider::get_nix_node_pkg(
+                    &PackageJson {
+                        name: Some(String::default()),
+                        main: None,
+                        scripts: None,
+                        workspaces: None,
+                        engines: engines_node(version),
+                    },
+                    &Environment::default()
+                )?,
+                Pkg::new(DEFAULT_NODE_PKG_NAME)
+            );
+        }
+
+        Ok(())
+    }
 }
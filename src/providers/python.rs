@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 
-use anyhow::{bail, Context, Result};
+use anyhow::{Context, Result};
 
 use serde::Deserialize;
 
@@ -10,14 +10,17 @@ use crate::{
         app::App,
         environment::Environment,
         phase::{InstallPhase, SetupPhase, StartPhase},
+        versions::resolve_version,
     },
     Pkg,
 };
 
-use super::Provider;
+use super::{Detection, Provider};
 
 pub const DEFAULT_PYTHON_PKG_NAME: &'static &str = &"python38";
 
+const PIP_CACHE_DIR: &str = "/root/.cache/pip";
+
 pub struct PythonProvider {}
 
 impl Provider for PythonProvider {
@@ -25,10 +28,20 @@ impl Provider for PythonProvider {
         "python"
     }
 
-    fn detect(&self, app: &crate::nixpacks::app::App, _env: &Environment) -> Result<bool> {
-        Ok(app.includes_file("main.py")
-            || app.includes_file("requirements.txt")
-            || app.includes_file("pyproject.toml"))
+    fn detect(&self, app: &crate::nixpacks::app::App, _env: &Environment) -> Result<Detection> {
+        let mut detection = Detection::new();
+        detection.add(
+            app.includes_file("requirements.txt"),
+            50,
+            "requirements.txt present",
+        );
+        detection.add(
+            app.includes_file("pyproject.toml"),
+            60,
+            "pyproject.toml present",
+        );
+        detection.add(app.includes_file("main.py"), 40, "main.py present");
+        Ok(detection)
     }
 
     fn setup(&self, app: &App, env: &Environment) -> Result<Option<SetupPhase>> {
@@ -48,6 +61,7 @@ impl Provider for PythonProvider {
             ));
             install_phase.add_file_dependency("requirements.txt".to_string());
             install_phase.add_path(format!("{}/bin", env_loc));
+            install_phase.add_cache_directory(PIP_CACHE_DIR.to_string());
             return Ok(Some(install_phase));
         } else if app.includes_file("pyproject.toml") {
             let mut install_phase = InstallPhase::new(format!(
@@ -56,6 +70,7 @@ impl Provider for PythonProvider {
             ));
             install_phase.add_file_dependency("pyproject.toml".to_string());
             install_phase.add_path(format!("{}/bin", env_loc));
+            install_phase.add_cache_directory(PIP_CACHE_DIR.to_string());
             return Ok(Some(install_phase));
         }
 
@@ -80,6 +95,10 @@ impl Provider for PythonProvider {
 
         Ok(None)
     }
+
+    fn ignore_patterns(&self, _app: &App, _env: &Environment) -> Result<Vec<String>> {
+        Ok(vec![".venv".to_string(), "**/__pycache__".to_string()])
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -121,15 +140,7 @@ impl PythonProvider {
         }
 
         match custom_version.map(|s| s.trim().to_string()) {
-            Some(custom_version) => {
-                if custom_version == "2" || custom_version == "2.7" {
-                    Ok(Pkg::new("python27Full"))
-                } else if custom_version == "3" || custom_version == "3.8" {
-                    Ok(Pkg::new(DEFAULT_PYTHON_PKG_NAME))
-                } else {
-                    bail!("Only the latest version of Python `2` or `3` are supported.")
-                }
-            }
+            Some(custom_version) => Ok(resolve_version("python", &custom_version)?.pkg),
             None => Ok(Pkg::new(DEFAULT_PYTHON_PKG_NAME)),
         }
     }
@@ -154,11 +165,11 @@ impl PythonProvider {
         let module_name = chain!(project.project.clone() =>
             (
                 |proj| proj.packages,
-                |pkgs| pkgs.get(0).cloned()
+                |pkgs| pkgs.first().cloned()
             );
             (
                 |proj| proj.py_modules,
-                |mods| mods.get(0).cloned()
+                |mods| mods.first().cloned()
             );
             (
                 |_| project_name.to_owned()
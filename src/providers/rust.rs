@@ -1,6 +1,6 @@
 use std::env::consts::ARCH;
 
-use super::Provider;
+use super::{Detection, Provider};
 use crate::nixpacks::{
     app::App,
     environment::{Environment, EnvironmentVariables},
@@ -13,6 +13,9 @@ use serde::{Deserialize, Serialize};
 static RUST_OVERLAY: &str = "https://github.com/oxalica/rust-overlay/archive/master.tar.gz";
 static DEFAULT_RUST_PACKAGE: &str = "rust-bin.stable.latest.default";
 
+const CARGO_REGISTRY_CACHE_DIR: &str = "/root/.cargo/registry";
+const CARGO_GIT_CACHE_DIR: &str = "/root/.cargo/git";
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct CargoTomlPackage {
     pub name: String,
@@ -33,8 +36,10 @@ impl Provider for RustProvider {
         "rust"
     }
 
-    fn detect(&self, app: &App, _env: &Environment) -> Result<bool> {
-        Ok(app.includes_file("Cargo.toml"))
+    fn detect(&self, app: &App, _env: &Environment) -> Result<Detection> {
+        let mut detection = Detection::new();
+        detection.add(app.includes_file("Cargo.toml"), 100, "Cargo.toml present");
+        Ok(detection)
     }
 
     fn setup(&self, app: &App, env: &Environment) -> Result<Option<SetupPhase>> {
@@ -56,10 +61,12 @@ impl Provider for RustProvider {
     }
 
     fn build(&self, app: &App, env: &Environment) -> Result<Option<BuildPhase>> {
-        let build_phase = match RustProvider::get_target(app, env)? {
+        let mut build_phase = match RustProvider::get_target(app, env)? {
             Some(target) => BuildPhase::new(format!("cargo build --release --target {target}")),
             None => BuildPhase::new("cargo build --release".to_string()),
         };
+        build_phase.add_cache_directory(CARGO_REGISTRY_CACHE_DIR.to_string());
+        build_phase.add_cache_directory(CARGO_GIT_CACHE_DIR.to_string());
 
         Ok(Some(build_phase))
     }
@@ -96,6 +103,10 @@ impl Provider for RustProvider {
         variables.insert("ROCKET_ADDRESS".to_string(), "0.0.0.0".to_string());
         Ok(Some(variables))
     }
+
+    fn ignore_patterns(&self, _app: &App, _env: &Environment) -> Result<Vec<String>> {
+        Ok(vec!["target".to_string()])
+    }
 }
 
 impl RustProvider {
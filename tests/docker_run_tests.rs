@@ -1,9 +1,10 @@
-use nixpacks::build;
+use nixpacks::{build, nixpacks::oci::verify_oci_archive, Nixpacks};
 use std::io::{BufRead, BufReader};
 use std::{
     process::{Command, Stdio},
     thread, time,
 };
+use tempdir::TempDir;
 use uuid::Uuid;
 
 const TIMEOUT_SECONDS: i32 = 5;
@@ -26,6 +27,8 @@ fn stop_containers(container_id: &str) {
         .arg("stop")
         .arg(container_id)
         .spawn()
+        .unwrap()
+        .wait()
         .unwrap();
 }
 
@@ -34,6 +37,8 @@ fn remove_containers(container_id: &str) {
         .arg("rm")
         .arg(container_id)
         .spawn()
+        .unwrap()
+        .wait()
         .unwrap();
 }
 
@@ -177,6 +182,20 @@ fn test_python_2() {
     assert!(output.contains("Hello from Python 2"));
 }
 
+#[test]
+fn test_node_python() {
+    let name = simple_build("./examples/node-python");
+    let output = run_image(name);
+    assert!(output.contains("Hello from Python and Node"));
+}
+
+#[test]
+fn test_named_phases() {
+    let name = simple_build("./examples/phases");
+    let output = run_image(name);
+    assert!(output.contains("Hello from generated code"));
+}
+
 #[test]
 fn test_rust_custom_version() {
     let name = Uuid::new_v4().to_string();
@@ -242,3 +261,22 @@ fn test_cowsay() {
     let output = run_image(name);
     assert!(output.contains("Hello World"));
 }
+
+#[test]
+fn test_oci_output() {
+    let name = Uuid::new_v4().to_string();
+    let out_dir = TempDir::new("nixpacks-oci").unwrap();
+    let oci_output = out_dir.path().join("image.tar");
+
+    Nixpacks::builder()
+        .path("./examples/node")
+        .name(name)
+        .quiet(true)
+        .oci_output(oci_output.to_str().unwrap())
+        .build()
+        .unwrap();
+
+    let archive = verify_oci_archive(&oci_output).unwrap();
+    assert!(archive.manifests >= 1);
+    assert!(archive.layers >= 1);
+}
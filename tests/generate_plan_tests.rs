@@ -1,5 +1,35 @@
 use anyhow::Result;
-use nixpacks::{gen_plan, nixpacks::nix::Pkg};
+use nixpacks::{
+    detect, gen_plan, get_providers,
+    nixpacks::{
+        app::App,
+        dockerfile::Instruction,
+        environment::{Environment, EnvironmentVariables, VariableScope, VariableScopes},
+        ignore::IgnoreRules,
+        images::DEBIAN_SLIM_IMAGE,
+        logger::{HumanLogger, LogEvent, Logger},
+        nix::Pkg,
+        phase::SetupPhase,
+        AppBuilder, AppBuilderOptions,
+    },
+    providers::{Detection, Provider},
+    Nixpacks,
+};
+use std::{cell::RefCell, fs, path::Path, process::Command, rc::Rc};
+use tempdir::TempDir;
+
+/// Keeps the warnings logged while planning
+struct WarningLogger {
+    warnings: Rc<RefCell<Vec<String>>>,
+}
+
+impl Logger for WarningLogger {
+    fn log(&self, event: LogEvent) {
+        if let LogEvent::Warning { message } = event {
+            self.warnings.borrow_mut().push(message);
+        }
+    }
+}
 
 #[test]
 fn test_node() -> Result<()> {
@@ -76,6 +106,36 @@ fn test_node_custom_version() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_node_open_ended_version() -> Result<()> {
+    let plan = gen_plan(
+        "./examples/node",
+        Vec::new(),
+        None,
+        None,
+        vec!["NIXPACKS_NODE_VERSION=>=14"],
+        false,
+    )?;
+    assert_eq!(plan.setup.unwrap().pkgs, vec![Pkg::new("nodejs-14_x")]);
+
+    Ok(())
+}
+
+#[test]
+fn test_python_major_version() -> Result<()> {
+    let plan = gen_plan(
+        "./examples/python",
+        Vec::new(),
+        None,
+        None,
+        vec!["NIXPACKS_PYTHON_VERSION=3"],
+        false,
+    )?;
+    assert_eq!(plan.setup.unwrap().pkgs, vec![Pkg::new("python38")]);
+
+    Ok(())
+}
+
 #[test]
 fn test_yarn() -> Result<()> {
     let plan = gen_plan("./examples/yarn", Vec::new(), None, None, Vec::new(), false)?;
@@ -264,6 +324,158 @@ fn test_custom_pkgs() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_custom_pkg_versions() -> Result<()> {
+    let plan = gen_plan(
+        "./examples/hello",
+        vec!["cowsay", "node@^16.2", "python@3.9"],
+        None,
+        None,
+        Vec::new(),
+        false,
+    )?;
+    assert_eq!(
+        plan.setup.unwrap().pkgs,
+        vec![
+            Pkg::new("cowsay"),
+            Pkg::new("nodejs-16_x"),
+            Pkg::new("python39")
+        ]
+    );
+
+    let error = gen_plan(
+        "./examples/hello",
+        vec!["node@15"],
+        None,
+        None,
+        Vec::new(),
+        false,
+    )
+    .unwrap_err();
+    assert!(format!("{:#}", error).contains("node version `15` is not available"));
+
+    Ok(())
+}
+
+#[test]
+fn test_custom_pkg_version_replaces_provider_pkg() -> Result<()> {
+    let plan = Nixpacks::builder()
+        .path("./examples/node")
+        .pkg("node@16")
+        .plan()?;
+    assert_eq!(plan.setup.unwrap().pkgs, vec![Pkg::new("nodejs-16_x")]);
+
+    let plan = Nixpacks::builder()
+        .path("./examples/node-python")
+        .pkg("python@3.10")
+        .plan()?;
+    assert_eq!(
+        plan.setup.unwrap().pkgs,
+        vec![Pkg::new("nodejs"), Pkg::new("python310")]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_custom_pkg_typo() -> Result<()> {
+    let warnings = Rc::new(RefCell::new(Vec::new()));
+    let plan = Nixpacks::builder()
+        .path("./examples/hello")
+        .pkg("cowsy")
+        .logger(Box::new(WarningLogger {
+            warnings: warnings.clone(),
+        }))
+        .plan()?;
+    assert_eq!(plan.setup.unwrap().pkgs, vec![Pkg::new("cowsy")]);
+    assert_eq!(
+        *warnings.borrow(),
+        vec!["Nix package `cowsy` is not in the package index. Did you mean `cowsay`?".to_string()]
+    );
+
+    let warnings = Rc::new(RefCell::new(Vec::new()));
+    let plan = Nixpacks::builder()
+        .path("./examples/hello")
+        .pkg("cowsy")
+        .skip_pkg_validation(true)
+        .logger(Box::new(WarningLogger {
+            warnings: warnings.clone(),
+        }))
+        .plan()?;
+    assert_eq!(plan.setup.unwrap().pkgs, vec![Pkg::new("cowsy")]);
+    assert!(warnings.borrow().is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_pkgs_pinned_to_archives() -> Result<()> {
+    let mut plan = gen_plan(
+        "./examples/hello",
+        vec!["postgresql"],
+        None,
+        None,
+        Vec::new(),
+        true,
+    )?;
+    let setup = plan.setup.as_mut().unwrap();
+    setup
+        .pkgs
+        .push(Pkg::new("nodejs-10_x").from_archive("a0b1c2d3"));
+    setup
+        .pkgs
+        .push(Pkg::new("python27").from_archive("a0b1c2d3"));
+
+    let nix = AppBuilder::gen_nix(&plan)?;
+    assert_eq!(
+        nix.matches("https://github.com/NixOS/nixpkgs/archive/a0b1c2d3.tar.gz")
+            .count(),
+        1
+    );
+    assert!(nix.contains("archive0 = import (fetchTarball"));
+    assert!(nix.contains("postgresql archive0.nodejs-10_x archive0.python27"));
+
+    Ok(())
+}
+
+#[test]
+fn test_pkgs_pinned_from_cli_and_config() -> Result<()> {
+    let app_dir = TempDir::new("nixpacks-archive-pins")?;
+    let app_path = app_dir.path();
+    fs::write(app_path.join("hello.sh"), "echo hello")?;
+    fs::write(
+        app_path.join("nixpacks.toml"),
+        r#"
+        [setup.archives]
+        python27 = { archive = "b1c2d3e4f5a6b1c2d3e4f5a6b1c2d3e4f5a6b1c2", archiveHash = "sha256-AAAA" }
+        "#,
+    )?;
+
+    let plan = Nixpacks::builder()
+        .path(app_path.to_str().unwrap())
+        .pkgs([
+            "nodejs-10_x@a0b1c2d3e4f5a0b1c2d3e4f5a0b1c2d3e4f5a0b1",
+            "python27",
+        ])
+        .plan()?;
+
+    let mut python = Pkg::new("python27").from_archive("b1c2d3e4f5a6b1c2d3e4f5a6b1c2d3e4f5a6b1c2");
+    python.archive_hash = Some("sha256-AAAA".to_string());
+    assert_eq!(
+        plan.setup.clone().unwrap().pkgs,
+        vec![
+            Pkg::new("nodejs-10_x").from_archive("a0b1c2d3e4f5a0b1c2d3e4f5a0b1c2d3e4f5a0b1"),
+            python
+        ]
+    );
+
+    let nix = AppBuilder::gen_nix(&plan)?;
+    assert!(nix.contains(
+        "archive1 = import (fetchTarball { url = \"https://github.com/NixOS/nixpkgs/archive/b1c2d3e4f5a6b1c2d3e4f5a6b1c2d3e4f5a6b1c2.tar.gz\"; sha256 = \"sha256-AAAA\"; })"
+    ));
+    Ok(())
+}
+
 #[test]
 fn test_pin_archive() -> Result<()> {
     let plan = gen_plan("./examples/hello", Vec::new(), None, None, Vec::new(), true)?;
@@ -516,3 +728,869 @@ fn test_config_from_environment_variables() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_config_file() -> Result<()> {
+    let plan = gen_plan(
+        "./examples/config-file",
+        Vec::new(),
+        None,
+        None,
+        Vec::new(),
+        false,
+    )?;
+    assert_eq!(
+        plan.setup.unwrap().pkgs,
+        vec![Pkg::new("nodejs"), Pkg::new("cowsay")]
+    );
+    assert_eq!(plan.install.unwrap().cmd, Some("npm i".to_string()));
+    assert_eq!(plan.build.unwrap().cmd, Some("npm run compile".to_string()));
+    assert_eq!(plan.start.unwrap().cmd, Some("node index.js".to_string()));
+    assert_eq!(
+        plan.variables.unwrap().get("GREETING"),
+        Some(&"hello".to_string())
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_config_file_overridden_by_cli_and_environment() -> Result<()> {
+    let plan = gen_plan(
+        "./examples/config-file",
+        vec!["ripgrep"],
+        Some("npm run build".to_string()),
+        None,
+        vec!["GREETING=hi", "NIXPACKS_START_CMD=npm start"],
+        false,
+    )?;
+    assert_eq!(
+        plan.setup.unwrap().pkgs,
+        vec![Pkg::new("nodejs"), Pkg::new("cowsay"), Pkg::new("ripgrep")]
+    );
+    assert_eq!(plan.build.unwrap().cmd, Some("npm run build".to_string()));
+    assert_eq!(plan.start.unwrap().cmd, Some("npm start".to_string()));
+    assert_eq!(
+        plan.variables.unwrap().get("GREETING"),
+        Some(&"hi".to_string())
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_plan_override() -> Result<()> {
+    let dir = TempDir::new("nixpacks-plan-override")?;
+    let override_path = dir.path().join("plan.json");
+    fs::write(
+        &override_path,
+        r#"{ "setup": { "pkgs": ["...", { "name": "cowsay" }] }, "start": { "cmd": "node index.js" } }"#,
+    )?;
+
+    let plan = Nixpacks::builder()
+        .path("./examples/node")
+        .plan_override_path(override_path.to_str().unwrap())
+        .plan()?;
+    assert_eq!(
+        plan.setup.unwrap().pkgs,
+        vec![Pkg::new("nodejs"), Pkg::new("cowsay")]
+    );
+    assert_eq!(plan.install.unwrap().cmd, Some("npm ci".to_string()));
+    assert_eq!(plan.start.unwrap().cmd, Some("node index.js".to_string()));
+
+    // Overrides use the format of their extension, like plans
+    for (file_name, contents) in [
+        ("plan.yaml", "start:\n  cmd: node server.js\n"),
+        ("plan.toml", "[start]\ncmd = \"node server.js\"\n"),
+    ] {
+        let override_path = dir.path().join(file_name);
+        fs::write(&override_path, contents)?;
+        let plan = Nixpacks::builder()
+            .path("./examples/node")
+            .plan_override_path(override_path.to_str().unwrap())
+            .plan()?;
+        assert_eq!(plan.start.unwrap().cmd, Some("node server.js".to_string()));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_multiple_providers() -> Result<()> {
+    let plan = gen_plan(
+        "./examples/node-python",
+        Vec::new(),
+        None,
+        None,
+        Vec::new(),
+        false,
+    )?;
+    assert_eq!(
+        plan.setup.unwrap().pkgs,
+        vec![Pkg::new("nodejs"), Pkg::new("python38")]
+    );
+
+    let install_phase = plan.install.unwrap();
+    assert_eq!(
+        install_phase.cmd,
+        Some("npm i && python -m venv /opt/venv && . /opt/venv/bin/activate && pip install -r requirements.txt".to_string())
+    );
+    assert_eq!(install_phase.only_include_files, None);
+    assert_eq!(install_phase.paths, Some(vec!["/opt/venv/bin".to_string()]));
+
+    assert_eq!(plan.start.unwrap().cmd, Some("npm run start".to_string()));
+    assert_eq!(
+        plan.variables.unwrap().get("NODE_ENV"),
+        Some(&"production".to_string())
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_multiple_providers_without_config() -> Result<()> {
+    let app_dir = TempDir::new("nixpacks-node-python")?;
+    let app_path = app_dir.path();
+    for file in ["package.json", "index.js", "requirements.txt", "greet.py"] {
+        fs::copy(
+            Path::new("./examples/node-python").join(file),
+            app_path.join(file),
+        )?;
+    }
+
+    // Both providers match, but only the best match is used unless they are combined
+    let plan = Nixpacks::builder()
+        .path(app_path.to_str().unwrap())
+        .plan()?;
+    assert_eq!(plan.setup.unwrap().pkgs, vec![Pkg::new("nodejs")]);
+    assert_eq!(plan.install.unwrap().cmd, Some("npm i".to_string()));
+    assert_eq!(plan.start.unwrap().cmd, Some("npm run start".to_string()));
+
+    Ok(())
+}
+
+/// Provider that only needs a different base image
+struct SlimProvider {}
+
+impl Provider for SlimProvider {
+    fn name(&self) -> &str {
+        "slim"
+    }
+
+    fn detect(&self, _app: &App, _env: &Environment) -> Result<Detection> {
+        Ok(Detection::new())
+    }
+
+    fn setup(&self, _app: &App, _env: &Environment) -> Result<Option<SetupPhase>> {
+        let mut setup_phase = SetupPhase::new(Vec::new());
+        setup_phase.base_image = DEBIAN_SLIM_IMAGE.to_string();
+        Ok(Some(setup_phase))
+    }
+}
+
+#[test]
+fn test_combine_configured_providers() -> Result<()> {
+    let app_dir = TempDir::new("nixpacks-providers")?;
+    let app_path = app_dir.path();
+    fs::write(
+        app_path.join("package.json"),
+        r#"{ "scripts": { "start": "node index.js" } }"#,
+    )?;
+    fs::write(
+        app_path.join("nixpacks.toml"),
+        r#"providers = ["node", "slim"]"#,
+    )?;
+
+    let app = App::new(app_path.to_str().unwrap())?;
+    let environment = Environment::default();
+    let logger = HumanLogger {};
+    let options = AppBuilderOptions::empty();
+    let slim = SlimProvider {};
+    let mut providers = get_providers();
+    providers.push(&slim);
+
+    let plan = AppBuilder::new(None, &app, &environment, &logger, &options)?.plan(providers)?;
+    let setup = plan.setup.unwrap();
+    assert_eq!(setup.pkgs, vec![Pkg::new("nodejs")]);
+    assert_eq!(setup.base_image, DEBIAN_SLIM_IMAGE);
+    assert_eq!(plan.start.unwrap().cmd, Some("npm run start".to_string()));
+
+    fs::write(
+        app_path.join("nixpacks.toml"),
+        "provider = \"node\"\nproviders = [\"node\", \"slim\"]",
+    )?;
+    let app = App::new(app_path.to_str().unwrap())?;
+    assert!(
+        AppBuilder::new(None, &app, &environment, &logger, &options)?
+            .plan(get_providers())
+            .is_err()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_go_with_package_json() -> Result<()> {
+    let plan = gen_plan(
+        "./examples/go-node-tooling",
+        Vec::new(),
+        None,
+        None,
+        Vec::new(),
+        false,
+    )?;
+    // Only the Go provider is used, the package.json is for tooling
+    assert_eq!(plan.setup.unwrap().pkgs, vec![Pkg::new("go_1_18")]);
+    assert_eq!(plan.install.unwrap().cmd, Some("go get".to_string()));
+    assert_eq!(plan.start.unwrap().cmd, Some("./out".to_string()));
+
+    Ok(())
+}
+
+#[test]
+fn test_go_with_package_json_and_lockfile() -> Result<()> {
+    let app_dir = TempDir::new("nixpacks-go-package-lock")?;
+    let app_path = app_dir.path();
+    fs::copy("./examples/go-node-tooling/go.mod", app_path.join("go.mod"))?;
+    fs::copy(
+        "./examples/go-node-tooling/main.go",
+        app_path.join("main.go"),
+    )?;
+    fs::copy(
+        "./examples/go-node-tooling/package.json",
+        app_path.join("package.json"),
+    )?;
+    fs::write(app_path.join("package-lock.json"), "{}")?;
+
+    // Node scores well below Go, so it is not used
+    let plan = Nixpacks::builder()
+        .path(app_path.to_str().unwrap())
+        .plan()?;
+    assert_eq!(plan.setup.unwrap().pkgs, vec![Pkg::new("go_1_18")]);
+    assert_eq!(plan.install.unwrap().cmd, Some("go get".to_string()));
+    assert_eq!(plan.start.unwrap().cmd, Some("./out".to_string()));
+
+    // Nor with only a go.mod
+    fs::remove_file(app_path.join("main.go"))?;
+    let plan = Nixpacks::builder()
+        .path(app_path.to_str().unwrap())
+        .plan()?;
+    assert_eq!(plan.setup.unwrap().pkgs, vec![Pkg::new("go_1_18")]);
+    assert_eq!(plan.install.unwrap().cmd, Some("go get".to_string()));
+
+    Ok(())
+}
+
+#[test]
+fn test_malformed_package_json() -> Result<()> {
+    let app_dir = TempDir::new("nixpacks-malformed-package-json")?;
+    let app_path = app_dir.path();
+    fs::copy("./examples/go-node-tooling/go.mod", app_path.join("go.mod"))?;
+    fs::copy(
+        "./examples/go-node-tooling/main.go",
+        app_path.join("main.go"),
+    )?;
+    fs::write(app_path.join("package.json"), "{ \"scripts\": ")?;
+
+    let plan = Nixpacks::builder()
+        .path(app_path.to_str().unwrap())
+        .plan()?;
+    assert_eq!(plan.start.unwrap().cmd, Some("./out".to_string()));
+
+    let detections = detect(app_path.to_str().unwrap(), Vec::new(), Vec::new())?;
+    let node = detections.iter().find(|d| d.provider == "node").unwrap();
+    assert!(node
+        .reasons
+        .contains(&"package.json could not be parsed".to_string()));
+
+    Ok(())
+}
+
+#[test]
+fn test_deno_with_package_json() -> Result<()> {
+    let plan = gen_plan(
+        "./examples/deno-package-json",
+        Vec::new(),
+        None,
+        None,
+        Vec::new(),
+        false,
+    )?;
+    // Only the Deno provider is used, the package.json only has scripts
+    assert_eq!(plan.setup.unwrap().pkgs, vec![Pkg::new("deno")]);
+    assert_eq!(plan.install.and_then(|install| install.cmd), None);
+    assert_eq!(
+        plan.build.unwrap().cmd,
+        Some("deno cache src/index.ts".to_string())
+    );
+    assert_eq!(
+        plan.start.unwrap().cmd,
+        Some("deno run --allow-all src/index.ts".to_string())
+    );
+    assert!(!plan
+        .variables
+        .is_some_and(|variables| variables.contains_key("NODE_ENV")));
+
+    Ok(())
+}
+
+#[test]
+fn test_detection_independent_of_provider_order() -> Result<()> {
+    let app = App::new("./examples/go-node-tooling")?;
+    let environment = Environment::default();
+    let logger = HumanLogger {};
+    let options = AppBuilderOptions::empty();
+
+    let mut providers = get_providers();
+    let plan =
+        AppBuilder::new(None, &app, &environment, &logger, &options)?.plan(providers.clone())?;
+
+    providers.reverse();
+    let reversed_plan =
+        AppBuilder::new(None, &app, &environment, &logger, &options)?.plan(providers)?;
+
+    assert_eq!(plan.start.unwrap().cmd, reversed_plan.start.unwrap().cmd);
+    assert_eq!(
+        plan.install.unwrap().cmd,
+        reversed_plan.install.unwrap().cmd
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_node_python_tie() -> Result<()> {
+    let app_dir = TempDir::new("nixpacks-node-python-tie")?;
+    let app_path = app_dir.path();
+    fs::write(
+        app_path.join("package.json"),
+        r#"{ "scripts": { "start": "node index.js" } }"#,
+    )?;
+    // Any number of lockfiles only counts once
+    fs::write(app_path.join("package-lock.json"), "{}")?;
+    fs::write(app_path.join("yarn.lock"), "")?;
+    fs::write(app_path.join("pyproject.toml"), "")?;
+    fs::write(app_path.join("main.py"), "")?;
+
+    let detections = detect(app_path.to_str().unwrap(), Vec::new(), Vec::new())?;
+    let node = detections.iter().find(|d| d.provider == "node").unwrap();
+    let python = detections.iter().find(|d| d.provider == "python").unwrap();
+    assert_eq!(node.score, 60);
+    assert_eq!(python.score, 100);
+
+    // A start script doesn't outweigh a Python project
+    let plan = Nixpacks::builder()
+        .path(app_path.to_str().unwrap())
+        .plan()?;
+    assert_eq!(plan.setup.unwrap().pkgs, vec![Pkg::new("python38")]);
+    assert_eq!(plan.start.unwrap().cmd, Some("python main.py".to_string()));
+
+    fs::remove_file(app_path.join("main.py"))?;
+    let detections = detect(app_path.to_str().unwrap(), Vec::new(), Vec::new())?;
+    let node = detections.iter().find(|d| d.provider == "node").unwrap();
+    let python = detections.iter().find(|d| d.provider == "python").unwrap();
+    assert_eq!(node.score, python.score);
+
+    // Equal scores are ordered by name
+    let plan = Nixpacks::builder()
+        .path(app_path.to_str().unwrap())
+        .plan()?;
+    assert_eq!(
+        plan.setup.unwrap().pkgs,
+        vec![Pkg::new("nodejs"), Pkg::new("yarn")]
+    );
+    assert_eq!(plan.start.unwrap().cmd, Some("yarn run start".to_string()));
+
+    // Without a lockfile Node scores lower and Python is used
+    fs::remove_file(app_path.join("package-lock.json"))?;
+    fs::remove_file(app_path.join("yarn.lock"))?;
+    let plan = Nixpacks::builder()
+        .path(app_path.to_str().unwrap())
+        .plan()?;
+    assert_eq!(plan.setup.unwrap().pkgs, vec![Pkg::new("python38")]);
+
+    Ok(())
+}
+
+#[test]
+fn test_detect() -> Result<()> {
+    let detections = detect("./examples/node-python", Vec::new(), Vec::new())?;
+    assert_eq!(detections.len(), get_providers().len());
+
+    let node = detections.iter().find(|d| d.provider == "node").unwrap();
+    assert!(node.matched);
+    assert!(node.reasons.contains(&"package.json present".to_string()));
+
+    let python = detections.iter().find(|d| d.provider == "python").unwrap();
+    assert!(python.matched);
+    assert_eq!(python.reasons, vec!["requirements.txt present".to_string()]);
+
+    let rust = detections.iter().find(|d| d.provider == "rust").unwrap();
+    assert!(!rust.matched);
+    assert!(rust.reasons.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_named_phases() -> Result<()> {
+    let plan = gen_plan(
+        "./examples/phases",
+        Vec::new(),
+        None,
+        None,
+        Vec::new(),
+        false,
+    )?;
+    let phases = plan.get_ordered_phases()?;
+    assert_eq!(
+        phases.iter().map(|p| p.name.as_str()).collect::<Vec<_>>(),
+        vec!["install", "codegen", "build", "test"]
+    );
+    assert_eq!(phases[1].cmd, Some("npm run codegen".to_string()));
+    assert_eq!(phases[3].cmd, Some("npm run test".to_string()));
+
+    Ok(())
+}
+
+#[test]
+fn test_dockerfile_instructions() -> Result<()> {
+    let plan = gen_plan(
+        "./examples/rust-rocket",
+        Vec::new(),
+        None,
+        None,
+        Vec::new(),
+        false,
+    )?;
+    let mut dockerfile = AppBuilder::create_dockerfile(&plan, &AppBuilderOptions::empty())?;
+
+    assert_eq!(dockerfile.stages.len(), 2);
+    assert!(dockerfile
+        .instructions()
+        .any(|i| i == &Instruction::run("nix-env -if environment.nix")));
+    assert_eq!(
+        dockerfile.stages[1].instructions.last(),
+        Some(&Instruction::Cmd("./rocket".to_string()))
+    );
+
+    dockerfile
+        .final_stage_mut()
+        .unwrap()
+        .add(Instruction::Env(vec![(
+            "HELLO".to_string(),
+            "world".to_string(),
+        )]));
+    assert!(dockerfile
+        .to_string()
+        .ends_with("CMD ./rocket\nENV HELLO=world\n"));
+
+    let dir = TempDir::new("nixpacks-dockerfile")?;
+    let dest = dir.path().to_str().unwrap();
+    AppBuilder::write_build_plan_with_dockerfile(
+        &plan,
+        &dockerfile,
+        dest,
+        &AppBuilderOptions::empty(),
+    )?;
+    assert!(fs::read_to_string(dir.path().join("Dockerfile"))?.ends_with("ENV HELLO=world\n"));
+    assert!(dir.path().join("environment.nix").exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_cache_directories() -> Result<()> {
+    let plan = gen_plan("./examples/yarn", Vec::new(), None, None, Vec::new(), false)?;
+    assert_eq!(
+        plan.install.clone().unwrap().cache_directories,
+        Some(vec!["/usr/local/share/.cache/yarn".to_string()])
+    );
+
+    let dockerfile = AppBuilder::gen_dockerfile(&plan, &AppBuilderOptions::empty())?;
+    assert!(!dockerfile.contains("# syntax"));
+    assert!(dockerfile.contains("RUN --mount=type=cache,target=/usr/local/share/.cache/yarn yarn"));
+
+    let mut options = AppBuilderOptions::empty();
+    options.no_cache_mounts = true;
+    let dockerfile = AppBuilder::gen_dockerfile(&plan, &options)?;
+    assert!(!dockerfile.contains("--mount"));
+
+    Ok(())
+}
+
+#[test]
+fn test_ignored_files_are_not_copied() -> Result<()> {
+    let app_dir = TempDir::new("nixpacks-ignore-app")?;
+    let app_path = app_dir.path();
+    fs::write(
+        app_path.join("package.json"),
+        r#"{ "scripts": { "start": "node index.js" } }"#,
+    )?;
+    fs::write(app_path.join("index.js"), "console.log('hello')")?;
+    fs::write(app_path.join(".env"), "SECRET=hunter2")?;
+    fs::write(app_path.join(".gitignore"), "dist/\n")?;
+    fs::write(app_path.join(".dockerignore"), "*.log\n!keep.log\n")?;
+    fs::write(app_path.join("debug.log"), "")?;
+    fs::write(app_path.join("keep.log"), "")?;
+    fs::create_dir_all(app_path.join("node_modules/express"))?;
+    fs::write(app_path.join("node_modules/express/index.js"), "")?;
+    fs::create_dir_all(app_path.join("dist"))?;
+    fs::write(app_path.join("dist/index.js"), "")?;
+
+    let out_dir = TempDir::new("nixpacks-ignore-out")?;
+    let out_path = out_dir.path();
+    Nixpacks::builder()
+        .path(app_path.to_str().unwrap())
+        .out_dir(out_path.to_str().unwrap())
+        .quiet(true)
+        .use_gitignore(true)
+        .build()?;
+
+    assert!(out_path.join("index.js").exists());
+    assert!(out_path.join("keep.log").exists());
+    assert!(out_path.join("Dockerfile").exists());
+    assert!(!out_path.join(".env").exists());
+    assert!(!out_path.join("debug.log").exists());
+    assert!(!out_path.join("node_modules").exists());
+    assert!(!out_path.join("dist").exists());
+
+    let dockerignore = fs::read_to_string(out_path.join(".dockerignore"))?;
+    assert!(dockerignore.contains("node_modules\n"));
+    assert!(dockerignore.contains("*.log\n!keep.log\n"));
+
+    Ok(())
+}
+
+#[test]
+fn test_generated_files_are_not_ignored() -> Result<()> {
+    let app_dir = TempDir::new("nixpacks-ignore-generated")?;
+    let app_path = app_dir.path();
+    fs::write(
+        app_path.join("package.json"),
+        r#"{ "scripts": { "start": "node index.js" } }"#,
+    )?;
+    fs::write(app_path.join(".dockerignore"), "*.nix\nDockerfile\n")?;
+    fs::create_dir_all(app_path.join("packages/api"))?;
+    fs::write(app_path.join("packages/api/.env"), "SECRET=hunter2")?;
+    fs::write(app_path.join("packages/api/.env.local"), "SECRET=hunter2")?;
+
+    let out_dir = TempDir::new("nixpacks-ignore-generated-out")?;
+    let out_path = out_dir.path();
+    Nixpacks::builder()
+        .path(app_path.to_str().unwrap())
+        .out_dir(out_path.to_str().unwrap())
+        .build()?;
+
+    assert!(!out_path.join("packages/api/.env").exists());
+    assert!(!out_path.join("packages/api/.env.local").exists());
+
+    let rules = IgnoreRules::from_app(&App::new(out_path.to_str().unwrap())?, Vec::new(), false)?;
+    assert!(!rules.is_ignored(Path::new("environment.nix")));
+    assert!(!rules.is_ignored(Path::new("Dockerfile")));
+    assert!(!rules.is_ignored(Path::new(".nixpacks/flake.nix")));
+
+    Ok(())
+}
+
+#[test]
+fn test_build_from_plan_does_not_read_config() -> Result<()> {
+    let app_dir = TempDir::new("nixpacks-plan-config")?;
+    let app_path = app_dir.path();
+    fs::write(
+        app_path.join("package.json"),
+        r#"{ "scripts": { "start": "node index.js" } }"#,
+    )?;
+    fs::write(app_path.join("nixpacks.toml"), "[setup\npkgs = ")?;
+
+    let builder = Nixpacks::builder().path(app_path.to_str().unwrap());
+    assert!(builder.plan().is_err());
+
+    let plan = gen_plan("./examples/node", Vec::new(), None, None, Vec::new(), false)?;
+    let plan_path = app_path.join("plan.json");
+    fs::write(&plan_path, serde_json::to_string(&plan)?)?;
+
+    let out_dir = TempDir::new("nixpacks-plan-config-out")?;
+    Nixpacks::builder()
+        .path(app_path.to_str().unwrap())
+        .plan_path(plan_path.to_str().unwrap())
+        .out_dir(out_dir.path().to_str().unwrap())
+        .build()?;
+    assert!(out_dir.path().join("Dockerfile").exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_secrets_with_plan_file() -> Result<()> {
+    let plan = gen_plan("./examples/npm", Vec::new(), None, None, Vec::new(), false)?;
+    let plan_dir = TempDir::new("nixpacks-plan-secrets")?;
+    let plan_path = plan_dir.path().join("plan.json");
+    fs::write(&plan_path, serde_json::to_string(&plan)?)?;
+
+    let out_dir = TempDir::new("nixpacks-plan-secrets-out")?;
+    Nixpacks::builder()
+        .path("./examples/npm")
+        .plan_path(plan_path.to_str().unwrap())
+        .secret("NPM_TOKEN")
+        .out_dir(out_dir.path().to_str().unwrap())
+        .build()?;
+
+    let dockerfile = fs::read_to_string(out_dir.path().join("Dockerfile"))?;
+    assert!(dockerfile.contains("--mount=type=secret,id=NPM_TOKEN"));
+    assert!(!dockerfile.contains("ARG NPM_TOKEN"));
+
+    Ok(())
+}
+
+#[test]
+fn test_secrets() -> Result<()> {
+    let plan = Nixpacks::builder()
+        .path("./examples/npm")
+        .env("NPM_TOKEN=hunter2")
+        .secret("NPM_TOKEN")
+        .plan()?;
+    assert_eq!(plan.secrets, Some(vec!["NPM_TOKEN".to_string()]));
+    assert_eq!(plan.variables.clone().unwrap().get("NPM_TOKEN"), None);
+    assert!(!serde_json::to_string(&plan)?.contains("hunter2"));
+
+    let dockerfile = AppBuilder::gen_dockerfile(&plan, &AppBuilderOptions::empty())?;
+    assert!(dockerfile.contains("RUN --mount=type=cache,target=/root/.npm --mount=type=secret,id=NPM_TOKEN export NPM_TOKEN=\"$(cat /run/secrets/NPM_TOKEN)\" && npm ci"));
+    assert!(dockerfile.contains(
+        "--mount=type=secret,id=NPM_TOKEN export NPM_TOKEN=\"$(cat /run/secrets/NPM_TOKEN)\" && npm run build"
+    ));
+    // Older Dockerfile frontends don't support exposing secrets as variables
+    assert!(!dockerfile.contains("env=NPM_TOKEN"));
+    assert!(!dockerfile.contains("# syntax"));
+    assert!(!dockerfile.contains("ARG NPM_TOKEN"));
+    assert!(!dockerfile.contains("hunter2"));
+
+    Ok(())
+}
+
+#[test]
+fn test_invalid_secret_name() {
+    assert!(Nixpacks::builder()
+        .path("./examples/npm")
+        .secret("NPM_TOKEN,src=/etc/passwd")
+        .plan()
+        .is_err());
+}
+
+#[test]
+fn test_variable_scopes() -> Result<()> {
+    let plan = gen_plan("./examples/npm", Vec::new(), None, None, Vec::new(), false)?;
+    assert_eq!(
+        plan.get_variable_scope("NPM_CONFIG_PRODUCTION"),
+        VariableScope::Build
+    );
+    assert_eq!(plan.get_variable_scope("NODE_ENV"), VariableScope::Both);
+
+    let dockerfile = AppBuilder::gen_dockerfile(&plan, &AppBuilderOptions::empty())?;
+    assert!(dockerfile.contains("ARG NODE_ENV NPM_CONFIG_PRODUCTION\nENV NODE_ENV=$NODE_ENV\n"));
+
+    // Variables are declared again in the run image
+    let plan = gen_plan(
+        "./examples/rust-rocket",
+        Vec::new(),
+        None,
+        None,
+        Vec::new(),
+        false,
+    )?;
+    let dockerfile = AppBuilder::create_dockerfile(&plan, &AppBuilderOptions::empty())?;
+    assert!(dockerfile.stages[1]
+        .instructions
+        .contains(&Instruction::EnvExpand(vec![(
+            "ROCKET_ADDRESS".to_string(),
+            "$ROCKET_ADDRESS".to_string()
+        )])));
+
+    Ok(())
+}
+
+#[test]
+fn test_variable_scopes_from_config() -> Result<()> {
+    let app_dir = TempDir::new("nixpacks-scopes")?;
+    let app_path = app_dir.path();
+    fs::write(
+        app_path.join("package.json"),
+        r#"{ "scripts": { "start": "node index.js" } }"#,
+    )?;
+    fs::write(
+        app_path.join("nixpacks.toml"),
+        r#"
+        [variables]
+        API_URL = "https://example.com"
+
+        [variableScopes]
+        API_URL = "runtime"
+        NPM_CONFIG_PRODUCTION = "both"
+        MISSING = "build"
+        "#,
+    )?;
+
+    let warnings = Rc::new(RefCell::new(Vec::new()));
+    let plan = Nixpacks::builder()
+        .path(app_path.to_str().unwrap())
+        .logger(Box::new(WarningLogger {
+            warnings: warnings.clone(),
+        }))
+        .plan()?;
+    assert_eq!(plan.get_variable_scope("API_URL"), VariableScope::Runtime);
+    assert_eq!(
+        plan.get_variable_scope("NPM_CONFIG_PRODUCTION"),
+        VariableScope::Both
+    );
+    assert_eq!(plan.get_variable_scope("MISSING"), VariableScope::Both);
+    assert!(warnings
+        .borrow()
+        .iter()
+        .any(|warning| warning.contains("MISSING")));
+
+    Ok(())
+}
+
+struct MismatchedScopeProvider {}
+
+impl Provider for MismatchedScopeProvider {
+    fn name(&self) -> &str {
+        "mismatched"
+    }
+
+    fn detect(&self, _app: &App, _env: &Environment) -> Result<Detection> {
+        let mut detection = Detection::new();
+        detection.add(true, 100, "always");
+        Ok(detection)
+    }
+
+    fn environment_variables(
+        &self,
+        _app: &App,
+        _env: &Environment,
+    ) -> Result<Option<EnvironmentVariables>> {
+        Ok(Some(EnvironmentVariables::from([(
+            "CGO_ENABLED".to_string(),
+            "0".to_string(),
+        )])))
+    }
+
+    fn variable_scopes(&self, _app: &App, _env: &Environment) -> Result<VariableScopes> {
+        Ok(VariableScopes::from([(
+            "CGO_ENABLE".to_string(),
+            VariableScope::Build,
+        )]))
+    }
+}
+
+#[test]
+fn test_provider_scopes_match_variables() -> Result<()> {
+    let app = App::new("./examples/npm")?;
+    let environment = Environment::default();
+    let logger = HumanLogger {};
+    let options = AppBuilderOptions::empty();
+    let provider = MismatchedScopeProvider {};
+
+    let error = AppBuilder::new(None, &app, &environment, &logger, &options)?
+        .plan(vec![&provider])
+        .unwrap_err();
+    assert!(format!("{:?}", error).contains("CGO_ENABLE,"));
+
+    Ok(())
+}
+
+#[test]
+fn test_interpolate_variables() -> Result<()> {
+    let plan = gen_plan(
+        "./examples/rust-rocket",
+        Vec::new(),
+        Some("cargo build --release --bin ${APP_BIN}".to_string()),
+        None,
+        vec!["APP_BIN=rocket"],
+        false,
+    )?;
+    assert_eq!(
+        plan.build.unwrap().cmd,
+        Some("cargo build --release --bin rocket".to_string())
+    );
+
+    let warnings = Rc::new(RefCell::new(Vec::new()));
+    let plan = Nixpacks::builder()
+        .path("./examples/rust-rocket")
+        .build_cmd("cargo build --release --bin ${APP_BIN} --target-dir ${HOME}/target")
+        .logger(Box::new(WarningLogger {
+            warnings: warnings.clone(),
+        }))
+        .plan()?;
+    assert_eq!(
+        plan.build.unwrap().cmd,
+        Some("cargo build --release --bin ${APP_BIN} --target-dir ${HOME}/target".to_string())
+    );
+    assert_eq!(warnings.borrow().len(), 2);
+    assert!(warnings.borrow()[0].contains("Variable `APP_BIN` is not set"));
+
+    let result = Nixpacks::builder()
+        .path("./examples/rust-rocket")
+        .build_cmd("cargo build --release --bin ${APP_BIN}")
+        .strict_variables(true)
+        .plan();
+    assert!(result.is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_flake_is_written_to_its_own_directory() -> Result<()> {
+    let app_dir = TempDir::new("nixpacks-flake-app")?;
+    let app_path = app_dir.path();
+    fs::write(
+        app_path.join("package.json"),
+        r#"{ "scripts": { "start": "node index.js" } }"#,
+    )?;
+    fs::write(app_path.join("flake.nix"), "{ outputs = { self }: { }; }")?;
+
+    let out_dir = TempDir::new("nixpacks-flake-out")?;
+    let out_path = out_dir.path();
+    Nixpacks::builder()
+        .path(app_path.to_str().unwrap())
+        .out_dir(out_path.to_str().unwrap())
+        .use_flake(true)
+        .build()?;
+
+    assert_eq!(
+        fs::read_to_string(out_path.join("flake.nix"))?,
+        "{ outputs = { self }: { }; }"
+    );
+    assert!(out_path.join(".nixpacks/flake.nix").exists());
+    assert!(out_path.join(".nixpacks/flake.lock").exists());
+
+    let dockerfile = fs::read_to_string(out_path.join("Dockerfile"))?;
+    assert!(dockerfile.contains("COPY .nixpacks/flake.nix .nixpacks/flake.lock /app/.nixpacks/"));
+    assert!(dockerfile
+        .contains("profile install --profile /nix/var/nix/profiles/nixpacks ./.nixpacks#default"));
+    Ok(())
+}
+
+#[test]
+fn test_json_log_keeps_plan_on_stdout() -> Result<()> {
+    let output = Command::new(env!("CARGO_BIN_EXE_nixpacks"))
+        .args([
+            "plan",
+            "./examples/node",
+            "--pkgs",
+            "not-a-real-nix-package",
+            "--log-format",
+            "json",
+        ])
+        .output()?;
+    assert!(output.status.success());
+
+    let plan: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    assert!(plan.get("setup").is_some());
+
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(stderr
+        .lines()
+        .any(|line| line.starts_with(r#"{"event":"warning""#)));
+    Ok(())
+}